@@ -9,14 +9,64 @@
 //! See the `LICENSE_MIT.markdown` file in the root directory for
 //! information on licensing and copyright.
 
+//!
+//! ## Platform support
+//!
+//! Nitro enclaves can only be run from Linux. On other platforms the same
+//! types exist, so that crates depending on this one still build, but
+//! launching, attaching to or reaping enclaves fails with
+//! `NitroError::Unsupported`.
+//!
+//! ## Logging
+//!
+//! Diagnostics are logged with the `log` crate, so their verbosity is up to
+//! the application's logger. Failures (of a launch attempt, say) are logged
+//! at warn level, and failures that leave an enclave running that the
+//! application must clean up itself at error level. Retries are logged at
+//! debug level, and the raw JSON output of the Nitro CLI tool at trace level.
+//!
+//! ## Child processes
+//!
+//! Enclaves are managed by running the Nitro CLI tool as a child process.
+//! Each run is waited for on its own process ID, so this crate never reaps
+//! the application's other children, and it never installs a `SIGCHLD`
+//! handler. Runs that time out are killed and then waited for, and
+//! terminations started in the background (see
+//! `NitroEnclaveBuilder::async_terminate`) are waited for by a background
+//! thread (or, if the application exits first, by init), so none are left
+//! as zombies. An application that ignores `SIGCHLD` (so that the kernel
+//! reaps its children itself), or whose `SIGCHLD` handler reaps every child,
+//! takes the exit status of the runs from this crate: see
+//! `ChildStatusPolicy` for what is done then.
+
+// `err_derive` expands each `#[derive(Error)]` into impl blocks nested inside
+// an anonymous constant, which newer compilers warn about; the allow can go
+// once the dependency stops doing that.
+#![allow(non_local_definitions)]
+
 use anyhow::{anyhow, Result};
 use err_derive::Error;
-use nix::unistd::alarm;
-use raw_fd;
-use serde_json::Value;
-use std::{os::unix::io::AsRawFd, process::Command};
+use std::{
+    collections::{hash_map::RandomState, BTreeMap},
+    hash::{BuildHasher, Hasher},
+    io::Write,
+    time::Duration,
+};
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "linux")]
+pub use linux::*;
+
+#[cfg(not(target_os = "linux"))]
+mod unsupported;
+#[cfg(not(target_os = "linux"))]
+pub use unsupported::*;
 
-use vsocket;
+#[cfg(all(target_os = "linux", feature = "record"))]
+mod record;
+#[cfg(all(target_os = "linux", feature = "record"))]
+pub use record::*;
 
 /// Errors generated by Nitro enclave components of Veracruz
 #[derive(Debug, Error)]
@@ -24,134 +74,519 @@ pub enum NitroError {
     /// An error occurred while serializing or deserializing
     #[error(display = "Nitro: Serde Error")]
     SerdeError,
+    /// An enclave with the given ID is not currently running
+    #[error(display = "Nitro: no running enclave with ID {}", _0)]
+    EnclaveNotFound(String),
+    /// The Nitro CLI tool exited unsuccessfully
+    #[error(display = "Nitro: CLI error: {}", _0)]
+    CliError(String),
+    /// A PCR value was not a valid hexadecimal string
+    #[error(display = "Nitro: invalid PCR value: {:?}", _0)]
+    InvalidPcr(String),
+    /// A PCR value differed from the value it was expected to have
+    #[error(
+        display = "Nitro: PCR mismatch: expected {}, found {}",
+        expected,
+        actual
+    )]
+    PcrMismatch {
+        /// The value the PCR was expected to have
+        expected: Pcr,
+        /// The value the PCR actually had
+        actual: Pcr,
+    },
+    /// Launching the enclave was cancelled through the builder's
+    /// cancellation token
+    #[error(display = "Nitro: launch cancelled")]
+    Cancelled,
+    /// The enclave can't be restarted, as it wasn't launched by this process
+    /// from an EIF file
+    #[error(
+        display = "Nitro: only enclaves launched by this process from a file can be restarted"
+    )]
+    RestartUnsupported,
+    /// No enclave is running, as a restart terminated the old one and failed
+    /// to launch its replacement
+    #[error(display = "Nitro: no enclave is running after a failed restart")]
+    NotRunning,
+    /// The Nitro CLI tool reported success, but printed nothing for the
+    /// given command
+    #[error(display = "Nitro: {} succeeded but produced no output", _0)]
+    EmptyCliOutput(String),
+    /// Connecting to the enclave took longer than the timeout
+    #[error(display = "Nitro: timed out connecting to the enclave")]
+    ConnectTimedOut,
+    /// The settings given for the enclave can't be used to launch it
+    #[error(display = "Nitro: invalid launch settings: {}", _0)]
+    InvalidLaunchSettings(String),
+    /// The CPU IDs given for the enclave can't be used
+    #[error(display = "Nitro: invalid CPU IDs: {}", _0)]
+    InvalidCpuIds(String),
+    /// Too little of the memory reserved for enclaves is free to launch the
+    /// enclave
+    #[error(
+        display = "Nitro: insufficient reserved memory: {} MiB requested, {} MiB free",
+        requested_mib,
+        available_mib
+    )]
+    InsufficientReservedMemory {
+        /// The memory requested for the enclave, in MiB
+        requested_mib: u32,
+        /// The reserved memory not allocated to running enclaves, in MiB
+        available_mib: u64,
+    },
+    /// The enclave answered a diagnostics request with a typed buffer of the
+    /// given type, rather than `DIAGNOSTICS_RESPONSE_FRAME_TYPE`
+    #[error(
+        display = "Nitro: expected a diagnostics response, received a frame of type {:#04x}",
+        _0
+    )]
+    UnexpectedDiagnosticsResponse(u8),
+    /// The enclave answered a ping with something other than a pong echoing
+    /// the ping's contents
+    #[error(display = "Nitro: the enclave's reply to a ping was not the matching pong")]
+    UnexpectedPong,
+    /// The Nitro CLI tool reported a CID that no enclave can have
+    #[error(display = "Nitro: {} is not a valid enclave CID", _0)]
+    InvalidCid(u64),
+    /// A session recording was not in the format written by
+    /// `RecordingTransport`
+    #[error(display = "Nitro: malformed session recording")]
+    MalformedRecording,
+    /// The Nitro CLI tool's output isn't in the form this crate expects
+    #[error(display = "Nitro: incompatible Nitro CLI: {}", _0)]
+    IncompatibleCli(String),
+    /// Holding another enclave would exceed the limit set with
+    /// `set_live_enclave_limit`
+    #[error(
+        display = "Nitro: this process already holds the maximum of {} enclaves",
+        _0
+    )]
+    TooManyEnclaves(usize),
+    /// The installed Nitro CLI tool doesn't have the command needed
+    #[error(display = "Nitro: the Nitro CLI tool has no {} command", _0)]
+    CliCommandUnsupported(String),
+    /// The enclave's reply to `NitroEnclave::verify_connection` showed the
+    /// connection not to be to the expected enclave
+    #[error(display = "Nitro: connection verification failed: {}", _0)]
+    ConnectionVerificationFailed(String),
+    /// The enclave did not acknowledge a buffer sent with
+    /// `send_buffer_acked` within the timeout
+    #[error(display = "Nitro: no acknowledgment from the enclave within {:?}", _0)]
+    AckTimeout(Duration),
+    /// The enclave rejected a buffer sent with `send_buffer_acked`, for the
+    /// given reason
+    #[error(display = "Nitro: the enclave rejected the buffer: {}", _0)]
+    MessageRejected(String),
+    /// The enclave answered a buffer sent with `send_buffer_acked` with
+    /// something other than an acknowledgment
+    #[error(display = "Nitro: the enclave's reply to a buffer was not an acknowledgment")]
+    UnexpectedAck,
+    /// No chunk of a stream arrived within the per-chunk timeout, so the
+    /// enclave appears to have stalled
+    #[error(display = "Nitro: no chunk of the stream received for {:?}", _0)]
+    StreamStalled(Duration),
+    /// The stream didn't end within the overall timeout
+    #[error(display = "Nitro: the stream did not end within {:?}", _0)]
+    StreamTimedOut(Duration),
+    /// A buffer in a stream was of the given type, which is neither
+    /// `STREAM_CHUNK_FRAME_TYPE` nor `STREAM_END_FRAME_TYPE`
+    #[error(display = "Nitro: unexpected buffer of type {:#x} in a stream", _0)]
+    UnexpectedStreamFrame(u8),
+    /// The host's kernel can't create VSOCKs, so enclaves can't be connected
+    /// to; the message says what is missing and how to fix it
+    #[error(display = "Nitro: VSOCK is not supported on this host: {}", _0)]
+    VsockUnsupported(String),
+    /// This process lacks the permissions needed to manage enclaves; the
+    /// message says what is inaccessible and how to fix it
+    #[error(display = "Nitro: permission denied: {}", _0)]
+    PermissionDenied(String),
+    /// The Nitro CLI process with the given process ID finished, but
+    /// something else in this process reaped it before its exit status could
+    /// be collected
+    #[error(
+        display = "Nitro: the exit status of Nitro CLI process {} was taken by another part of this process (is SIGCHLD ignored, or reaped by a handler?)",
+        _0
+    )]
+    ChildStatusLost(u32),
+    /// The installed Nitro CLI tool doesn't have the option needed, given
+    /// with the command it belongs to
+    #[error(display = "Nitro: the Nitro CLI tool has no {} option", _0)]
+    CliOptionUnsupported(String),
+    /// The CID requested for an enclave is already that of a running
+    /// enclave
+    #[error(display = "Nitro: enclave CID {} is already in use", _0)]
+    CidInUse(u32),
+    /// The Nitro CLI tool launched the enclave with a CID other than the one
+    /// requested
+    #[error(
+        display = "Nitro: enclave CID {} was requested, but the enclave was given CID {}",
+        requested,
+        actual
+    )]
+    CidMismatch {
+        /// The CID requested
+        requested: u32,
+        /// The CID the enclave was given
+        actual: u32,
+    },
+    /// Nitro enclaves are not supported on this platform
+    #[error(display = "Nitro: Nitro enclaves are only supported on Linux")]
+    Unsupported,
+}
+
+/// A sink for the raw output of the Nitro CLI tool
+pub type DiagnosticsWriter = Box<dyn Write + Send>;
+
+/// How a failed attempt to launch an enclave is handled, as decided by a
+/// `StderrClassifier` from what the Nitro CLI tool wrote to stderr
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The failure may be transient (e.g. "resource temporarily
+    /// unavailable"), so the launch is retried as the retry policy allows
+    Retryable,
+    /// Retrying can't help (e.g. "enclave device not found"), so the launch
+    /// fails straight away
+    Fatal,
+}
+
+/// Decides from the stderr of a failed `run-enclave` whether to retry it
+pub type StderrClassifier = Box<dyn Fn(&str) -> ErrorClass + Send + Sync>;
+
+/// The number of CPUs allocated to an enclave unless the builder says
+/// otherwise
+pub const DEFAULT_CPU_COUNT: u32 = 2;
+
+/// The least memory (in MiB) the Nitro CLI tool will allocate to an enclave
+pub const MIN_ENCLAVE_MEMORY_MIB: u32 = 64;
+
+/// The fields of the JSON output of `nitro-cli run-enclave` that launching an
+/// enclave can't do without. Others (such as `MemoryMiB`) are read where
+/// present.
+pub const REQUIRED_RUN_ENCLAVE_FIELDS: &[&str] = &["EnclaveID", "EnclaveCID"];
+
+/// The fields of each enclave in the JSON output of
+/// `nitro-cli describe-enclaves` that attaching to or waiting for an enclave
+/// can't do without. Others (such as `Flags`) are read where present.
+pub const REQUIRED_DESCRIBE_ENCLAVES_FIELDS: &[&str] = &["EnclaveID", "EnclaveCID", "State"];
+
+/// The type (in the sense of `raw_fd::send_typed_buffer`) of the buffer
+/// `NitroEnclave::request_diagnostics` sends to ask the enclave for
+/// diagnostics. Its contents are empty.
+pub const DIAGNOSTICS_REQUEST_FRAME_TYPE: u8 = 0xd1;
+
+/// The type of the buffer with which an enclave answers a diagnostics
+/// request. Its contents are whatever logs or state the enclave chooses to
+/// expose.
+pub const DIAGNOSTICS_RESPONSE_FRAME_TYPE: u8 = 0xd2;
+
+/// The type (in the sense of `raw_fd::send_typed_buffer`) of the buffer
+/// `NitroEnclave::ping` sends to check that the enclave is responsive. Its
+/// contents are 8 bytes chosen by the host.
+pub const PING_FRAME_TYPE: u8 = 0xf0;
+
+/// The type of the buffer with which an enclave answers a ping. Its contents
+/// must be those of the ping, unchanged.
+pub const PONG_FRAME_TYPE: u8 = 0xf1;
+
+/// The type (in the sense of `raw_fd::send_typed_buffer`) of the buffer
+/// `NitroEnclave::verify_connection` sends to check which enclave it is
+/// connected to. Its contents are a challenge chosen by the host.
+pub const VERIFY_REQUEST_FRAME_TYPE: u8 = 0xe1;
+
+/// The type of the buffer with which an enclave answers a verification
+/// request. Its contents must be the challenge, unchanged, followed by the
+/// raw bytes of the enclave's PCR0.
+pub const VERIFY_RESPONSE_FRAME_TYPE: u8 = 0xe2;
+
+/// The type (in the sense of `raw_fd::send_typed_buffer`) of the buffer
+/// `NitroEnclave::send_buffer_acked` sends. Its contents are the buffer
+/// being sent, which the enclave must answer with an `ACK_FRAME_TYPE` or
+/// `NACK_FRAME_TYPE` buffer once it has processed it.
+pub const ACKED_DATA_FRAME_TYPE: u8 = 0xa1;
+
+/// The type of the buffer with which an enclave confirms that it received
+/// and accepted an `ACKED_DATA_FRAME_TYPE` buffer. Its contents are empty.
+pub const ACK_FRAME_TYPE: u8 = 0xa2;
+
+/// The type of the buffer with which an enclave reports that it received
+/// an `ACKED_DATA_FRAME_TYPE` buffer but rejected it. Its contents are the
+/// reason, as UTF-8 text.
+pub const NACK_FRAME_TYPE: u8 = 0xa3;
+
+/// The type (in the sense of `raw_fd::send_typed_buffer`) of the buffer
+/// `NitroEnclave::transact_multi` sends. Its contents are the request, which
+/// the enclave must answer with any number of `STREAM_CHUNK_FRAME_TYPE`
+/// buffers, one for each response, followed by a `STREAM_END_FRAME_TYPE`
+/// buffer.
+pub const TRANSACT_REQUEST_FRAME_TYPE: u8 = 0xc0;
+
+/// The type (in the sense of `raw_fd::send_typed_buffer`) of each buffer
+/// of streamed output an enclave sends for `NitroEnclave::collect_stream`.
+/// Its contents are the next chunk of output.
+pub const STREAM_CHUNK_FRAME_TYPE: u8 = 0xc1;
+
+/// The type of the buffer with which an enclave ends a stream of
+/// `STREAM_CHUNK_FRAME_TYPE` buffers. Its contents are empty.
+pub const STREAM_END_FRAME_TYPE: u8 = 0xc2;
+
+/// How long `NitroEnclave::send_buffer_acked` waits for the enclave to
+/// acknowledge a buffer
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long `NitroEnclave::transact_multi` waits for the enclave to finish
+/// responding to a request
+pub const TRANSACT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A suggested location for the leaked enclave registry
+pub const DEFAULT_LEAK_REGISTRY_PATH: &str = "/run/nitro-enclave-crate/active";
+
+/// Exponential back-off with jitter between retries of Nitro CLI commands.
+/// Spreading out retries keeps a fleet of hosts reacting to the same event
+/// from hammering the CLI and allocator in lock-step, and gives slow
+/// transient conditions longer to clear on later attempts.
+#[derive(Clone, Debug)]
+pub struct RetryBackoff {
+    /// The delay before the first retry, doubled on each subsequent retry
+    pub base_delay: Duration,
+    /// The longest delay between retries, before jitter is applied
+    pub max_delay: Duration,
+    /// The fraction (between 0 and 1) by which each delay is randomly
+    /// lengthened or shortened
+    pub jitter: f64,
 }
 
-/// a struct for holding all of the information about a nitro enclave
-pub struct NitroEnclave {
-    /// The enclave ID, as generated from the Nitro CLI tool when the enclave
-    /// is created - it's the EC2-instance ID appended with an enclave-specific
-    /// value
-    enclave_id: String,
-    /// A convenience struct for handling VSOCK connections to the enclave
-    vsocksocket: vsocket::VsockSocket,
-    /// the path to the Nitro CLI function. Not all AMI images have it in the
-    /// same place in the file system, so we need to keep track of it
-    nitro_cli_path: String,
-}
-
-/// Delay (in seconds) before terminating this process with SIGALRM if
-/// the attempt to "connect" to the enclave does not return.
-const NITRO_ENCLAVE_CONNECT_TIMEOUT: u32 = 30;
-
-impl NitroEnclave {
-    /// create a new Nitro enclave, started with the file in eif_path
-    /// * `eif_path` - path to the EIF file that will be started in the enclave
-    /// * `debug` - indicates of the enclave will be started in debug mode
-    /// * `max_memory_mib` - The amount of memory to be allocated to the enclave
-    /// * `port` - The port number that will be used to communicate with the enclave (The code in the EIF should be 
-    ///            written to communicate on this port as well)
-    pub fn new(eif_path: &str, debug: bool, max_memory_mib: u32, port: u32) -> Result<Self> {
-        let max_memory_mib_str = max_memory_mib.to_string();
-        let mut args = vec![
-            "run-enclave",
-            "--eif-path",
-            eif_path,
-            "--cpu-count",
-            "2",
-            "--memory",
-            &max_memory_mib_str,
-        ];
-        if debug {
-            args.push("--debug-mode=true");
+impl Default for RetryBackoff {
+    fn default() -> Self {
+        RetryBackoff {
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.2,
         }
-        let nitro_cli_path = "/usr/bin/nitro-cli";
-        let stdout = loop {
-            let enclave_result = Command::new(nitro_cli_path)
-                .args(&args)
-                .output()
-                .map_err(|err| err);
-            match enclave_result {
-                Err(err) => {
-                    println!("NitroEnclave::new failed to start enclave:{:?}", err);
-                    println!("sleeping before trying again");
-                    std::thread::sleep(std::time::Duration::from_millis(1000));
-                    continue;
-                }
-                Ok(result) => {
-                    if !result.status.success() {
-                        let enclave_result_stderr = std::str::from_utf8(&result.stderr)?;
-                        println!("NitroEnclave::new CLI error:{:?}", enclave_result_stderr);
-                        println!("sleeping before trying again");
-                        std::thread::sleep(std::time::Duration::from_millis(1000));
-                        continue;
-                    } else {
-                        break result.stdout;
-                    }
-                }
-            }
-        };
-
-        let enclave_result_stdout = std::str::from_utf8(&stdout)?;
-        println!("enclave_result_stdout:{:?}", enclave_result_stdout);
-
-        let enclave_data: Value = serde_json::from_str(enclave_result_stdout)?;
-        let cid: u32 = if !enclave_data["EnclaveCID"].is_number() {
-            return Err(anyhow!(NitroError::SerdeError));
-        } else {
-            serde_json::from_value(enclave_data["EnclaveCID"].clone()).unwrap()
-        };
-
-        alarm::set(NITRO_ENCLAVE_CONNECT_TIMEOUT);
-        let vsocket = vsocket::VsockSocket::connect(cid, port)?;
-        alarm::cancel();
-
-        let enclave: Self = NitroEnclave {
-            enclave_id: enclave_data["EnclaveID"]
-                .to_string()
-                .trim_matches('"')
-                .to_string(),
-            vsocksocket: vsocket,
-            nitro_cli_path: nitro_cli_path.to_string(),
-        };
-        Ok(enclave)
     }
+}
 
-    /// send a buffer of data to the enclave
-    pub fn send_buffer(&self, buffer: &[u8]) -> Result<()> {
-        crate::raw_fd::send_buffer(self.vsocksocket.as_raw_fd(), buffer)
+impl RetryBackoff {
+    /// The delay to wait after the `attempt`th failure (counting from zero)
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .checked_mul(1 << attempt.min(31))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        // `RandomState` is randomly seeded, which is all the randomness needed
+        let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        delay.mul_f64(1.0 - jitter + 2.0 * jitter * random)
     }
+}
+
+/// The number of times a failed Nitro CLI command is retried by default
+pub const DEFAULT_MAX_RETRIES: u32 = 5;
 
-    /// receive a buffer of data from the enclave
-    pub fn receive_buffer(&self) -> Result<Vec<u8>> {
-        crate::raw_fd::receive_buffer(self.vsocksocket.as_raw_fd())
+/// How many times to retry a Nitro CLI command (to launch or terminate an
+/// enclave) that fails
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetryPolicy {
+    /// Try once, and report the failure
+    None,
+    /// Retry up to the given number of times, then report the last failure
+    Bounded(u32),
+    /// Retry until the command succeeds, logging each failure
+    Unbounded,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::Bounded(DEFAULT_MAX_RETRIES)
     }
 }
 
-impl Drop for NitroEnclave {
-    /// Drop the enclave. In ideal conditions, this means that the enclave will
-    /// be terminated.
-    fn drop(&mut self) {
-        // shutdown the enclave
-        loop {
-            let enclave_result = Command::new(&self.nitro_cli_path)
-                .args(&["terminate-enclave", "--enclave-id", &self.enclave_id])
-                .output();
-            match enclave_result {
-                Err(err) => {
-                    println!("NitroEnclave::drop Command::new returned err:{:?}, sleeping and trying again", err);
-                    std::thread::sleep(std::time::Duration::from_millis(1000));
-                    continue;
-                }
-                Ok(result) => {
-                    if !result.status.success() {
-                        println!("NitroEnclave::drop failed to terminate the enclave (exit_status:{:?}. You will need to terminate it yourself.", result.status);
-                        let result_stderr = std::str::from_utf8(&result.stderr).unwrap();
-                        println!("NitroEnclave::drop CLI error:{:?}", result_stderr);
-                    }
-                    break;
-                }
-            }
+impl RetryPolicy {
+    /// Whether another retry is allowed after `retries` retries
+    pub fn allows_retry(&self, retries: u32) -> bool {
+        match self {
+            RetryPolicy::None => false,
+            RetryPolicy::Bounded(max_retries) => retries < *max_retries,
+            RetryPolicy::Unbounded => true,
         }
     }
 }
 
+/// What building an enclave does if the enclave launches but can't be
+/// connected to, which often means that it came up in a bad state
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConnectFailurePolicy {
+    /// Terminate the enclave, and report the failure
+    #[default]
+    Fail,
+    /// Terminate the enclave, and launch and connect to a new one in its
+    /// place, up to the given number of times, then report the last failure
+    TeardownAndRetry(u32),
+}
+
+/// What is done when a run of the Nitro CLI tool finishes but something
+/// else in the process has already reaped it, so that its exit status is
+/// lost: set with `set_child_status_policy`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChildStatusPolicy {
+    /// Fail with `NitroError::ChildStatusLost`
+    #[default]
+    Fail,
+    /// Treat the run as successful, and judge it by its output alone, for
+    /// applications that can't stop reaping children. A failed run then
+    /// shows up as output that can't be parsed, if at all.
+    UseOutput,
+}
+
+/// A description of a successful launch, as reported by the Nitro CLI tool,
+/// for logging or metrics
+#[derive(Clone, Debug)]
+pub struct LaunchReport {
+    /// The enclave ID
+    pub enclave_id: String,
+    /// The CID the enclave was assigned
+    pub cid: u32,
+    /// The amount of memory (in MiB) actually allocated to the enclave, as
+    /// read back from the Nitro CLI tool once it was running, or else as
+    /// reported at launch, or failing both the amount asked for
+    pub memory_mib: Option<u32>,
+    /// The number of CPUs actually allocated to the enclave, found out as
+    /// for `memory_mib`. This is `None` only if the tool said nothing and it
+    /// was left to choose.
+    pub cpu_count: Option<u32>,
+    /// The enclave's measurements, by name (e.g. `PCR0`), if reported
+    pub measurements: BTreeMap<String, Pcr>,
+    /// The number of attempts it took to launch the enclave, including the
+    /// successful one
+    pub attempts: u32,
+}
+
+/// Something that buffers of data can be sent to and received from, such as
+/// an enclave. This trait is object safe, so code can hold a
+/// `Box<dyn Transport>` and be handed either a real `NitroEnclave` or a test
+/// double at runtime.
+pub trait Transport {
+    /// Send a buffer of data to the peer
+    fn send(&self, buffer: &[u8]) -> Result<()>;
+
+    /// Receive a buffer of data from the peer
+    fn recv(&self) -> Result<Vec<u8>>;
+
+    /// Receive a buffer of data from the peer, as `recv` does, passing `tap`
+    /// the raw bytes read from the connection to the peer, framing and all,
+    /// including those of a buffer that fails to arrive in full or to decode.
+    /// By default nothing is passed, for transports (such as test doubles)
+    /// with no connection underneath.
+    fn recv_tapped(&self, tap: &mut dyn FnMut(&[u8])) -> Result<Vec<u8>> {
+        let _ = tap;
+        self.recv()
+    }
+}
+
+/// How waiting for an enclave to exit ended
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EnclaveExit {
+    /// The enclave is no longer running. `last_state` is the state the Nitro
+    /// CLI tool reports it to be in (such as `TERMINATING`), or `None` if it
+    /// is no longer reported at all. The Nitro CLI tool doesn't say whether
+    /// the enclave was terminated or crashed.
+    Exited {
+        /// The state the enclave was last reported in, if any
+        last_state: Option<String>,
+    },
+    /// The enclave was still running when the timeout elapsed
+    StillRunning,
+}
+
+/// The result of checking whether this host is able to run Nitro enclaves
+#[derive(Clone, Debug)]
+pub struct PreflightReport {
+    /// Whether the Nitro enclaves device is present
+    pub device_present: bool,
+    /// Whether this process has the permissions needed to manage enclaves
+    pub has_permission: bool,
+    /// The amount of memory (in MiB) reserved in huge pages, which is where
+    /// the allocator service puts the memory it sets aside for enclaves
+    pub reserved_memory_mib: u64,
+    /// Whether `nitro-cli describe-enclaves` ran successfully
+    pub cli_working: bool,
+    /// Whether the kernel supports VSOCKs, over which enclaves are reached
+    pub vsock_supported: bool,
+    /// A human-readable description of everything found to be missing
+    pub problems: Vec<String>,
+}
+
+impl PreflightReport {
+    /// Whether no problems were found
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Measurements.
+////////////////////////////////////////////////////////////////////////////////
+
+/// The value of a platform configuration register (PCR), such as PCR0, the
+/// measurement of an enclave image. PCRs are compared as raw bytes, so two
+/// values parsed from hex strings are equal regardless of the case of their
+/// digits or any whitespace in the strings.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Pcr(Vec<u8>);
+
+impl Pcr {
+    /// Wrap the raw bytes of a PCR value
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Pcr(bytes)
+    }
+
+    /// Parse a PCR value from a hexadecimal string, as reported by the Nitro
+    /// CLI tool. Digits may be upper or lower case, and whitespace anywhere
+    /// in the string is ignored. Fails with `NitroError::InvalidPcr` if the
+    /// string contains anything else, or an odd number of digits.
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let invalid = || anyhow!(NitroError::InvalidPcr(hex.to_string()));
+        let digits = hex
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .map(|c| c.to_digit(16).map(|digit| digit as u8))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(invalid)?;
+        if digits.len() % 2 != 0 {
+            return Err(invalid());
+        }
+        Ok(Pcr(digits
+            .chunks(2)
+            .map(|pair| (pair[0] << 4) | pair[1])
+            .collect()))
+    }
+
+    /// Format the PCR value as a lower-case hexadecimal string
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    /// The raw bytes of the PCR value
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Check that the PCR value is `expected`, failing with
+    /// `NitroError::PcrMismatch` if it isn't
+    pub fn verify(&self, expected: &Pcr) -> Result<()> {
+        if self != expected {
+            return Err(anyhow!(NitroError::PcrMismatch {
+                expected: expected.clone(),
+                actual: self.clone(),
+            }));
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Pcr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
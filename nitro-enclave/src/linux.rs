@@ -0,0 +1,3301 @@
+//! The implementation of Nitro enclave management on Linux, the only
+//! platform on which Nitro enclaves can run.
+//!
+//! ## Authors
+//!
+//! The Veracruz Development Team.
+//!
+//! ## Licensing and copyright notice
+//!
+//! See the `LICENSE_MIT.markdown` file in the root directory for
+//! information on licensing and copyright.
+
+use crate::{
+    ChildStatusPolicy, ConnectFailurePolicy, DiagnosticsWriter, EnclaveExit, ErrorClass,
+    LaunchReport, NitroError, Pcr, PreflightReport, RetryBackoff, RetryPolicy, StderrClassifier,
+    Transport, ACKED_DATA_FRAME_TYPE, ACK_FRAME_TYPE, ACK_TIMEOUT, DEFAULT_CPU_COUNT,
+    DIAGNOSTICS_REQUEST_FRAME_TYPE, DIAGNOSTICS_RESPONSE_FRAME_TYPE, MIN_ENCLAVE_MEMORY_MIB,
+    NACK_FRAME_TYPE, PING_FRAME_TYPE, PONG_FRAME_TYPE, REQUIRED_DESCRIBE_ENCLAVES_FIELDS,
+    STREAM_CHUNK_FRAME_TYPE, STREAM_END_FRAME_TYPE, TRANSACT_REQUEST_FRAME_TYPE, TRANSACT_TIMEOUT,
+    VERIFY_REQUEST_FRAME_TYPE, VERIFY_RESPONSE_FRAME_TYPE,
+};
+use anyhow::{anyhow, Context, Result};
+use log::{debug, error, trace, warn};
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+    poll::PollFlags,
+    sys::{
+        signal::kill,
+        socket::{
+            getpeername, shutdown, socket, AddressFamily, Shutdown, SockFlag, SockType, VsockAddr,
+        },
+    },
+    unistd::{access, close, AccessFlags, Gid, Group, Pid},
+};
+use raw_fd::{RawFdError, TransferConfig};
+use serde_json::{json, Value};
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, Write},
+    mem::ManuallyDrop,
+    os::unix::fs::MetadataExt,
+    os::unix::io::{AsRawFd, RawFd},
+    os::unix::process::{CommandExt, ExitStatusExt},
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Output, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(feature = "leak-check")]
+use std::sync::atomic::AtomicUsize;
+
+/// A running enclave, terminated when this is dropped
+struct EnclaveProcess {
+    /// The enclave ID, as generated from the Nitro CLI tool when the enclave
+    /// is created - it's the EC2-instance ID appended with an enclave-specific
+    /// value
+    enclave_id: String,
+    /// the path to the Nitro CLI function. Not all AMI images have it in the
+    /// same place in the file system, so we need to keep track of it
+    nitro_cli_path: String,
+    /// Where the raw output of the Nitro CLI tool is written, if anywhere
+    diagnostics_writer: Option<DiagnosticsWriter>,
+    /// The leaked enclave registry this enclave is recorded in, if any
+    leak_registry: Option<PathBuf>,
+    /// The audit log the termination of this enclave is recorded in, if any
+    audit_log: Option<PathBuf>,
+    /// How long to wait between attempts to terminate the enclave
+    retry_backoff: RetryBackoff,
+    /// How many times to try to terminate the enclave
+    retry_policy: RetryPolicy,
+    /// Whether dropping this starts the termination without waiting for it
+    async_terminate: bool,
+    /// Whether dropping this terminates the enclave at all: not once the
+    /// caller has taken responsibility for it with `NitroEnclave::into_socket`
+    terminate_on_drop: bool,
+    /// How long each attempt to terminate the enclave may take before the
+    /// Nitro CLI tool is killed
+    terminate_timeout: Duration,
+    /// This enclave's entry in the ports in use by this process, if its port
+    /// is known
+    _port_claim: Option<PortClaim>,
+    /// This enclave's place in the count of live enclaves
+    #[cfg(feature = "leak-check")]
+    _live: LiveEnclave,
+    /// The memory allocated to the enclave, in MiB, if it could be found out
+    memory_mib: Option<u32>,
+    /// The number of CPUs allocated to the enclave, if it could be found out
+    cpu_count: Option<u32>,
+    /// Whether the enclave is running in debug mode, or `None` if that isn't
+    /// known
+    debug: Option<bool>,
+    /// The measurement of the enclave image (PCR0), if the Nitro CLI tool
+    /// reported it at launch
+    pcr0: Option<Pcr>,
+}
+
+/// An enclave that has been launched but not yet connected to. The only thing
+/// that can be done with it is to `connect` to it, which produces a
+/// `ConnectedEnclave`. Dropping it terminates the enclave.
+pub struct LaunchedEnclave {
+    /// The running enclave
+    process: EnclaveProcess,
+    /// The CID the enclave was assigned by the Nitro CLI tool
+    cid: u32,
+    /// The port number that will be used to communicate with the enclave
+    port: u32,
+    /// How the connection to the enclave will be set up
+    connection: ConnectionOptions,
+    /// The settings the enclave was launched with, if it was launched by
+    /// this process
+    settings: Option<LaunchSettings>,
+}
+
+/// Options for the connection to an enclave, set on the builder and applied
+/// when a `LaunchedEnclave` is connected to
+#[derive(Clone, Debug, Default)]
+struct ConnectionOptions {
+    /// A buffer to send to the enclave as soon as it is connected to
+    initial_config: Option<Vec<u8>>,
+    /// A buffer to send to the enclave before disconnecting from it
+    shutdown_frame: Option<Vec<u8>>,
+    /// The requested size of the kernel's send buffer for the VSOCK
+    send_buffer_size: Option<usize>,
+    /// The requested size of the kernel's receive buffer for the VSOCK
+    receive_buffer_size: Option<usize>,
+    /// How long to give the enclave to initialize once it is connected to
+    warmup_delay: Option<Duration>,
+}
+
+/// a struct for holding all of the information about a nitro enclave.
+///
+/// When dropped, the enclave is torn down in this order:
+/// 1. the shutdown frame, if one was configured, is sent to the enclave
+///    (failures are ignored);
+/// 2. the VSOCK is shut down in both directions, so the enclave observes a
+///    clean disconnect rather than having its peer vanish;
+/// 3. the enclave is terminated with the Nitro CLI tool;
+/// 4. the VSOCK is closed.
+pub struct NitroEnclave {
+    /// The running enclave, or `None` if a restart terminated the enclave but
+    /// failed to launch its replacement
+    process: Option<EnclaveProcess>,
+    /// A convenience struct for handling VSOCK connections to the enclave
+    vsocksocket: vsocket::VsockSocket,
+    /// The CID the enclave was assigned by the Nitro CLI tool
+    cid: u32,
+    /// The port number used to communicate with the enclave
+    port: u32,
+    /// How the connection to the enclave was set up
+    connection: ConnectionOptions,
+    /// The settings the enclave was launched with, if it was launched by
+    /// this process
+    settings: Option<LaunchSettings>,
+}
+
+/// A launched enclave that has been connected to, and so can be sent data
+pub type ConnectedEnclave = NitroEnclave;
+
+/// Delay before terminating this process with `connect_timeout_signal()` if
+/// the attempt to "connect" to the enclave does not return.
+pub const NITRO_ENCLAVE_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long each run of `terminate-enclave` made when an enclave is dropped
+/// may take before the Nitro CLI tool is killed, unless the builder says
+/// otherwise
+pub const DEFAULT_TERMINATE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often a Nitro CLI command run with a timeout is checked for completion
+const CLI_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How often `NitroEnclave::wait_exit` checks whether the enclave is running
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often a sleep between launch attempts checks the cancellation token
+const CANCELLATION_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The lowest CID an enclave can have: 0, 1 and 2 are reserved for the
+/// hypervisor, local communication and the host
+const MIN_ENCLAVE_CID: u64 = 3;
+
+/// The lowest CID an enclave can be launched with on request: 3 is also the
+/// parent instance's
+const MIN_REQUESTED_ENCLAVE_CID: u32 = 4;
+
+/// The default location of the Nitro CLI tool
+const DEFAULT_NITRO_CLI_PATH: &str = "/usr/bin/nitro-cli";
+
+/// The device through which the Nitro CLI tool talks to the hypervisor
+const NITRO_ENCLAVES_DEVICE: &str = "/dev/nitro_enclaves";
+
+/// The directories in which the Nitro CLI tool keeps its logs and state,
+/// which the user managing enclaves must be able to write to
+const NITRO_CLI_DIRECTORIES: &[&str] = &["/var/log/nitro_enclaves", "/run/nitro_enclaves"];
+
+/// The device the kernel's VSOCK support provides
+const VSOCK_DEVICE: &str = "/dev/vsock";
+
+/// The configuration file of the Nitro enclaves allocator service
+const ALLOCATOR_CONFIG_PATH: &str = "/etc/nitro_enclaves/allocator.yaml";
+
+/// The directory in which the kernel reports the huge pages reserved by the
+/// Nitro enclaves allocator service
+const HUGEPAGES_SYSFS_DIR: &str = "/sys/kernel/mm/hugepages";
+
+/// Write the raw stdout and stderr of a Nitro CLI invocation to `writer`, if
+/// there is one. Returns `false` if there is no writer, in which case the
+/// caller should report the output itself. Failing to write the diagnostics
+/// is not fatal.
+fn record_cli_output<S: AsRef<str>>(
+    writer: &mut Option<DiagnosticsWriter>,
+    args: &[S],
+    output: &Output,
+) -> bool {
+    let writer = match writer {
+        Some(writer) => writer,
+        None => return false,
+    };
+    let result = writeln!(
+        writer,
+        "nitro-cli {} (exit_status:{:?})",
+        args.iter().map(AsRef::as_ref).collect::<Vec<_>>().join(" "),
+        output.status
+    )
+    .and_then(|_| writeln!(writer, "--- stdout ---"))
+    .and_then(|_| writer.write_all(&output.stdout))
+    .and_then(|_| writeln!(writer, "--- stderr ---"))
+    .and_then(|_| writer.write_all(&output.stderr))
+    .and_then(|_| writer.flush());
+    if let Err(err) = result {
+        warn!("Failed to write Nitro CLI diagnostics: {:?}", err);
+    }
+    true
+}
+
+/// The signal raised if connecting to an enclave takes too long. The default
+/// disposition of a real-time signal is to terminate the process. Unlike
+/// `alarm()` and SIGALRM, this leaves any use of SIGALRM by the rest of the
+/// application alone: the timeout is a private POSIX timer, so a pending
+/// `alarm()` is neither cancelled nor delayed, and no signal handler is
+/// installed, so there is no previous disposition to restore afterwards.
+/// An application that handles this signal itself receives it instead of
+/// being terminated.
+pub fn connect_timeout_signal() -> i32 {
+    libc::SIGRTMIN()
+}
+
+/// A one-shot POSIX timer that raises `connect_timeout_signal()` when it
+/// expires. The timer is disarmed and deleted when this is dropped.
+struct ConnectTimer {
+    /// The kernel timer
+    timer_id: libc::timer_t,
+}
+
+impl ConnectTimer {
+    /// Create a timer and arm it to expire after `timeout`, which has
+    /// nanosecond resolution
+    fn arm(timeout: Duration) -> Result<Self> {
+        let mut timer_id: libc::timer_t = std::ptr::null_mut();
+        // sigevent contains padding fields that can only be zero-initialised
+        let mut event: libc::sigevent = unsafe { std::mem::zeroed() };
+        event.sigev_notify = libc::SIGEV_SIGNAL;
+        event.sigev_signo = connect_timeout_signal();
+        if unsafe { libc::timer_create(libc::CLOCK_MONOTONIC, &mut event, &mut timer_id) } != 0 {
+            return Err(anyhow!(std::io::Error::last_os_error()));
+        }
+        let timer = ConnectTimer { timer_id };
+
+        // a zero expiry would disarm the timer rather than fire immediately
+        let timeout = timeout.max(Duration::from_nanos(1));
+        let spec = libc::itimerspec {
+            it_interval: libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            },
+            it_value: libc::timespec {
+                tv_sec: timeout.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+                tv_nsec: timeout.subsec_nanos() as _,
+            },
+        };
+        if unsafe { libc::timer_settime(timer.timer_id, 0, &spec, std::ptr::null_mut()) } != 0 {
+            return Err(anyhow!(std::io::Error::last_os_error()));
+        }
+        Ok(timer)
+    }
+}
+
+impl Drop for ConnectTimer {
+    fn drop(&mut self) {
+        unsafe {
+            libc::timer_delete(self.timer_id);
+        }
+    }
+}
+
+/// The settings an enclave is launched with, which are kept so that it can
+/// be restarted with the same settings
+#[derive(Clone, Debug)]
+struct LaunchSettings {
+    /// Path to the EIF file that will be started in the enclave
+    eif_path: String,
+    /// Whether the enclave will be started in debug mode
+    debug: bool,
+    /// The amount of memory to be allocated to the enclave
+    max_memory_mib: u32,
+    /// The number of CPUs to be allocated to the enclave, or `None` to let
+    /// the Nitro CLI tool decide
+    cpu_count: Option<u32>,
+    /// The IDs of the CPUs to be allocated to the enclave, in place of a
+    /// number of CPUs
+    cpu_ids: Option<Vec<u32>>,
+    /// Whether the number of CPUs was set explicitly, to catch it being
+    /// combined with `cpu_ids`
+    cpu_count_set: bool,
+    /// The port number that will be used to communicate with the enclave
+    port: u32,
+    /// The CID to launch the enclave with, or `None` to let the Nitro CLI
+    /// tool choose one
+    enclave_cid: Option<u32>,
+    /// The path to the Nitro CLI tool
+    nitro_cli_path: String,
+    /// The leaked enclave registry to record the enclave in, if any
+    leak_registry: Option<PathBuf>,
+    /// The audit log to record the launch and termination in, if any
+    audit_log: Option<PathBuf>,
+    /// How long to wait between attempts to launch or terminate the enclave
+    retry_backoff: RetryBackoff,
+    /// How many times to try to launch or terminate the enclave
+    retry_policy: RetryPolicy,
+    /// What to do if the enclave launches but can't be connected to
+    connect_failure_policy: ConnectFailurePolicy,
+    /// How long `build` waits for the connection to the enclave
+    connect_timeout: Duration,
+    /// Whether to check that enough reserved memory is free before launching
+    check_reserved_memory: bool,
+    /// Whether to raise a request for less than `MIN_ENCLAVE_MEMORY_MIB` to
+    /// the minimum, rather than failing
+    auto_adjust_memory: bool,
+    /// Whether dropping the enclave starts its termination without waiting
+    /// for it
+    async_terminate: bool,
+    /// How long each attempt to terminate the enclave may take
+    terminate_timeout: Duration,
+    /// Decides whether a failed launch is retried, if set
+    stderr_classifier: Option<SharedClassifier>,
+}
+
+/// A `StderrClassifier` shared between the settings an enclave was launched
+/// with and their copies, so that it survives a restart
+#[derive(Clone)]
+struct SharedClassifier(Arc<StderrClassifier>);
+
+impl fmt::Debug for SharedClassifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StderrClassifier")
+    }
+}
+
+/// Validate `settings` and build from them the arguments to pass to the
+/// Nitro CLI tool to launch the enclave. Fails with
+/// `NitroError::InvalidLaunchSettings` if the path to the EIF file is empty,
+/// less than `MIN_ENCLAVE_MEMORY_MIB` of memory or no CPUs are requested, or
+/// the CID requested (if any) is reserved, and with
+/// `NitroError::InvalidCpuIds` if the CPU IDs, if any, can't be allocated to
+/// an enclave.
+fn build_run_enclave_args(settings: &LaunchSettings) -> Result<Vec<String>> {
+    let invalid = |reason: &str| {
+        Err(anyhow!(NitroError::InvalidLaunchSettings(
+            reason.to_string()
+        )))
+    };
+    if settings.eif_path.is_empty() {
+        return invalid("no EIF path given");
+    }
+    if settings.max_memory_mib < MIN_ENCLAVE_MEMORY_MIB {
+        return invalid(&format!(
+            "{} MiB of memory requested, below the minimum of {} MiB",
+            settings.max_memory_mib, MIN_ENCLAVE_MEMORY_MIB
+        ));
+    }
+    if settings.cpu_count == Some(0) {
+        return invalid("no CPUs requested");
+    }
+    if let Some(cid) = settings.enclave_cid {
+        if cid < MIN_REQUESTED_ENCLAVE_CID || cid == vsocket::VMADDR_CID_ANY {
+            return invalid(&format!(
+                "enclave CID {} requested, but CIDs below {} (and {}) are reserved",
+                cid,
+                MIN_REQUESTED_ENCLAVE_CID,
+                vsocket::VMADDR_CID_ANY
+            ));
+        }
+    }
+    check_cpu_ids(settings)?;
+
+    let mut args = vec![
+        "run-enclave".to_string(),
+        "--eif-path".to_string(),
+        settings.eif_path.clone(),
+    ];
+    if let Some(cpu_ids) = &settings.cpu_ids {
+        args.push("--cpu-ids".to_string());
+        args.extend(cpu_ids.iter().map(|cpu_id| cpu_id.to_string()));
+    } else if let Some(cpu_count) = settings.cpu_count {
+        args.push("--cpu-count".to_string());
+        args.push(cpu_count.to_string());
+    }
+    args.push("--memory".to_string());
+    args.push(settings.max_memory_mib.to_string());
+    if let Some(cid) = settings.enclave_cid {
+        args.push("--enclave-cid".to_string());
+        args.push(cid.to_string());
+    }
+    if settings.debug {
+        args.push("--debug-mode=true".to_string());
+    }
+    Ok(args)
+}
+
+/// Raise the memory requested in `settings` to `MIN_ENCLAVE_MEMORY_MIB` if
+/// it is below the minimum and `auto_adjust_memory` is set, returning the
+/// amount originally requested if so
+fn adjust_memory(settings: &mut LaunchSettings) -> Option<u32> {
+    if !settings.auto_adjust_memory || settings.max_memory_mib >= MIN_ENCLAVE_MEMORY_MIB {
+        return None;
+    }
+    let requested = settings.max_memory_mib;
+    settings.max_memory_mib = MIN_ENCLAVE_MEMORY_MIB;
+    Some(requested)
+}
+
+/// Fail with `NitroError::InvalidCpuIds` if the CPU IDs in `settings`, if
+/// any, can't be allocated to an enclave
+fn check_cpu_ids(settings: &LaunchSettings) -> Result<()> {
+    let cpu_ids = match &settings.cpu_ids {
+        Some(cpu_ids) => cpu_ids,
+        None => return Ok(()),
+    };
+    let invalid = |reason: String| Err(anyhow!(NitroError::InvalidCpuIds(reason)));
+    if settings.cpu_count_set && settings.cpu_count.is_some() {
+        return invalid("cpu_ids and cpu_count are mutually exclusive".to_string());
+    }
+    if cpu_ids.is_empty() {
+        return invalid("no CPU IDs given".to_string());
+    }
+    // CPUs the kernel knows about, online or not: CPUs reserved for
+    // enclaves are taken offline by the allocator
+    let host_cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) };
+    for (index, cpu_id) in cpu_ids.iter().enumerate() {
+        if *cpu_id == 0 {
+            return invalid("CPU 0 can't be allocated to an enclave".to_string());
+        }
+        if host_cpus > 0 && i64::from(*cpu_id) >= host_cpus as i64 {
+            return invalid(format!("the host has no CPU {}", cpu_id));
+        }
+        if cpu_ids[..index].contains(cpu_id) {
+            return invalid(format!("CPU {} is given more than once", cpu_id));
+        }
+    }
+    Ok(())
+}
+
+/// A builder for launching a Nitro enclave with non-default settings
+pub struct NitroEnclaveBuilder {
+    /// The settings the enclave will be launched with
+    settings: LaunchSettings,
+    /// Where the raw output of the Nitro CLI tool is written, if anywhere
+    diagnostics_writer: Option<DiagnosticsWriter>,
+    /// How the connection to the enclave will be set up
+    connection: ConnectionOptions,
+    /// Set to abandon launching the enclave
+    cancellation: Option<Arc<AtomicBool>>,
+}
+
+impl NitroEnclaveBuilder {
+    /// Create a builder for an enclave started with the file in `eif_path`,
+    /// allocated `max_memory_mib` of memory and communicating on `port`
+    pub fn new(eif_path: &str, max_memory_mib: u32, port: u32) -> Self {
+        NitroEnclaveBuilder {
+            settings: LaunchSettings {
+                eif_path: eif_path.to_string(),
+                debug: false,
+                max_memory_mib,
+                cpu_count: Some(DEFAULT_CPU_COUNT),
+                cpu_ids: None,
+                cpu_count_set: false,
+                port,
+                enclave_cid: None,
+                nitro_cli_path: DEFAULT_NITRO_CLI_PATH.to_string(),
+                leak_registry: None,
+                audit_log: None,
+                retry_backoff: RetryBackoff::default(),
+                retry_policy: RetryPolicy::default(),
+                connect_failure_policy: ConnectFailurePolicy::default(),
+                connect_timeout: NITRO_ENCLAVE_CONNECT_TIMEOUT,
+                check_reserved_memory: false,
+                auto_adjust_memory: false,
+                async_terminate: false,
+                terminate_timeout: DEFAULT_TERMINATE_TIMEOUT,
+                stderr_classifier: None,
+            },
+            diagnostics_writer: None,
+            connection: ConnectionOptions::default(),
+            cancellation: None,
+        }
+    }
+
+    /// Set whether the enclave will be started in debug mode
+    pub fn debug(mut self, debug: bool) -> Self {
+        self.settings.debug = debug;
+        self
+    }
+
+    /// Set the number of CPUs to be allocated to the enclave (by default,
+    /// `DEFAULT_CPU_COUNT`). With `None`, no `--cpu-count` is passed to the
+    /// Nitro CLI tool, so it applies its own default based on the
+    /// allocator's configuration.
+    pub fn cpu_count(mut self, cpu_count: Option<u32>) -> Self {
+        self.settings.cpu_count = cpu_count;
+        self.settings.cpu_count_set = true;
+        self
+    }
+
+    /// Allocate exactly the CPUs with the given IDs to the enclave, by
+    /// passing `--cpu-ids` to the Nitro CLI tool instead of `--cpu-count`.
+    /// This gives deterministic CPU placement on multi-socket instances, for
+    /// example keeping the enclave on one NUMA node. The IDs must be CPUs
+    /// reserved for enclaves in the allocator's configuration. This can't be
+    /// combined with `cpu_count`: launching fails with
+    /// `NitroError::InvalidCpuIds` if both are set, or if the IDs are empty,
+    /// repeated, include CPU 0 (which always stays with the parent instance)
+    /// or name CPUs the host doesn't have.
+    pub fn cpu_ids(mut self, cpu_ids: &[u32]) -> Self {
+        self.settings.cpu_ids = Some(cpu_ids.to_vec());
+        self
+    }
+
+    /// Launch the enclave with the given CID, by passing `--enclave-cid` to
+    /// the Nitro CLI tool, rather than letting the tool choose one, for hosts
+    /// with firewall rules or routing tied to CIDs. Launching fails with
+    /// `NitroError::InvalidLaunchSettings` if the CID is reserved (below 4,
+    /// since 3 is the parent instance's), with `NitroError::CidInUse` if a
+    /// running enclave already has it, and with
+    /// `NitroError::CliOptionUnsupported` if the installed Nitro CLI tool is
+    /// too old to have the option. If the tool
+    /// gives the enclave some other CID regardless, the enclave is terminated
+    /// and launching fails with `NitroError::CidMismatch`. With `None` (the
+    /// default), the tool chooses.
+    pub fn enclave_cid(mut self, cid: Option<u32>) -> Self {
+        self.settings.enclave_cid = cid;
+        self
+    }
+
+    /// Set the path to the Nitro CLI tool
+    pub fn nitro_cli_path(mut self, nitro_cli_path: &str) -> Self {
+        self.settings.nitro_cli_path = nitro_cli_path.to_string();
+        self
+    }
+
+    /// Write the raw stdout and stderr of every Nitro CLI invocation made
+    /// during the lifetime of the enclave to `writer`, instead of printing
+    /// it
+    pub fn diagnostics_writer(mut self, writer: DiagnosticsWriter) -> Self {
+        self.diagnostics_writer = Some(writer);
+        self
+    }
+
+    /// As `diagnostics_writer`, but appending to the file at `path`, which is
+    /// created if it doesn't exist
+    pub fn diagnostics_file<P: AsRef<Path>>(self, path: P) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(self.diagnostics_writer(Box::new(file)))
+    }
+
+    /// Append a record of the launch of the enclave, and of its termination,
+    /// to the audit log at `path` (created if it doesn't exist), for an
+    /// audit trail of which enclaves ran when that doesn't depend on the
+    /// application's logging. Each record is one line of JSON, with an
+    /// `event` of `launch` or `terminate`, the `enclave_id`, and a
+    /// `timestamp_ms` in milliseconds since the Unix epoch. A launch record
+    /// also has the `cid`, `eif_path`, `memory_mib`, `cpu_count`, `debug` and
+    /// `measurements` (in hex, by name); a termination record has an
+    /// `outcome` of `terminated`, `already_terminated` or `failed`. If a
+    /// restart relaunches the enclave, both the termination and the new
+    /// launch are recorded. Writing a record is best-effort: a failure is
+    /// logged, and never fails the launch or termination.
+    pub fn audit_log<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.settings.audit_log = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Consult `classifier` with the stderr of each failed attempt to launch
+    /// the enclave: if it returns `ErrorClass::Fatal`, launching fails with
+    /// `NitroError::CliError` at once, without using up the retry policy. By
+    /// default every failure is retried.
+    pub fn stderr_classifier(mut self, classifier: StderrClassifier) -> Self {
+        self.settings.stderr_classifier = Some(SharedClassifier(Arc::new(classifier)));
+        self
+    }
+
+    /// Record the enclave's ID in the registry file at `path` while it is
+    /// running, so that if this process dies (or forgets the enclave) without
+    /// terminating it, a later call to `reap_leaked_enclaves` can do so.
+    /// `DEFAULT_LEAK_REGISTRY_PATH` is a reasonable choice of `path`.
+    pub fn leak_registry<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.settings.leak_registry = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Set how long to wait between failed attempts to launch the enclave,
+    /// and between failed attempts to terminate it
+    pub fn retry_backoff(mut self, retry_backoff: RetryBackoff) -> Self {
+        self.settings.retry_backoff = retry_backoff;
+        self
+    }
+
+    /// Set how many times a failed attempt to launch the enclave, or to
+    /// terminate it, is retried (by default,
+    /// `RetryPolicy::Bounded(DEFAULT_MAX_RETRIES)`). Once the retries are
+    /// used up, launching fails with the last error, and termination gives
+    /// up and reports that the enclave must be terminated by hand.
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.settings.retry_policy = retry_policy;
+        self
+    }
+
+    /// Set what `build` does if the enclave launches but can't be connected
+    /// to (by default, `ConnectFailurePolicy::Fail`). With
+    /// `ConnectFailurePolicy::TeardownAndRetry`, each enclave that can't be
+    /// connected to is terminated before the next is launched, so at most
+    /// one is running at a time, and a connection not made within the
+    /// connect timeout fails with `NitroError::ConnectTimedOut` and is
+    /// retried like any other failure. Otherwise, a connection that times
+    /// out terminates this process, as for `LaunchedEnclave::connect`.
+    pub fn on_connect_failure(mut self, policy: ConnectFailurePolicy) -> Self {
+        self.settings.connect_failure_policy = policy;
+        self
+    }
+
+    /// Set how long `build` waits for the connection to each enclave it
+    /// launches (by default, `NITRO_ENCLAVE_CONNECT_TIMEOUT`): see
+    /// `on_connect_failure` for what happens when it runs out
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.connect_timeout = timeout;
+        self
+    }
+
+    /// Check, before launching the enclave, that the memory reserved for
+    /// enclaves by the allocator service (as set in its configuration file)
+    /// less the memory of the enclaves already running leaves room for this
+    /// one. If not, launching fails straight away with
+    /// `NitroError::InsufficientReservedMemory`, rather than retrying a Nitro
+    /// CLI command that can't succeed.
+    pub fn check_reserved_memory(mut self, check: bool) -> Self {
+        self.settings.check_reserved_memory = check;
+        self
+    }
+
+    /// Set whether a request for less memory than Nitro enclaves can have
+    /// (`MIN_ENCLAVE_MEMORY_MIB`) is raised to the minimum, with a warning,
+    /// rather than failing with `NitroError::InvalidLaunchSettings`. Off by
+    /// default.
+    pub fn auto_adjust_memory(mut self, adjust: bool) -> Self {
+        self.settings.auto_adjust_memory = adjust;
+        self
+    }
+
+    /// Set whether dropping the enclave waits for the Nitro CLI tool to
+    /// terminate it (the default) or starts `terminate-enclave` in the
+    /// background and returns at once. The background command carries on
+    /// even if this process exits first, so the enclave is still terminated,
+    /// but the trade-off is that a failure to terminate it can't be reported
+    /// or retried, and its CLI output isn't written to the diagnostics
+    /// writer. If this process exits before the command finishes, the enclave
+    /// is left in the leak registry, if there is one, for
+    /// `reap_leaked_enclaves` to check later.
+    pub fn async_terminate(mut self, async_terminate: bool) -> Self {
+        self.settings.async_terminate = async_terminate;
+        self
+    }
+
+    /// Set how long each run of `terminate-enclave` made when the enclave is
+    /// dropped may take (by default, `DEFAULT_TERMINATE_TIMEOUT`). A run that
+    /// takes longer is killed and not retried, and the enclave must then be
+    /// terminated by hand, so that a wedged Nitro CLI tool can't hold up the
+    /// shutdown of this process indefinitely.
+    pub fn terminate_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.terminate_timeout = timeout;
+        self
+    }
+
+    /// Abandon launching the enclave, failing with `NitroError::Cancelled`,
+    /// once `token` is set. The token is checked before each attempt to
+    /// launch the enclave and regularly while sleeping between attempts, so
+    /// a supervisor shutting down doesn't have to wait for the retries to
+    /// give up. An attempt already in progress is allowed to finish.
+    pub fn cancellation_token(mut self, token: Arc<AtomicBool>) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Send `config` to the enclave as the first buffer (framed as with
+    /// `NitroEnclave::send_buffer`) on the connection, as soon as it is
+    /// made and before the `ConnectedEnclave` is returned. Enclaves get no
+    /// environment from the host, so this is the way to parameterise the
+    /// workload: an enclave launched with this option should read one buffer
+    /// before anything else, and interpret it as its configuration. What the
+    /// configuration contains is a matter for the protocol between the host
+    /// and the enclave. The same configuration is sent again if the enclave
+    /// is restarted.
+    pub fn initial_config(mut self, config: Vec<u8>) -> Self {
+        self.connection.initial_config = Some(config);
+        self
+    }
+
+    /// Send `frame` to the enclave (as with `NitroEnclave::send_buffer`) when
+    /// the `NitroEnclave` is dropped, before disconnecting and terminating
+    /// it, so the enclave has a chance to shut down gracefully. What the
+    /// frame contains is a matter for the protocol between the host and the
+    /// enclave.
+    pub fn shutdown_frame(mut self, frame: Vec<u8>) -> Self {
+        self.connection.shutdown_frame = Some(frame);
+        self
+    }
+
+    /// Request a kernel send buffer (`SO_SNDBUF`) of `size` bytes for the
+    /// VSOCK. Small buffers suit low-latency RPC and large ones suit bulk
+    /// transfer. The kernel may adjust the requested size (Linux doubles it,
+    /// and clamps it to `net.core.wmem_max`), so use
+    /// `NitroEnclave::send_buffer_size` to find the size actually in effect.
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.connection.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Request a kernel receive buffer (`SO_RCVBUF`) of `size` bytes for the
+    /// VSOCK. As with `send_buffer_size`, the kernel may adjust the requested
+    /// size: use `NitroEnclave::receive_buffer_size` to find the size
+    /// actually in effect.
+    pub fn receive_buffer_size(mut self, size: usize) -> Self {
+        self.connection.receive_buffer_size = Some(size);
+        self
+    }
+
+    /// Wait for `delay` after connecting to the enclave (and sending the
+    /// initial configuration, if any) before returning it, for enclaves that
+    /// need a known time to initialize but can't answer a `ping` to say when
+    /// they are ready. This applies whenever the enclave is connected to,
+    /// including by `connect` and `restart`. By default there is no delay.
+    pub fn warmup_delay(mut self, delay: Duration) -> Self {
+        self.connection.warmup_delay = Some(delay);
+        self
+    }
+
+    /// The full command line (the path to the Nitro CLI tool followed by
+    /// its arguments) that `launch` would run, without running it. This is
+    /// useful for running the command by hand to see the CLI's own error
+    /// output, or for checking the arguments in deployment tooling. Fails as
+    /// `launch` would if the settings are invalid.
+    pub fn command_line(&self) -> Result<Vec<String>> {
+        let mut settings = self.settings.clone();
+        adjust_memory(&mut settings);
+        let mut command_line = vec![settings.nitro_cli_path.clone()];
+        command_line.extend(build_run_enclave_args(&settings)?);
+        Ok(command_line)
+    }
+
+    /// Fail with `NitroError::InsufficientReservedMemory` if too little
+    /// reserved memory is free for the enclave
+    fn check_free_memory(&self) -> Result<()> {
+        let reserved_mib = match allocator_memory_mib() {
+            Some(reserved_mib) => reserved_mib,
+            None => reserved_hugepage_memory_mib()?,
+        };
+        let used_mib: u64 = describe_enclaves(&self.settings.nitro_cli_path)?
+            .iter()
+            .filter_map(|enclave| enclave["MemoryMiB"].as_u64())
+            .sum();
+        let available_mib = reserved_mib.saturating_sub(used_mib);
+        if u64::from(self.settings.max_memory_mib) > available_mib {
+            return Err(anyhow!(NitroError::InsufficientReservedMemory {
+                requested_mib: self.settings.max_memory_mib,
+                available_mib,
+            }));
+        }
+        Ok(())
+    }
+
+    /// Fail with `NitroError::CliOptionUnsupported` if the Nitro CLI tool
+    /// can't launch an enclave with a given CID, and with
+    /// `NitroError::CidInUse` if a running enclave already has `cid`
+    fn check_cid_available(&self, cid: u32) -> Result<()> {
+        let nitro_cli_path = &self.settings.nitro_cli_path;
+        if !cli_has_option(nitro_cli_path, "run-enclave", "--enclave-cid")? {
+            return Err(anyhow!(NitroError::CliOptionUnsupported(
+                "run-enclave --enclave-cid".to_string()
+            )));
+        }
+        let in_use = describe_enclaves(nitro_cli_path)?
+            .iter()
+            .any(|enclave| enclave["EnclaveCID"].as_u64() == Some(cid.into()));
+        if in_use {
+            return Err(anyhow!(NitroError::CidInUse(cid)));
+        }
+        Ok(())
+    }
+
+    /// Fail with `NitroError::Cancelled` if the cancellation token is set
+    fn check_cancelled(&self) -> Result<()> {
+        match &self.cancellation {
+            Some(token) if token.load(Ordering::SeqCst) => Err(anyhow!(NitroError::Cancelled)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Sleep for `delay`, failing early with `NitroError::Cancelled` if the
+    /// cancellation token is set in the meantime
+    fn sleep(&self, delay: Duration) -> Result<()> {
+        let deadline = Instant::now() + delay;
+        loop {
+            self.check_cancelled()?;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(());
+            }
+            if self.cancellation.is_none() {
+                std::thread::sleep(remaining);
+            } else {
+                std::thread::sleep(remaining.min(CANCELLATION_POLL_INTERVAL));
+            }
+        }
+    }
+
+    /// Launch the enclave and connect to it, waiting up to the connect
+    /// timeout (by default, `NITRO_ENCLAVE_CONNECT_TIMEOUT`) for the
+    /// connection
+    pub fn build(self) -> Result<ConnectedEnclave> {
+        self.build_with_report().map(|(connected, _)| connected)
+    }
+
+    /// As `build`, but also returning a `LaunchReport` describing the
+    /// launch of the enclave connected to
+    pub fn build_with_report(self) -> Result<(ConnectedEnclave, LaunchReport)> {
+        let settings = self.settings.clone();
+        let connection = self.connection.clone();
+        let cancellation = self.cancellation.clone();
+        let mut builder = self;
+        let mut retries = 0;
+        loop {
+            let (mut launched, report) = builder.launch_with_report()?;
+            let connected = match settings.connect_failure_policy {
+                // a timeout has to come back as an error to be retried
+                ConnectFailurePolicy::TeardownAndRetry(_) => {
+                    launched.connect_vsock_within(settings.connect_timeout)
+                }
+                ConnectFailurePolicy::Fail => launched.connect_vsock(settings.connect_timeout),
+            };
+            let err = match connected {
+                Ok(vsocket) => return Ok((launched.into_connected(vsocket), report)),
+                Err(err) => err,
+            };
+            match settings.connect_failure_policy {
+                ConnectFailurePolicy::TeardownAndRetry(max_retries) if retries < max_retries => {
+                    warn!(
+                        "Failed to connect to enclave {}, terminating it and launching another: {:?}",
+                        launched.process.enclave_id, err
+                    );
+                }
+                _ => return Err(err),
+            }
+            let diagnostics_writer = launched.process.diagnostics_writer.take();
+            // terminates the enclave before the next is launched
+            drop(launched);
+            builder = NitroEnclaveBuilder {
+                settings: settings.clone(),
+                diagnostics_writer,
+                connection: connection.clone(),
+                cancellation: cancellation.clone(),
+            };
+            retries += 1;
+        }
+    }
+
+    /// Launch the enclave, without connecting to it
+    pub fn launch(self) -> Result<LaunchedEnclave> {
+        self.launch_with_report().map(|(launched, _)| launched)
+    }
+
+    /// As `launch`, but also returning a `LaunchReport` describing the
+    /// launch
+    pub fn launch_with_report(mut self) -> Result<(LaunchedEnclave, LaunchReport)> {
+        #[cfg(feature = "leak-check")]
+        let live = LiveEnclave::claim()?;
+        if let Some(requested) = adjust_memory(&mut self.settings) {
+            warn!(
+                "Raising enclave memory from {} MiB to the minimum of {} MiB",
+                requested, MIN_ENCLAVE_MEMORY_MIB
+            );
+        }
+        let args = build_run_enclave_args(&self.settings)?;
+        check_enclave_permissions()?;
+        if self.settings.check_reserved_memory {
+            self.check_free_memory()?;
+        }
+        if let Some(cid) = self.settings.enclave_cid {
+            self.check_cid_available(cid)?;
+        }
+        let mut diagnostics_writer = self.diagnostics_writer.take();
+        let mut attempt = 0;
+        let stdout = loop {
+            self.check_cancelled()?;
+            let enclave_result =
+                cli_output(Command::new(&self.settings.nitro_cli_path).args(&args));
+            match enclave_result {
+                Err(err) => {
+                    warn!("NitroEnclave::new failed to start enclave:{:?}", err);
+                    // the run finished, so the enclave may well be running:
+                    // launching another could leak it
+                    let status_lost = err.get_ref().is_some_and(|inner| inner.is::<NitroError>());
+                    if status_lost || !self.settings.retry_policy.allows_retry(attempt) {
+                        return Err(anyhow!(err));
+                    }
+                    debug!("sleeping before trying again");
+                    self.sleep(self.settings.retry_backoff.delay(attempt))?;
+                    attempt += 1;
+                    continue;
+                }
+                Ok(result) => {
+                    let recorded = record_cli_output(&mut diagnostics_writer, &args, &result);
+                    if !result.status.success() {
+                        let enclave_result_stderr = std::str::from_utf8(&result.stderr)?;
+                        if !recorded {
+                            warn!("NitroEnclave::new CLI error:{:?}", enclave_result_stderr);
+                        }
+                        let fatal = self
+                            .settings
+                            .stderr_classifier
+                            .as_ref()
+                            .map(|classifier| (classifier.0)(enclave_result_stderr))
+                            == Some(ErrorClass::Fatal);
+                        if fatal || !self.settings.retry_policy.allows_retry(attempt) {
+                            return Err(anyhow!(NitroError::CliError(
+                                enclave_result_stderr.trim().to_string()
+                            )));
+                        }
+                        debug!("sleeping before trying again");
+                        self.sleep(self.settings.retry_backoff.delay(attempt))?;
+                        attempt += 1;
+                        continue;
+                    } else {
+                        if !recorded {
+                            trace!(
+                                "enclave_result_stdout:{:?}",
+                                String::from_utf8_lossy(&result.stdout)
+                            );
+                        }
+                        break result.stdout;
+                    }
+                }
+            }
+        };
+
+        let enclave_result_stdout = std::str::from_utf8(&stdout)?;
+        // some broken builds of the CLI exit successfully without saying
+        // what they launched
+        if enclave_result_stdout.trim().is_empty() {
+            return Err(anyhow!(NitroError::EmptyCliOutput(args.join(" "))));
+        }
+
+        let enclave_data: Value = serde_json::from_str(enclave_result_stdout)?;
+
+        // deserialized rather than printed, so that any escapes in the ID are
+        // undone and `terminate-enclave` is given exactly the ID issued
+        let enclave_id: String = serde_json::from_value(enclave_data["EnclaveID"].clone())
+            .map_err(|_| anyhow!(NitroError::SerdeError))?;
+
+        let mut process = EnclaveProcess {
+            enclave_id,
+            nitro_cli_path: self.settings.nitro_cli_path.clone(),
+            diagnostics_writer,
+            leak_registry: self.settings.leak_registry.clone(),
+            audit_log: self.settings.audit_log.clone(),
+            retry_backoff: self.settings.retry_backoff.clone(),
+            retry_policy: self.settings.retry_policy,
+            async_terminate: self.settings.async_terminate,
+            terminate_on_drop: true,
+            terminate_timeout: self.settings.terminate_timeout,
+            _port_claim: Some(PortClaim::new(self.settings.port)),
+            #[cfg(feature = "leak-check")]
+            _live: live,
+            memory_mib: None,
+            cpu_count: None,
+            debug: Some(self.settings.debug),
+            pcr0: None,
+        };
+        if let Some(registry) = &process.leak_registry {
+            let pid = std::process::id();
+            let enclave_id = process.enclave_id.clone();
+            update_leak_registry(registry, |entries| entries.push((enclave_id, pid)))
+                .unwrap_or_else(|e| warn!("Failed to record enclave in leak registry: {:?}", e));
+        }
+        // dropping `process` terminates the enclave
+        let cid = match enclave_data["EnclaveCID"].as_u64() {
+            Some(cid) => check_enclave_cid(cid)?,
+            None => return Err(anyhow!(NitroError::SerdeError)),
+        };
+        if let Some(requested) = self.settings.enclave_cid {
+            if cid != requested {
+                // dropping `process` terminates the enclave
+                return Err(anyhow!(NitroError::CidMismatch {
+                    requested,
+                    actual: cid,
+                }));
+            }
+        }
+        let (memory_mib, cpu_count) = allocation_of(&enclave_data);
+        // What the enclave actually got is read back from `describe-enclaves`
+        // where possible, falling back to what `run-enclave` reported and
+        // what was asked for
+        let described = describe_enclaves(&self.settings.nitro_cli_path)
+            .ok()
+            .and_then(|enclaves| {
+                enclaves
+                    .into_iter()
+                    .find(|enclave| enclave["EnclaveID"].as_str() == Some(&process.enclave_id))
+            });
+        let (allocated_memory_mib, allocated_cpu_count) = described
+            .as_ref()
+            .map(allocation_of)
+            .unwrap_or((None, None));
+        process.memory_mib = allocated_memory_mib.or(memory_mib);
+        process.cpu_count = allocated_cpu_count.or(cpu_count);
+        if let Some(debug) = described.as_ref().and_then(debug_mode_of) {
+            process.debug = Some(debug);
+        }
+        let requested_cpu_count = self
+            .settings
+            .cpu_ids
+            .as_ref()
+            .map(|cpu_ids| cpu_ids.len() as u32)
+            .or(self.settings.cpu_count);
+        let report = LaunchReport {
+            enclave_id: process.enclave_id.clone(),
+            cid,
+            memory_mib: process.memory_mib.or(Some(self.settings.max_memory_mib)),
+            cpu_count: process.cpu_count.or(requested_cpu_count),
+            measurements: enclave_data["Measurements"]
+                .as_object()
+                .map(|measurements| {
+                    measurements
+                        .iter()
+                        .filter_map(|(name, value)| {
+                            Some((name.clone(), Pcr::from_hex(value.as_str()?).ok()?))
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            attempts: attempt + 1,
+        };
+        process.pcr0 = report.measurements.get("PCR0").cloned();
+        if let Some(audit_log) = &process.audit_log {
+            let measurements: serde_json::Map<String, Value> = report
+                .measurements
+                .iter()
+                .map(|(name, pcr)| (name.clone(), Value::String(pcr.to_hex())))
+                .collect();
+            append_audit_record(
+                audit_log,
+                json!({
+                    "event": "launch",
+                    "enclave_id": process.enclave_id,
+                    "cid": cid,
+                    "eif_path": self.settings.eif_path,
+                    "memory_mib": process.memory_mib,
+                    "cpu_count": process.cpu_count,
+                    "debug": process.debug,
+                    "measurements": measurements,
+                }),
+            );
+        }
+        let launched = LaunchedEnclave {
+            process,
+            cid,
+            port: self.settings.port,
+            connection: self.connection,
+            settings: Some(self.settings),
+        };
+        Ok((launched, report))
+    }
+}
+
+impl LaunchedEnclave {
+    /// Open the VSOCK to the enclave, as described for `connect`, apply the
+    /// connection options to it, and send the initial configuration
+    fn connect_vsock(&self, timeout: Duration) -> Result<vsocket::VsockSocket> {
+        check_vsock_support()?;
+        let timer = ConnectTimer::arm(timeout)?;
+        let vsocket = vsocket::VsockSocket::connect(self.cid, self.port);
+        drop(timer);
+        self.set_up_vsock(vsocket?)
+    }
+
+    /// As `connect_vsock`, but failing with `NitroError::ConnectTimedOut`,
+    /// rather than terminating this process, if the VSOCK can't be opened
+    /// within `timeout`
+    fn connect_vsock_within(&self, timeout: Duration) -> Result<vsocket::VsockSocket> {
+        check_vsock_support()?;
+        let vsocket = vsocket::VsockSocket::connect_with_timeout(self.cid, self.port, timeout)
+            .map_err(|err| match err {
+                Errno::ETIMEDOUT => anyhow!(NitroError::ConnectTimedOut),
+                err => anyhow!(err),
+            })?;
+        self.set_up_vsock(vsocket)
+    }
+
+    /// Configure a newly opened VSOCK, as for `configure_vsock`, and then
+    /// give the enclave its warm-up delay, if any
+    fn set_up_vsock(&self, vsocket: vsocket::VsockSocket) -> Result<vsocket::VsockSocket> {
+        let vsocket = self.configure_vsock(vsocket)?;
+        if let Some(delay) = self.connection.warmup_delay {
+            std::thread::sleep(delay);
+        }
+        Ok(vsocket)
+    }
+
+    /// Apply the connection options to a newly opened VSOCK, and send the
+    /// initial configuration
+    fn configure_vsock(&self, vsocket: vsocket::VsockSocket) -> Result<vsocket::VsockSocket> {
+        if let Some(size) = self.connection.send_buffer_size {
+            vsocket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.connection.receive_buffer_size {
+            vsocket.set_receive_buffer_size(size)?;
+        }
+        if let Some(config) = &self.connection.initial_config {
+            raw_fd::send_buffer(vsocket.as_raw_fd(), config)?;
+        }
+        Ok(vsocket)
+    }
+
+    /// The enclave ID, as generated from the Nitro CLI tool
+    pub fn enclave_id(&self) -> &str {
+        &self.process.enclave_id
+    }
+
+    /// Connect to the enclave, terminating this process with
+    /// `connect_timeout_signal()` if the connection is not made within
+    /// `timeout`. If the connection fails, or the initial configuration (if
+    /// any) can't be sent, the enclave is terminated.
+    pub fn connect(self, timeout: Duration) -> Result<ConnectedEnclave> {
+        let vsocket = self.connect_vsock(timeout)?;
+        Ok(self.into_connected(vsocket))
+    }
+
+    /// As `connect`, but without blocking the thread, for services running
+    /// on a tokio runtime. Rather than terminating the process, this fails
+    /// with `NitroError::ConnectTimedOut` if the connection is not made
+    /// within `timeout`. Dropping the future (for instance, when it loses a
+    /// `tokio::select!` against a shutdown signal) abandons the connection
+    /// and terminates the enclave.
+    #[cfg(feature = "tokio")]
+    pub async fn connect_async(self, timeout: Duration) -> Result<ConnectedEnclave> {
+        let vsocket = tokio::time::timeout(
+            timeout,
+            vsocket::VsockSocket::connect_async(self.cid, self.port),
+        )
+        .await
+        .map_err(|_| anyhow!(NitroError::ConnectTimedOut))??;
+        let vsocket = self.configure_vsock(vsocket)?;
+        if let Some(delay) = self.connection.warmup_delay {
+            tokio::time::sleep(delay).await;
+        }
+        Ok(self.into_connected(vsocket))
+    }
+
+    /// The connected enclave, communicating over `vsocket`
+    fn into_connected(self, vsocket: vsocket::VsockSocket) -> ConnectedEnclave {
+        NitroEnclave {
+            process: Some(self.process),
+            vsocksocket: vsocket,
+            cid: self.cid,
+            port: self.port,
+            connection: self.connection,
+            settings: self.settings,
+        }
+    }
+}
+
+impl NitroEnclave {
+    /// create a new Nitro enclave, started with the file in eif_path
+    /// * `eif_path` - path to the EIF file that will be started in the enclave
+    /// * `debug` - indicates of the enclave will be started in debug mode
+    /// * `max_memory_mib` - The amount of memory to be allocated to the enclave
+    /// * `port` - The port number that will be used to communicate with the enclave (The code in the EIF should be
+    ///   written to communicate on this port as well)
+    pub fn new(eif_path: &str, debug: bool, max_memory_mib: u32, port: u32) -> Result<Self> {
+        Self::builder(eif_path, max_memory_mib, port)
+            .debug(debug)
+            .build()
+    }
+
+    /// As `new`, but starting the enclave from an EIF held in memory, such as
+    /// one fetched from object storage, rather than from a file. The Nitro
+    /// CLI tool can only read an EIF from a file, so `eif` is written to a
+    /// temporary file readable only by this user, which is removed once the
+    /// enclave has been launched, or launching has failed. With the file
+    /// gone, the enclave can't be launched again: `restart` fails with
+    /// `NitroError::RestartUnsupported`, leaving it running.
+    pub fn new_from_bytes(eif: &[u8], debug: bool, max_memory_mib: u32, port: u32) -> Result<Self> {
+        let mut eif_file = tempfile::Builder::new()
+            .prefix("nitro-enclave-")
+            .suffix(".eif")
+            .tempfile()?;
+        eif_file.write_all(eif)?;
+        eif_file.flush()?;
+        let eif_path = eif_file
+            .path()
+            .to_str()
+            .ok_or_else(|| {
+                anyhow!(NitroError::InvalidLaunchSettings(
+                    "temporary EIF path is not valid UTF-8".to_string()
+                ))
+            })?
+            .to_string();
+        let launched = Self::builder(&eif_path, max_memory_mib, port)
+            .debug(debug)
+            .launch();
+        drop(eif_file);
+        let mut enclave = launched?.connect(NITRO_ENCLAVE_CONNECT_TIMEOUT)?;
+        enclave.settings = None;
+        Ok(enclave)
+    }
+
+    /// Build a `NitroEnclave` around an existing connection to the enclave
+    /// with ID `enclave_id` and CID `cid`, without launching or connecting to
+    /// anything, for connections made by other means and for tests (where
+    /// `socket` can be any connected stream socket: see
+    /// `VsockSocket::from_raw_fd`). Nothing checks that `socket` is actually
+    /// connected to that enclave, or that the enclave exists. As with
+    /// `attach`, the returned `NitroEnclave` takes ownership of the enclave,
+    /// so dropping it terminates the enclave with the Nitro CLI tool at its
+    /// default location, and it can't be restarted. The port is read from the
+    /// socket's peer address, and is reported as 0 if the socket isn't a
+    /// VSOCK. The enclave's debug mode is unknown, so `is_debug` reports it
+    /// as being in debug mode.
+    pub fn from_parts(enclave_id: String, cid: u32, socket: vsocket::VsockSocket) -> Self {
+        let port = getpeername::<VsockAddr>(socket.as_raw_fd())
+            .map(|address| address.port())
+            .unwrap_or(0);
+        NitroEnclave {
+            process: Some(EnclaveProcess {
+                enclave_id,
+                nitro_cli_path: DEFAULT_NITRO_CLI_PATH.to_string(),
+                diagnostics_writer: None,
+                leak_registry: None,
+                audit_log: None,
+                retry_backoff: RetryBackoff::default(),
+                retry_policy: RetryPolicy::default(),
+                async_terminate: false,
+                terminate_on_drop: true,
+                terminate_timeout: DEFAULT_TERMINATE_TIMEOUT,
+                _port_claim: (port != 0).then(|| PortClaim::new(port)),
+                #[cfg(feature = "leak-check")]
+                _live: LiveEnclave::track(),
+                memory_mib: None,
+                cpu_count: None,
+                debug: None,
+                pcr0: None,
+            }),
+            vsocksocket: socket,
+            cid,
+            port,
+            connection: ConnectionOptions::default(),
+            settings: None,
+        }
+    }
+
+    /// Attach to an already-running enclave with ID `enclave_id`, for instance
+    /// one launched by a previous incarnation of this process, by looking up
+    /// its CID with the Nitro CLI tool at `nitro_cli_path` and connecting to
+    /// it on `port`. No enclave is launched. The returned `NitroEnclave` takes
+    /// ownership of the enclave, so dropping it terminates the enclave. Fails
+    /// with `NitroError::EnclaveNotFound` if the enclave isn't running.
+    pub fn attach(enclave_id: &str, port: u32, nitro_cli_path: &str) -> Result<Self> {
+        #[cfg(feature = "leak-check")]
+        let live = LiveEnclave::claim()?;
+        let enclaves = describe_enclaves(nitro_cli_path)?;
+        let enclave = enclaves
+            .iter()
+            .find(|enclave| {
+                enclave["EnclaveID"].as_str() == Some(enclave_id)
+                    && enclave["State"].as_str() == Some("RUNNING")
+            })
+            .ok_or_else(|| anyhow!(NitroError::EnclaveNotFound(enclave_id.to_string())))?;
+        let cid = enclave["EnclaveCID"]
+            .as_u64()
+            .ok_or_else(|| anyhow!(NitroError::EnclaveNotFound(enclave_id.to_string())))?;
+        let (memory_mib, cpu_count) = allocation_of(enclave);
+        let debug = debug_mode_of(enclave);
+        let launched = LaunchedEnclave {
+            process: EnclaveProcess {
+                enclave_id: enclave_id.to_string(),
+                nitro_cli_path: nitro_cli_path.to_string(),
+                diagnostics_writer: None,
+                leak_registry: None,
+                audit_log: None,
+                retry_backoff: RetryBackoff::default(),
+                retry_policy: RetryPolicy::default(),
+                async_terminate: false,
+                terminate_on_drop: true,
+                terminate_timeout: DEFAULT_TERMINATE_TIMEOUT,
+                _port_claim: Some(PortClaim::new(port)),
+                #[cfg(feature = "leak-check")]
+                _live: live,
+                memory_mib,
+                cpu_count,
+                debug,
+                pcr0: None,
+            },
+            cid: check_enclave_cid(cid)?,
+            port,
+            connection: ConnectionOptions::default(),
+            settings: None,
+        };
+        launched.connect(NITRO_ENCLAVE_CONNECT_TIMEOUT)
+    }
+
+    /// Launch a new Nitro enclave as `new` does, but without connecting to
+    /// it. The returned `LaunchedEnclave` must be connected to before any
+    /// data can be sent.
+    pub fn launch(
+        eif_path: &str,
+        debug: bool,
+        max_memory_mib: u32,
+        port: u32,
+    ) -> Result<LaunchedEnclave> {
+        Self::builder(eif_path, max_memory_mib, port)
+            .debug(debug)
+            .launch()
+    }
+
+    /// Create a builder for a Nitro enclave, for when the defaults used by
+    /// `new` are not appropriate
+    pub fn builder(eif_path: &str, max_memory_mib: u32, port: u32) -> NitroEnclaveBuilder {
+        NitroEnclaveBuilder::new(eif_path, max_memory_mib, port)
+    }
+
+    /// The enclave ID, as generated from the Nitro CLI tool, or an empty
+    /// string if no enclave is running because a `restart` failed
+    pub fn enclave_id(&self) -> &str {
+        self.process
+            .as_ref()
+            .map_or("", |process| process.enclave_id.as_str())
+    }
+
+    /// Whether an enclave is running. This is only false after a `restart`
+    /// that terminated the old enclave but failed to launch the new one.
+    pub fn is_running(&self) -> bool {
+        self.process.is_some()
+    }
+
+    /// The CID of the enclave
+    pub fn cid(&self) -> u32 {
+        self.cid
+    }
+
+    /// The memory allocated to the enclave, in MiB, as read back from the
+    /// Nitro CLI tool once the enclave was running. This can differ from the
+    /// memory asked for, and is `None` if the tool didn't say.
+    pub fn allocated_memory_mib(&self) -> Option<u32> {
+        self.process.as_ref().and_then(|process| process.memory_mib)
+    }
+
+    /// The number of CPUs allocated to the enclave, as read back from the
+    /// Nitro CLI tool once the enclave was running, or `None` if the tool
+    /// didn't say
+    pub fn allocated_cpu_count(&self) -> Option<u32> {
+        self.process.as_ref().and_then(|process| process.cpu_count)
+    }
+
+    /// Whether the enclave is running in debug mode, in which case its
+    /// attestation documents must not be trusted. This is the mode the Nitro
+    /// CLI tool reported for the enclave once it was running, where it said,
+    /// rather than the mode asked for. Where the mode is unknown (for an
+    /// enclave built with `from_parts`, one attached to whose description
+    /// has no flags, or after a failed `restart`), this is true, so that a
+    /// check of `!is_debug()` before trusting an attestation fails closed.
+    pub fn is_debug(&self) -> bool {
+        self.process
+            .as_ref()
+            .and_then(|process| process.debug)
+            .unwrap_or(true)
+    }
+
+    /// The vsock port the enclave is listening on
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// The size of the kernel's send buffer for the VSOCK, which may differ
+    /// from the size requested with `NitroEnclaveBuilder::send_buffer_size`
+    pub fn send_buffer_size(&self) -> Result<usize> {
+        Ok(self.vsocksocket.send_buffer_size()?)
+    }
+
+    /// The size of the kernel's receive buffer for the VSOCK, which may
+    /// differ from the size requested with
+    /// `NitroEnclaveBuilder::receive_buffer_size`
+    pub fn receive_buffer_size(&self) -> Result<usize> {
+        Ok(self.vsocksocket.receive_buffer_size()?)
+    }
+
+    /// Set how long a receive from the enclave may block before failing with
+    /// `raw_fd::RawFdError::ReceiveTimeout` (the VSOCK's `SO_RCVTIMEO`), or
+    /// with `None` let it block indefinitely, for instance a short timeout
+    /// for a ping and a long one for a bulk transfer. A zero timeout is
+    /// rejected.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        Ok(self.vsocksocket.set_read_timeout(timeout)?)
+    }
+
+    /// How long a receive from the enclave may block, or `None` if it may
+    /// block indefinitely
+    pub fn read_timeout(&self) -> Result<Option<Duration>> {
+        Ok(self.vsocksocket.read_timeout()?)
+    }
+
+    /// Set how long a send to the enclave may block before failing with
+    /// `EAGAIN` (the VSOCK's `SO_SNDTIMEO`), or with `None` let it block
+    /// indefinitely. A send that times out may have sent part of its buffer,
+    /// so the connection must then be abandoned. A zero timeout is rejected.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        Ok(self.vsocksocket.set_write_timeout(timeout)?)
+    }
+
+    /// How long a send to the enclave may block, or `None` if it may block
+    /// indefinitely
+    pub fn write_timeout(&self) -> Result<Option<Duration>> {
+        Ok(self.vsocksocket.write_timeout()?)
+    }
+
+    /// Terminate the enclave and launch a new one in its place, with the
+    /// same settings but started with the file in `new_eif_path` if one is
+    /// given, and then connect to it. The enclave ID and CID are updated,
+    /// and the shutdown frame and buffer sizes carry over. The diagnostics
+    /// writer, if any, carries over too, unless the launch fails.
+    ///
+    /// Fails with `NitroError::RestartUnsupported`, leaving the enclave
+    /// running, if it was not launched by this process (for instance, if it
+    /// was `attach`ed to) or was launched from an EIF held in memory (see
+    /// `new_from_bytes`). If the old enclave is terminated but the new one
+    /// fails to launch or connect, the error is returned and no enclave is
+    /// running: `is_running` returns false, sending and receiving fail with
+    /// `NitroError::NotRunning`, and `restart` may be called again.
+    pub fn restart(&mut self, new_eif_path: Option<&str>) -> Result<()> {
+        let mut settings = self
+            .settings
+            .clone()
+            .ok_or_else(|| anyhow!(NitroError::RestartUnsupported))?;
+        if let Some(eif_path) = new_eif_path {
+            settings.eif_path = eif_path.to_string();
+        }
+
+        let mut diagnostics_writer = None;
+        if let Some(mut process) = self.process.take() {
+            if let Some(frame) = &self.connection.shutdown_frame {
+                let _ = raw_fd::send_buffer(self.vsocksocket.as_raw_fd(), frame);
+            }
+            shutdown(self.vsocksocket.as_raw_fd(), Shutdown::Both).unwrap_or(());
+            diagnostics_writer = process.diagnostics_writer.take();
+            // terminates the old enclave
+            drop(process);
+        }
+
+        let launched = NitroEnclaveBuilder {
+            settings,
+            diagnostics_writer,
+            connection: self.connection.clone(),
+            cancellation: None,
+        }
+        .launch()?;
+        self.vsocksocket = launched.connect_vsock(NITRO_ENCLAVE_CONNECT_TIMEOUT)?;
+        self.process = Some(launched.process);
+        self.cid = launched.cid;
+        self.settings = launched.settings;
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for the enclave to exit, polling the Nitro CLI
+    /// tool's description of the enclaves on this host, so that a supervisor
+    /// can notice the enclave application crashing without having to wait
+    /// for a send or receive to fail. Returns `EnclaveExit::StillRunning` if
+    /// the enclave is still running when the timeout elapses.
+    pub fn wait_exit(&self, timeout: Duration) -> Result<EnclaveExit> {
+        let process = self
+            .process
+            .as_ref()
+            .ok_or_else(|| anyhow!(NitroError::NotRunning))?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let state = describe_enclaves(&process.nitro_cli_path)?
+                .iter()
+                .find(|enclave| enclave["EnclaveID"].as_str() == Some(&process.enclave_id))
+                .map(|enclave| enclave["State"].as_str().unwrap_or_default().to_string());
+            if state.as_deref() != Some("RUNNING") {
+                return Ok(EnclaveExit::Exited { last_state: state });
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(EnclaveExit::StillRunning);
+            }
+            std::thread::sleep(remaining.min(EXIT_POLL_INTERVAL));
+        }
+    }
+
+    /// Pause the enclave, stopping its CPUs while keeping its state, with the
+    /// Nitro CLI tool's `pause-enclave` command. No released version of the
+    /// Nitro CLI tool has such a command yet, so this fails with
+    /// `NitroError::CliCommandUnsupported` unless the installed one lists it
+    /// in its help; it is provided for Nitro CLI tools that do.
+    pub fn pause(&self) -> Result<()> {
+        self.run_enclave_command("pause-enclave")
+    }
+
+    /// Resume an enclave paused with `pause`, with the Nitro CLI tool's
+    /// `resume-enclave` command. As with `pause`, this fails with
+    /// `NitroError::CliCommandUnsupported` unless the installed Nitro CLI
+    /// tool has that command.
+    pub fn resume(&self) -> Result<()> {
+        self.run_enclave_command("resume-enclave")
+    }
+
+    /// Run the Nitro CLI `command` on the enclave, if the tool has it
+    fn run_enclave_command(&self, command: &str) -> Result<()> {
+        let process = self
+            .process
+            .as_ref()
+            .ok_or_else(|| anyhow!(NitroError::NotRunning))?;
+        if !cli_has_command(&process.nitro_cli_path, command)? {
+            return Err(anyhow!(NitroError::CliCommandUnsupported(
+                command.to_string()
+            )));
+        }
+        let output = cli_output(Command::new(&process.nitro_cli_path).args([
+            command,
+            "--enclave-id",
+            &process.enclave_id,
+        ]))?;
+        if !output.status.success() {
+            return Err(anyhow!(NitroError::CliError(
+                String::from_utf8_lossy(&output.stderr).trim().to_string()
+            )));
+        }
+        Ok(())
+    }
+
+    /// The VSOCK's file descriptor, or `NitroError::NotRunning` if no
+    /// enclave is running
+    fn connected_fd(&self) -> Result<RawFd> {
+        if self.process.is_none() {
+            return Err(anyhow!(NitroError::NotRunning));
+        }
+        Ok(self.vsocksocket.as_raw_fd())
+    }
+
+    /// Whether the VSOCK can accept more data right now without blocking
+    pub fn is_writable(&self) -> Result<bool> {
+        raw_fd::is_writable(self.connected_fd()?)
+    }
+
+    /// Which enclave this is, for the context of errors sending to and
+    /// receiving from it
+    fn error_context(&self) -> String {
+        match &self.process {
+            Some(process) => format!("Enclave {} (CID {})", process.enclave_id, self.cid),
+            None => format!("Enclave with CID {}", self.cid),
+        }
+    }
+
+    /// send a buffer of data to the enclave. Transport errors carry the
+    /// enclave ID and CID as context, and can still be downcast to the
+    /// underlying error.
+    pub fn send_buffer(&self, buffer: &[u8]) -> Result<()> {
+        raw_fd::send_buffer(self.connected_fd()?, buffer).with_context(|| self.error_context())
+    }
+
+    /// receive a buffer of data from the enclave. As with `send_buffer`,
+    /// transport errors carry the enclave ID and CID as context.
+    pub fn receive_buffer(&self) -> Result<Vec<u8>> {
+        raw_fd::receive_buffer(self.connected_fd()?).with_context(|| self.error_context())
+    }
+
+    /// receive buffers of data from the enclave, passing each to `f`, until
+    /// `f` returns true, for streaming responses (see `raw_fd::receive_until`,
+    /// which also describes how the final buffer must be recognised).
+    /// Returns the number of buffers received.
+    pub fn receive_until<F: FnMut(&[u8]) -> bool>(&self, f: F) -> Result<usize> {
+        raw_fd::receive_until(self.connected_fd()?, f).with_context(|| self.error_context())
+    }
+
+    /// Collect the output of a long-running enclave job that streams its
+    /// results: receive typed buffers (see `raw_fd::send_typed_buffer`) of
+    /// type `STREAM_CHUNK_FRAME_TYPE` until one of type
+    /// `STREAM_END_FRAME_TYPE`, returning the contents of the chunks in
+    /// order. Each chunk (and the end of the stream) must arrive in full
+    /// within `per_chunk_timeout` of the previous one, or this fails with
+    /// `NitroError::StreamStalled`, and the whole stream must end within
+    /// `max_total`, or this fails with `NitroError::StreamTimedOut`. A buffer
+    /// of any other type fails with `NitroError::UnexpectedStreamFrame`. After
+    /// a failure the connection may be part way through a buffer, so it must
+    /// be abandoned.
+    pub fn collect_stream(
+        &self,
+        per_chunk_timeout: Duration,
+        max_total: Duration,
+    ) -> Result<Vec<Vec<u8>>> {
+        let fd = self.connected_fd()?;
+        let end = Instant::now() + max_total;
+        let mut chunks = Vec::new();
+        loop {
+            let chunk_end = Instant::now() + per_chunk_timeout;
+            let session = raw_fd::DeadlineSession::new(fd, chunk_end.min(end));
+            let (frame_type, chunk) = match session.receive_typed() {
+                Ok(frame) => frame,
+                Err(err) if matches!(err.downcast_ref(), Some(RawFdError::DeadlineExceeded)) => {
+                    return Err(anyhow!(if end <= chunk_end {
+                        NitroError::StreamTimedOut(max_total)
+                    } else {
+                        NitroError::StreamStalled(per_chunk_timeout)
+                    }));
+                }
+                Err(err) => return Err(err.context(self.error_context())),
+            };
+            match frame_type {
+                STREAM_CHUNK_FRAME_TYPE => chunks.push(chunk),
+                STREAM_END_FRAME_TYPE => return Ok(chunks),
+                other => return Err(anyhow!(NitroError::UnexpectedStreamFrame(other))),
+            }
+        }
+    }
+
+    /// Send a request to the enclave and collect all of its responses, for
+    /// protocols in which one query yields several replies (paginated or
+    /// streamed results, say). The request is sent as a typed buffer (see
+    /// `raw_fd::send_typed_buffer`) of type `TRANSACT_REQUEST_FRAME_TYPE`,
+    /// which the enclave answers as it would a stream for `collect_stream`:
+    /// a `STREAM_CHUNK_FRAME_TYPE` buffer for each response, then a
+    /// `STREAM_END_FRAME_TYPE` buffer. The responses are returned in order.
+    /// Fails with `NitroError::StreamTimedOut` if the enclave hasn't ended
+    /// its responses within `TRANSACT_TIMEOUT`, and otherwise as
+    /// `collect_stream` does. As with `ping`, the enclave must have nothing
+    /// else in flight.
+    pub fn transact_multi(&self, request: &[u8]) -> Result<Vec<Vec<u8>>> {
+        raw_fd::send_typed_buffer(self.connected_fd()?, TRANSACT_REQUEST_FRAME_TYPE, request)
+            .with_context(|| self.error_context())?;
+        self.collect_stream(TRANSACT_TIMEOUT, TRANSACT_TIMEOUT)
+    }
+
+    /// Take the VSOCK to the enclave, giving up management of the enclave
+    /// itself, for callers that manage the enclave's lifecycle some other
+    /// way. **The enclave is not terminated: the caller becomes responsible
+    /// for terminating it**, for instance with `nitro-cli terminate-enclave`
+    /// and the ID from `enclave_id`, or by `attach`ing to it again later.
+    /// Neither the shutdown frame (if any) is sent nor the VSOCK shut down,
+    /// and the enclave is removed from the leaked enclave registry (if any),
+    /// so that `reap_leaked_enclaves` won't terminate it either. It no longer
+    /// counts among the enclaves held by this process.
+    pub fn into_socket(self) -> vsocket::VsockSocket {
+        let mut this = ManuallyDrop::new(self);
+        if let Some(mut process) = this.process.take() {
+            if let Some(registry) = &process.leak_registry {
+                forget_leaked_enclave(registry, &process.enclave_id);
+            }
+            process.terminate_on_drop = false;
+        }
+        // SAFETY: `Drop for NitroEnclave` is skipped, so that the VSOCK can be
+        // moved out; `this` is not used again, so the VSOCK is moved out only
+        // once and each other field is dropped exactly once
+        unsafe {
+            let socket = std::ptr::read(&this.vsocksocket);
+            std::ptr::drop_in_place(&mut this.process);
+            std::ptr::drop_in_place(&mut this.connection);
+            std::ptr::drop_in_place(&mut this.settings);
+            socket
+        }
+    }
+
+    /// Split the connection into a sending half and a receiving half, so
+    /// that one thread can send to the enclave while another receives from
+    /// it. Each direction has its own lock, so buffers sent concurrently
+    /// (from clones of the sender) are never interleaved with each other, and
+    /// likewise for receives, while a send and a receive can proceed at the
+    /// same time. Multiple senders still take turns: each buffer is sent
+    /// whole before the next starts. The enclave is disconnected and
+    /// terminated once both halves (and all their clones) are dropped.
+    pub fn split(self) -> (EnclaveSender, EnclaveReceiver) {
+        let shared = Arc::new(SplitEnclave {
+            fd: self.connected_fd().ok(),
+            context: self.error_context(),
+            send_lock: Mutex::new(()),
+            receive_lock: Mutex::new(()),
+            _enclave: Mutex::new(self),
+        });
+        (EnclaveSender(shared.clone()), EnclaveReceiver(shared))
+    }
+
+    /// Ask the enclave for diagnostics, for production enclaves with no
+    /// debug console. This sends an empty typed buffer (see
+    /// `raw_fd::send_typed_buffer`) of type `DIAGNOSTICS_REQUEST_FRAME_TYPE`
+    /// and returns the contents of the reply, which the enclave must send as
+    /// a typed buffer of type `DIAGNOSTICS_RESPONSE_FRAME_TYPE`; what the
+    /// reply contains is up to the enclave. Fails with
+    /// `NitroError::UnexpectedDiagnosticsResponse` if the enclave replies with
+    /// a typed buffer of another type. The request and reply take turns with
+    /// the application's own traffic, so this must only be called when the
+    /// enclave is waiting for a request and has nothing else in flight.
+    pub fn request_diagnostics(&self) -> Result<Vec<u8>> {
+        let fd = self.connected_fd()?;
+        raw_fd::send_typed_buffer(fd, DIAGNOSTICS_REQUEST_FRAME_TYPE, &[])
+            .with_context(|| self.error_context())?;
+        match raw_fd::receive_typed_buffer(fd).with_context(|| self.error_context())? {
+            (DIAGNOSTICS_RESPONSE_FRAME_TYPE, response) => Ok(response),
+            (frame_type, _) => Err(anyhow!(NitroError::UnexpectedDiagnosticsResponse(
+                frame_type
+            ))),
+        }
+    }
+
+    /// Check that the connection is to the enclave this `NitroEnclave` was
+    /// launched as, and not (through CID reuse or a race) to some other
+    /// enclave. This sends a typed buffer (see `raw_fd::send_typed_buffer`)
+    /// of type `VERIFY_REQUEST_FRAME_TYPE` holding `challenge`, to which the
+    /// enclave must reply with a typed buffer of type
+    /// `VERIFY_RESPONSE_FRAME_TYPE` holding `challenge` followed by the raw
+    /// bytes of its PCR0, as read from its Nitro Secure Module. Fails with
+    /// `NitroError::ConnectionVerificationFailed` if the reply doesn't echo
+    /// the challenge or, where the Nitro CLI tool reported the enclave's
+    /// PCR0 at launch, if the PCR0 in the reply differs. For an enclave
+    /// attached to or built with `from_parts`, the PCR0 isn't known, so only
+    /// the echo is checked. This is a sanity check, not attestation: an
+    /// enclave can claim any PCR0 it likes. As with `request_diagnostics`,
+    /// this must only be called when the enclave is waiting for a request
+    /// and has nothing else in flight.
+    pub fn verify_connection(&self, challenge: &[u8]) -> Result<()> {
+        let fd = self.connected_fd()?;
+        let failed = |reason: &str| {
+            Err(anyhow!(NitroError::ConnectionVerificationFailed(
+                reason.to_string()
+            )))
+        };
+        raw_fd::send_typed_buffer(fd, VERIFY_REQUEST_FRAME_TYPE, challenge)
+            .with_context(|| self.error_context())?;
+        let (frame_type, response) =
+            raw_fd::receive_typed_buffer(fd).with_context(|| self.error_context())?;
+        if frame_type != VERIFY_RESPONSE_FRAME_TYPE {
+            return failed("the reply was not a verification response");
+        }
+        let pcr0 = match response.strip_prefix(challenge) {
+            Some(pcr0) => pcr0,
+            None => return failed("the reply did not echo the challenge"),
+        };
+        let expected = self
+            .process
+            .as_ref()
+            .and_then(|process| process.pcr0.as_ref());
+        if let Some(expected) = expected {
+            if Pcr::new(pcr0.to_vec()) != *expected {
+                return failed("the enclave's PCR0 is not the one it was launched with");
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a buffer of data to the enclave, and wait up to `ACK_TIMEOUT`
+    /// for the enclave to confirm that it received and accepted it, for
+    /// protocols in which a lost buffer is unacceptable. Unlike `flush`,
+    /// which only shows that the kernel passed the buffer on, this confirms
+    /// delivery to the enclave application. The buffer is sent as a typed
+    /// buffer (see `raw_fd::send_typed_buffer`) of type
+    /// `ACKED_DATA_FRAME_TYPE`, which the enclave answers with a typed buffer
+    /// of type `ACK_FRAME_TYPE`, or `NACK_FRAME_TYPE` to reject it. Fails
+    /// with `NitroError::AckTimeout` if no answer arrives in time, with
+    /// `NitroError::MessageRejected` if the enclave rejects the buffer, and
+    /// with `NitroError::UnexpectedAck` if it answers with anything else. As
+    /// with `ping`, the enclave must have nothing else in flight.
+    pub fn send_buffer_acked(&self, buffer: &[u8]) -> Result<()> {
+        let fd = self.connected_fd()?;
+        raw_fd::send_typed_buffer(fd, ACKED_DATA_FRAME_TYPE, buffer)
+            .with_context(|| self.error_context())?;
+        let deadline = Instant::now() + ACK_TIMEOUT;
+        if !raw_fd::poll_until(fd, PollFlags::POLLIN, deadline, &TransferConfig::default())
+            .with_context(|| self.error_context())?
+        {
+            return Err(anyhow!(NitroError::AckTimeout(ACK_TIMEOUT)));
+        }
+        let (frame_type, reply) =
+            raw_fd::receive_typed_buffer(fd).with_context(|| self.error_context())?;
+        match frame_type {
+            ACK_FRAME_TYPE => Ok(()),
+            NACK_FRAME_TYPE => Err(anyhow!(NitroError::MessageRejected(
+                String::from_utf8_lossy(&reply).into_owned()
+            ))),
+            _ => Err(anyhow!(NitroError::UnexpectedAck)),
+        }
+    }
+
+    /// Check that the enclave is responsive, returning the round-trip time
+    /// of the check. This sends a typed buffer of type `PING_FRAME_TYPE`
+    /// holding 8 arbitrary bytes, and waits for the enclave to send the same
+    /// bytes back in a typed buffer of type `PONG_FRAME_TYPE`, failing with
+    /// `NitroError::UnexpectedPong` if it replies with anything else. Polled
+    /// regularly, the round-trip time is a measure of the health of the
+    /// channel: a rising time suggests an overloaded enclave. As with
+    /// `request_diagnostics`, this must only be called when the enclave is
+    /// waiting for a request and has nothing else in flight.
+    pub fn ping(&self) -> Result<Duration> {
+        let fd = self.connected_fd()?;
+        let nonce = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos() as u64)
+            .unwrap_or_default()
+            .to_le_bytes();
+        let start = Instant::now();
+        raw_fd::send_typed_buffer(fd, PING_FRAME_TYPE, &nonce)
+            .with_context(|| self.error_context())?;
+        let (frame_type, reply) =
+            raw_fd::receive_typed_buffer(fd).with_context(|| self.error_context())?;
+        let round_trip = start.elapsed();
+        if frame_type != PONG_FRAME_TYPE || reply != nonce {
+            return Err(anyhow!(NitroError::UnexpectedPong));
+        }
+        Ok(round_trip)
+    }
+
+    /// send a buffer of data to the enclave, encrypted and authenticated with
+    /// the pre-shared `key` (see `raw_fd::send_buffer_sealed`)
+    #[cfg(feature = "encryption")]
+    pub fn send_buffer_sealed(
+        &self,
+        key: &[u8; raw_fd::SEALING_KEY_SIZE],
+        buffer: &[u8],
+    ) -> Result<()> {
+        raw_fd::send_buffer_sealed(self.connected_fd()?, key, buffer)
+            .with_context(|| self.error_context())
+    }
+
+    /// receive a buffer of data from the enclave sealed with the pre-shared
+    /// `key`, failing with `raw_fd::RawFdError::AuthenticationFailed` if it
+    /// has been tampered with
+    #[cfg(feature = "encryption")]
+    pub fn receive_buffer_sealed(&self, key: &[u8; raw_fd::SEALING_KEY_SIZE]) -> Result<Vec<u8>> {
+        raw_fd::receive_buffer_sealed(self.connected_fd()?, key)
+            .with_context(|| self.error_context())
+    }
+}
+
+impl Transport for NitroEnclave {
+    fn send(&self, buffer: &[u8]) -> Result<()> {
+        self.send_buffer(buffer)
+    }
+
+    fn recv(&self) -> Result<Vec<u8>> {
+        self.receive_buffer()
+    }
+
+    fn recv_tapped(&self, tap: &mut dyn FnMut(&[u8])) -> Result<Vec<u8>> {
+        raw_fd::receive_buffer_tapped(self.connected_fd()?, tap)
+            .with_context(|| self.error_context())
+    }
+}
+
+impl Drop for NitroEnclave {
+    /// Disconnect from the enclave. Terminating the enclave and closing the
+    /// VSOCK happen afterwards, as the fields are dropped.
+    fn drop(&mut self) {
+        if let Some(frame) = &self.connection.shutdown_frame {
+            let _ = self.send_buffer(frame);
+        }
+        // Do nothing on failure: the enclave may already have disconnected
+        shutdown(self.vsocksocket.as_raw_fd(), Shutdown::Both).unwrap_or(());
+    }
+}
+
+impl EnclaveProcess {
+    /// Start terminating the enclave in the background, without waiting for
+    /// the Nitro CLI tool to finish
+    fn terminate_detached(&self) {
+        let child = Command::new(&self.nitro_cli_path)
+            .args(["terminate-enclave", "--enclave-id", &self.enclave_id])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            // so that a ^C to this process's group doesn't also interrupt
+            // the termination
+            .process_group(0)
+            .spawn();
+        match child {
+            Ok(child) => {
+                let enclave_id = self.enclave_id.clone();
+                let leak_registry = self.leak_registry.clone();
+                let audit_log = self.audit_log.clone();
+                // reap the command, if this process lives long enough;
+                // otherwise it is inherited by init, which does
+                std::thread::spawn(move || {
+                    let outcome = match collect_output(child, None) {
+                        Ok(Some(output)) if output.status.success() => "terminated",
+                        Ok(Some(output)) if already_terminated(&output.stderr) => {
+                            "already_terminated"
+                        }
+                        _ => "failed",
+                    };
+                    if let (true, Some(registry)) = (outcome != "failed", leak_registry) {
+                        forget_leaked_enclave(&registry, &enclave_id);
+                    }
+                    if let Some(audit_log) = audit_log {
+                        audit_termination(&audit_log, &enclave_id, outcome);
+                    }
+                });
+            }
+            Err(err) => {
+                error!("NitroEnclave::drop Command::new returned err:{:?}. You will need to terminate the enclave yourself.", err);
+                self.audit_termination("failed");
+            }
+        }
+    }
+
+    /// Record the outcome of terminating the enclave in the audit log, if
+    /// there is one
+    fn audit_termination(&self, outcome: &str) {
+        if let Some(audit_log) = &self.audit_log {
+            audit_termination(audit_log, &self.enclave_id, outcome);
+        }
+    }
+}
+
+/// Append a record of the termination of the enclave `enclave_id`, with
+/// `outcome`, to the audit log at `audit_log`
+fn audit_termination(audit_log: &Path, enclave_id: &str, outcome: &str) {
+    append_audit_record(
+        audit_log,
+        json!({
+            "event": "terminate",
+            "enclave_id": enclave_id,
+            "outcome": outcome,
+        }),
+    );
+}
+
+/// Append `record`, stamped with the current time, to the audit log at
+/// `path` as one line of JSON. Failing to do so is not fatal.
+fn append_audit_record(path: &Path, mut record: Value) {
+    let timestamp_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default();
+    record["timestamp_ms"] = json!(timestamp_ms);
+    let mut line = record.to_string();
+    line.push('\n');
+    // written with a single `write` to a file opened for appending, so that
+    // records from concurrent writers aren't interleaved
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()));
+    if let Err(err) = result {
+        warn!("Failed to write to audit log {:?}: {:?}", path, err);
+    }
+}
+
+/// Whether a run of the Nitro CLI tool whose exit status was lost is
+/// treated as successful (see `ChildStatusPolicy`)
+static USE_OUTPUT_OF_REAPED_CHILDREN: AtomicBool = AtomicBool::new(false);
+
+/// Set what is done, throughout this process, when a run of the Nitro CLI
+/// tool is reaped by something other than this crate, such as the
+/// application's `SIGCHLD` handler (see the crate documentation)
+pub fn set_child_status_policy(policy: ChildStatusPolicy) {
+    USE_OUTPUT_OF_REAPED_CHILDREN.store(policy == ChildStatusPolicy::UseOutput, Ordering::SeqCst);
+}
+
+/// Run `command` to completion, collecting its output as `Command::output`
+/// does, but allowing for its exit status having been taken by something
+/// else in this process, as set with `set_child_status_policy`. All runs of
+/// the Nitro CLI tool go through here (or `output_with_timeout`).
+fn cli_output(command: &mut Command) -> std::io::Result<Output> {
+    let output = output_with_timeout(command, None)?;
+    Ok(output.expect("a run without a timeout can't time out"))
+}
+
+/// Run `command` to completion as `cli_output` does, unless it takes longer
+/// than `timeout`, in which case it is killed and `None` is returned
+fn output_with_timeout(
+    command: &mut Command,
+    timeout: Option<Duration>,
+) -> std::io::Result<Option<Output>> {
+    let child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    collect_output(child, timeout)
+}
+
+/// Wait for `child` to exit, collecting whichever of its output is piped,
+/// unless it takes longer than `timeout`, in which case it is killed and
+/// `None` is returned
+fn collect_output(mut child: Child, timeout: Option<Duration>) -> std::io::Result<Option<Output>> {
+    // drain the pipes while waiting, so a chatty command can't block on them
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let stdout_reader = std::thread::spawn(move || read_all(stdout));
+    let stderr_reader = std::thread::spawn(move || read_all(stderr));
+    let status = match timeout {
+        None => child.wait(),
+        Some(timeout) => {
+            let deadline = Instant::now() + timeout;
+            loop {
+                match child.try_wait() {
+                    Ok(Some(status)) => break Ok(status),
+                    Ok(None) => (),
+                    Err(err) => break Err(err),
+                }
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Ok(None);
+                }
+                std::thread::sleep(remaining.min(CLI_POLL_INTERVAL));
+            }
+        }
+    };
+    let stdout = stdout_reader.join().unwrap_or_else(|_| Ok(Vec::new()))?;
+    let stderr = stderr_reader.join().unwrap_or_else(|_| Ok(Vec::new()))?;
+    let status = match status {
+        // the child was reaped by someone else: `SIGCHLD` is ignored, or an
+        // application handler waited for it
+        Err(err) if err.raw_os_error() == Some(libc::ECHILD) => {
+            if !USE_OUTPUT_OF_REAPED_CHILDREN.load(Ordering::SeqCst) {
+                return Err(std::io::Error::other(NitroError::ChildStatusLost(
+                    child.id(),
+                )));
+            }
+            warn!(
+                "The exit status of Nitro CLI process {} was lost: judging it by its output",
+                child.id()
+            );
+            ExitStatus::from_raw(0)
+        }
+        status => status?,
+    };
+    Ok(Some(Output {
+        status,
+        stdout,
+        stderr,
+    }))
+}
+
+/// Read everything from `pipe`, if there is one
+fn read_all<R: Read>(pipe: Option<R>) -> std::io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    if let Some(mut pipe) = pipe {
+        pipe.read_to_end(&mut buffer)?;
+    }
+    Ok(buffer)
+}
+
+/// Remove the enclave `enclave_id` from the leak registry at `registry`, once
+/// it has been terminated. Failing to do so is not fatal.
+fn forget_leaked_enclave(registry: &Path, enclave_id: &str) {
+    update_leak_registry(registry, |entries| {
+        entries.retain(|(id, _)| id != enclave_id)
+    })
+    .unwrap_or_else(|e| warn!("Failed to remove enclave from leak registry: {:?}", e));
+}
+
+impl Drop for EnclaveProcess {
+    /// Drop the enclave. In ideal conditions, this means that the enclave will
+    /// be terminated.
+    fn drop(&mut self) {
+        if !self.terminate_on_drop {
+            return;
+        }
+        if self.async_terminate {
+            self.terminate_detached();
+            return;
+        }
+        // shutdown the enclave
+        let args = ["terminate-enclave", "--enclave-id", &self.enclave_id];
+        let mut attempt = 0;
+        loop {
+            let enclave_result = output_with_timeout(
+                Command::new(&self.nitro_cli_path).args(args),
+                Some(self.terminate_timeout),
+            );
+            match enclave_result {
+                Ok(None) => {
+                    error!("NitroEnclave::drop: terminate-enclave for enclave {} did not finish within {:?} and was killed. You will need to terminate the enclave yourself.", self.enclave_id, self.terminate_timeout);
+                    self.audit_termination("failed");
+                    break;
+                }
+                Err(err) => {
+                    if !self.retry_policy.allows_retry(attempt) {
+                        error!("NitroEnclave::drop Command::new returned err:{:?}. You will need to terminate the enclave yourself.", err);
+                        self.audit_termination("failed");
+                        break;
+                    }
+                    debug!("NitroEnclave::drop Command::new returned err:{:?}, sleeping and trying again", err);
+                    std::thread::sleep(self.retry_backoff.delay(attempt));
+                    attempt += 1;
+                    continue;
+                }
+                Ok(Some(result)) => {
+                    let recorded = record_cli_output(&mut self.diagnostics_writer, &args, &result);
+                    if result.status.success() || already_terminated(&result.stderr) {
+                        if let Some(registry) = &self.leak_registry {
+                            forget_leaked_enclave(registry, &self.enclave_id);
+                        }
+                        self.audit_termination(if result.status.success() {
+                            "terminated"
+                        } else {
+                            "already_terminated"
+                        });
+                    } else {
+                        error!("NitroEnclave::drop failed to terminate the enclave (exit_status:{:?}). You will need to terminate it yourself.", result.status);
+                        self.audit_termination("failed");
+                        if !recorded {
+                            let result_stderr = std::str::from_utf8(&result.stderr).unwrap();
+                            warn!("NitroEnclave::drop CLI error:{:?}", result_stderr);
+                        }
+                    }
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Whether the error output of a failed `nitro-cli terminate-enclave` says
+/// that there was no such enclave to terminate, in which case the enclave has
+/// already gone (if it crashed, say) and the termination has nothing to do.
+/// The Nitro CLI tool has no distinct exit status for this, so its message
+/// is matched instead.
+fn already_terminated(stderr: &[u8]) -> bool {
+    let stderr = String::from_utf8_lossy(stderr).to_lowercase();
+    ["not found", "does not exist", "no such enclave"]
+        .iter()
+        .any(|message| stderr.contains(message))
+}
+
+/// Whether the Nitro CLI tool at `nitro_cli_path` lists `command` among the
+/// subcommands in its help
+fn cli_has_command(nitro_cli_path: &str, command: &str) -> Result<bool> {
+    let output = cli_output(Command::new(nitro_cli_path).arg("--help"))?;
+    let help = String::from_utf8_lossy(&output.stdout);
+    Ok(help
+        .split_whitespace()
+        .any(|word| word.trim_end_matches(',') == command))
+}
+
+/// Whether the Nitro CLI tool at `nitro_cli_path` lists `option` among the
+/// options in its help for `command`
+fn cli_has_option(nitro_cli_path: &str, command: &str, option: &str) -> Result<bool> {
+    let output = cli_output(Command::new(nitro_cli_path).args([command, "--help"]))?;
+    let help = String::from_utf8_lossy(&output.stdout);
+    Ok(help
+        .split_whitespace()
+        .any(|word| word.trim_end_matches(',').split('=').next() == Some(option)))
+}
+
+/// Run `nitro-cli describe-enclaves` and return its description of each
+/// enclave on this host
+fn describe_enclaves(nitro_cli_path: &str) -> Result<Vec<Value>> {
+    let output = cli_output(Command::new(nitro_cli_path).arg("describe-enclaves"))?;
+    if !output.status.success() {
+        return Err(anyhow!(NitroError::CliError(
+            String::from_utf8_lossy(&output.stderr).trim().to_string()
+        )));
+    }
+    match serde_json::from_slice(&output.stdout)? {
+        Value::Array(enclaves) => Ok(enclaves),
+        _ => Err(anyhow!(NitroError::SerdeError)),
+    }
+}
+
+/// Check that `cid`, as reported by the Nitro CLI tool, is one an enclave
+/// could have, failing with `NitroError::InvalidCid` if it is reserved (or
+/// is the wildcard `vsocket::VMADDR_CID_ANY`), which means the tool's output
+/// is malformed
+fn check_enclave_cid(cid: u64) -> Result<u32> {
+    match u32::try_from(cid) {
+        Ok(cid) if u64::from(cid) >= MIN_ENCLAVE_CID && cid != vsocket::VMADDR_CID_ANY => Ok(cid),
+        _ => Err(anyhow!(NitroError::InvalidCid(cid))),
+    }
+}
+
+/// The memory in MiB and the number of CPUs in a description of an enclave
+/// from the Nitro CLI tool, where present
+fn allocation_of(enclave: &Value) -> (Option<u32>, Option<u32>) {
+    let field = |name: &str| {
+        enclave[name]
+            .as_u64()
+            .and_then(|value| u32::try_from(value).ok())
+    };
+    (
+        field("MemoryMiB"),
+        field("NumberOfCPUs").or_else(|| field("CPUCount")),
+    )
+}
+
+/// Whether a description of an enclave from the Nitro CLI tool says that it
+/// is running in debug mode, if it has any flags
+fn debug_mode_of(enclave: &Value) -> Option<bool> {
+    enclave["Flags"]
+        .as_str()
+        .map(|flags| flags.contains("DEBUG_MODE"))
+}
+
+/// Sum the memory reserved in huge pages of all sizes, in MiB
+fn reserved_hugepage_memory_mib() -> std::io::Result<u64> {
+    let mut total_kib = 0;
+    for entry in fs::read_dir(HUGEPAGES_SYSFS_DIR)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        // directories are named e.g. `hugepages-2048kB`
+        let page_kib: u64 = match name
+            .to_str()
+            .and_then(|name| name.strip_prefix("hugepages-"))
+            .and_then(|size| size.strip_suffix("kB"))
+            .and_then(|size| size.parse().ok())
+        {
+            Some(page_kib) => page_kib,
+            None => continue,
+        };
+        let pages: u64 = fs::read_to_string(entry.path().join("nr_hugepages"))?
+            .trim()
+            .parse()
+            .unwrap_or(0);
+        total_kib += pages * page_kib;
+    }
+    Ok(total_kib / 1024)
+}
+
+/// The memory (in MiB) the allocator service is configured to reserve for
+/// enclaves, if its configuration file can be read. Only the top-level
+/// `memory_mib` key is needed, so the YAML is scanned line by line rather
+/// than parsed.
+fn allocator_memory_mib() -> Option<u64> {
+    fs::read_to_string(ALLOCATOR_CONFIG_PATH)
+        .ok()?
+        .lines()
+        .find_map(|line| {
+            let value = line.strip_prefix("memory_mib:")?;
+            // allow for a trailing comment
+            value.split('#').next()?.trim().parse().ok()
+        })
+}
+
+/// Check whether this host is configured to run Nitro enclaves, without
+/// attempting to launch one: that the Nitro enclaves device is present, that
+/// the allocator service has reserved some memory, and that the Nitro CLI
+/// tool at `nitro_cli_path` works. Problems are reported in the returned
+/// `PreflightReport` rather than as an error.
+pub fn preflight_check(nitro_cli_path: &str) -> Result<PreflightReport> {
+    let mut problems = Vec::new();
+
+    let device_present = Path::new(NITRO_ENCLAVES_DEVICE).exists();
+    if !device_present {
+        problems.push(format!(
+            "{} is missing: is this an enclave-enabled instance with the nitro_enclaves driver loaded?",
+            NITRO_ENCLAVES_DEVICE
+        ));
+    }
+
+    let has_permission = match check_enclave_permissions() {
+        Ok(()) => true,
+        Err(err) => {
+            problems.push(err.to_string());
+            false
+        }
+    };
+
+    let reserved_memory_mib = match reserved_hugepage_memory_mib() {
+        Ok(reserved_memory_mib) => reserved_memory_mib,
+        Err(err) => {
+            problems.push(format!("failed to read reserved huge pages: {}", err));
+            0
+        }
+    };
+    if reserved_memory_mib == 0 {
+        problems.push(
+            "no memory is reserved for enclaves: is the nitro-enclaves-allocator service running?"
+                .to_string(),
+        );
+    }
+
+    let cli_working = match cli_output(Command::new(nitro_cli_path).arg("describe-enclaves")) {
+        Ok(output) if output.status.success() => true,
+        Ok(output) => {
+            problems.push(format!(
+                "{} describe-enclaves failed (exit_status:{:?}): {}",
+                nitro_cli_path,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+            false
+        }
+        Err(err) => {
+            problems.push(format!("failed to run {}: {}", nitro_cli_path, err));
+            false
+        }
+    };
+
+    let vsock_supported = match check_vsock_support() {
+        Ok(()) => true,
+        Err(err) => {
+            problems.push(err.to_string());
+            false
+        }
+    };
+
+    Ok(PreflightReport {
+        device_present,
+        has_permission,
+        reserved_memory_mib,
+        cli_working,
+        vsock_supported,
+        problems,
+    })
+}
+
+/// Check that this process may use the Nitro enclaves device and write to
+/// the directories the Nitro CLI tool keeps its state in, so that a missing
+/// group membership is reported as such before a launch is attempted, rather
+/// than as a Nitro CLI error that looks like any other. Fails with
+/// `NitroError::PermissionDenied`, saying what is inaccessible and how to
+/// fix it. Anything missing altogether is left for `preflight_check` (or the
+/// launch) to report. This runs before every launch.
+pub fn check_enclave_permissions() -> Result<()> {
+    let denied = |path: &str, mode: AccessFlags| access(path, mode) == Err(Errno::EACCES);
+    let mut problems = Vec::new();
+    if denied(NITRO_ENCLAVES_DEVICE, AccessFlags::R_OK | AccessFlags::W_OK) {
+        let group = fs::metadata(NITRO_ENCLAVES_DEVICE)
+            .ok()
+            .and_then(|metadata| Group::from_gid(Gid::from_raw(metadata.gid())).ok()?)
+            .map(|group| group.name);
+        problems.push(match group {
+            Some(group) => format!(
+                "{} is only accessible to the {} group: add this user to it (`sudo usermod -aG {} $USER`, then log in again) or run as root",
+                NITRO_ENCLAVES_DEVICE, group, group
+            ),
+            None => format!(
+                "this user can't open {}: run as root, or as a member of the group that owns it",
+                NITRO_ENCLAVES_DEVICE
+            ),
+        });
+    }
+    for directory in NITRO_CLI_DIRECTORIES {
+        if denied(directory, AccessFlags::W_OK | AccessFlags::X_OK) {
+            problems.push(format!(
+                "this user can't write to {}, where the Nitro CLI tool keeps its state",
+                directory
+            ));
+        }
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(NitroError::PermissionDenied(problems.join("; "))))
+    }
+}
+
+/// Check that the kernel supports VSOCKs, by creating (and closing) one, so
+/// that a host without VSOCK support is reported as such rather than as a
+/// bare errno from connecting. Fails with `NitroError::VsockUnsupported` if
+/// the kernel doesn't support the address family. This is also checked
+/// before connecting to an enclave.
+pub fn check_vsock_support() -> Result<()> {
+    match socket(
+        AddressFamily::Vsock,
+        SockType::Stream,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    ) {
+        Ok(fd) => {
+            close(fd).unwrap_or(());
+            Ok(())
+        }
+        Err(Errno::EAFNOSUPPORT) => {
+            let device = if Path::new(VSOCK_DEVICE).exists() {
+                ""
+            } else {
+                " and /dev/vsock is missing"
+            };
+            Err(anyhow!(NitroError::VsockUnsupported(format!(
+                "the kernel does not support the AF_VSOCK address family{}: load the VSOCK modules (e.g. `modprobe vmw_vsock_virtio_transport`) or use a kernel built with them",
+                device
+            ))))
+        }
+        Err(err) => Err(anyhow!(err)),
+    }
+}
+
+/// Check that the Nitro CLI tool at `nitro_cli_path` speaks the JSON schema
+/// this crate expects, so that deployment tooling can fail at startup rather
+/// than on the first launch. This runs `--version` and `describe-enclaves`,
+/// and checks that each enclave described has the
+/// `REQUIRED_DESCRIBE_ENCLAVES_FIELDS`, of the right types. Nothing is
+/// launched, so if no enclaves are running, only the shape of the output of
+/// `describe-enclaves` can be checked, and the `REQUIRED_RUN_ENCLAVE_FIELDS`
+/// aren't checked at all. Fails with `NitroError::IncompatibleCli`
+/// describing the first discrepancy found.
+pub fn verify_cli_compatibility(nitro_cli_path: &str) -> Result<()> {
+    let incompatible = |reason: String| anyhow!(NitroError::IncompatibleCli(reason));
+    let version = cli_output(Command::new(nitro_cli_path).arg("--version"))?;
+    if !version.status.success() {
+        return Err(incompatible(format!(
+            "{} --version failed (exit_status:{:?}): {}",
+            nitro_cli_path,
+            version.status,
+            String::from_utf8_lossy(&version.stderr).trim()
+        )));
+    }
+    let enclaves = describe_enclaves(nitro_cli_path)
+        .map_err(|err| incompatible(format!("describe-enclaves: {}", err)))?;
+    for enclave in &enclaves {
+        for field in REQUIRED_DESCRIBE_ENCLAVES_FIELDS {
+            let valid = match *field {
+                "EnclaveCID" => enclave[field].is_u64(),
+                _ => enclave[field].is_string(),
+            };
+            if !valid {
+                return Err(incompatible(format!(
+                    "describe-enclaves reported an enclave with {} {}: {}",
+                    if enclave[field].is_null() {
+                        "no"
+                    } else {
+                        "an unexpected"
+                    },
+                    field,
+                    enclave
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Split connections.
+////////////////////////////////////////////////////////////////////////////////
+
+/// The state shared by the two halves of a split `NitroEnclave`
+struct SplitEnclave {
+    /// The VSOCK to the enclave, or `None` if the enclave wasn't running
+    /// when it was split
+    fd: Option<RawFd>,
+    /// The context given to transport errors, as for `NitroEnclave`
+    context: String,
+    /// Held while a buffer is sent, so that sends aren't interleaved
+    send_lock: Mutex<()>,
+    /// Held while a buffer is received, so that receives aren't interleaved
+    receive_lock: Mutex<()>,
+    /// The enclave, which owns the VSOCK and is terminated once both halves
+    /// are dropped. It is never locked: the mutex only makes it shareable.
+    _enclave: Mutex<NitroEnclave>,
+}
+
+impl SplitEnclave {
+    /// The VSOCK to the enclave, failing with `NitroError::NotRunning` if the
+    /// enclave isn't running
+    fn fd(&self) -> Result<RawFd> {
+        self.fd.ok_or_else(|| anyhow!(NitroError::NotRunning))
+    }
+}
+
+/// The sending half of a `NitroEnclave`, from `NitroEnclave::split`. Clones
+/// send on the same connection, taking turns.
+#[derive(Clone)]
+pub struct EnclaveSender(Arc<SplitEnclave>);
+
+impl EnclaveSender {
+    /// send a buffer of data to the enclave, as `NitroEnclave::send_buffer`
+    /// does, waiting for any other sender to finish first
+    pub fn send_buffer(&self, buffer: &[u8]) -> Result<()> {
+        let fd = self.0.fd()?;
+        let _guard = lock_ignoring_poison(&self.0.send_lock);
+        raw_fd::send_buffer(fd, buffer).with_context(|| self.0.context.clone())
+    }
+}
+
+/// The receiving half of a `NitroEnclave`, from `NitroEnclave::split`.
+/// Clones receive from the same connection, taking turns.
+#[derive(Clone)]
+pub struct EnclaveReceiver(Arc<SplitEnclave>);
+
+impl EnclaveReceiver {
+    /// receive a buffer of data from the enclave, as
+    /// `NitroEnclave::receive_buffer` does, waiting for any other receiver to
+    /// finish first
+    pub fn receive_buffer(&self) -> Result<Vec<u8>> {
+        let fd = self.0.fd()?;
+        let _guard = lock_ignoring_poison(&self.0.receive_lock);
+        raw_fd::receive_buffer(fd).with_context(|| self.0.context.clone())
+    }
+}
+
+/// Lock `mutex`, carrying on if a thread panicked while holding it: the
+/// locks on a split connection guard no data
+fn lock_ignoring_poison(mutex: &Mutex<()>) -> MutexGuard<'_, ()> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Port allocation.
+////////////////////////////////////////////////////////////////////////////////
+
+/// The ports of the enclaves this process currently holds, with one entry
+/// per enclave
+static PORTS_IN_USE: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+/// The ports in use by this process, tolerating a panic elsewhere while the
+/// lock was held
+fn ports_in_use_lock() -> MutexGuard<'static, Vec<u32>> {
+    PORTS_IN_USE
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// An enclave's entry in `PORTS_IN_USE`, removed when this is dropped
+struct PortClaim(u32);
+
+impl PortClaim {
+    /// Record that an enclave is using `port`, warning if another enclave
+    /// held by this process already is
+    fn new(port: u32) -> Self {
+        let mut ports = ports_in_use_lock();
+        if ports.contains(&port) {
+            warn!(
+                "NitroEnclave: port {} is already in use by another enclave in this process",
+                port
+            );
+        }
+        ports.push(port);
+        PortClaim(port)
+    }
+}
+
+impl Drop for PortClaim {
+    fn drop(&mut self) {
+        let mut ports = ports_in_use_lock();
+        if let Some(index) = ports.iter().position(|port| *port == self.0) {
+            ports.swap_remove(index);
+        }
+    }
+}
+
+/// The VSOCK ports of the enclaves currently held by this process (launched,
+/// attached to or built with `NitroEnclave::from_parts`), in ascending order
+/// and without repeats. The Nitro CLI tool doesn't report which ports an
+/// enclave listens on, so enclaves held by other processes can't be
+/// included. VSOCK ports are per CID, so two enclaves on the same port don't
+/// interfere with each other's connections; distinct ports matter where
+/// something else keys on the port alone, such as a listener on the parent
+/// instance or a proxy.
+pub fn ports_in_use() -> Vec<u32> {
+    let mut ports = ports_in_use_lock().clone();
+    ports.sort_unstable();
+    ports.dedup();
+    ports
+}
+
+/// The lowest port from `first` upwards that isn't in `ports_in_use`, for
+/// giving each of several enclaves its own port. Launching an enclave on a
+/// port already in use by this process prints a warning.
+pub fn suggest_unused_port(first: u32) -> u32 {
+    let ports = ports_in_use();
+    (first..=u32::MAX)
+        .find(|port| ports.binary_search(port).is_err())
+        .unwrap_or(first)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Leak checking.
+////////////////////////////////////////////////////////////////////////////////
+
+/// The number of enclaves this process currently holds
+#[cfg(feature = "leak-check")]
+static LIVE_ENCLAVES: AtomicUsize = AtomicUsize::new(0);
+
+/// The most enclaves this process may hold at once, or `usize::MAX` for no
+/// limit
+#[cfg(feature = "leak-check")]
+static LIVE_ENCLAVE_LIMIT: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// An enclave's place in `LIVE_ENCLAVES`, given up when this is dropped
+#[cfg(feature = "leak-check")]
+struct LiveEnclave;
+
+#[cfg(feature = "leak-check")]
+impl LiveEnclave {
+    /// Count another live enclave, failing with `NitroError::TooManyEnclaves`
+    /// if that would exceed the limit set with `set_live_enclave_limit`
+    fn claim() -> Result<Self> {
+        let limit = LIVE_ENCLAVE_LIMIT.load(Ordering::SeqCst);
+        LIVE_ENCLAVES
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < limit).then_some(count + 1)
+            })
+            .map(|_| LiveEnclave)
+            .map_err(|_| anyhow!(NitroError::TooManyEnclaves(limit)))
+    }
+
+    /// Count another live enclave regardless of the limit, for enclaves that
+    /// already exist by the time they are handed to this crate
+    fn track() -> Self {
+        LIVE_ENCLAVES.fetch_add(1, Ordering::SeqCst);
+        LiveEnclave
+    }
+}
+
+#[cfg(feature = "leak-check")]
+impl Drop for LiveEnclave {
+    fn drop(&mut self) {
+        LIVE_ENCLAVES.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The number of enclaves this process currently holds: launched (and not
+/// yet dropped), attached to or built with `NitroEnclave::from_parts`. An
+/// enclave being launched counts from the start of the launch. Asserting that
+/// this is zero at the end of a test checks that every enclave was torn down.
+#[cfg(feature = "leak-check")]
+pub fn live_enclave_count() -> usize {
+    LIVE_ENCLAVES.load(Ordering::SeqCst)
+}
+
+/// Limit the number of enclaves this process may hold at once to `limit`, or
+/// remove the limit with `None` (the default). Launching or attaching to an
+/// enclave beyond the limit fails with `NitroError::TooManyEnclaves`, without
+/// running the Nitro CLI tool. Enclaves built with `NitroEnclave::from_parts`
+/// are counted, but never refused. Lowering the limit below the number of
+/// enclaves already held doesn't affect them.
+#[cfg(feature = "leak-check")]
+pub fn set_live_enclave_limit(limit: Option<usize>) {
+    LIVE_ENCLAVE_LIMIT.store(limit.unwrap_or(usize::MAX), Ordering::SeqCst);
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Leaked enclave recovery.
+////////////////////////////////////////////////////////////////////////////////
+
+/// Apply `update` to the `(enclave ID, owning process ID)` entries of the
+/// leaked enclave registry at `path`, under an exclusive lock so that several
+/// processes can share one registry. The registry is created if it doesn't
+/// exist.
+fn update_leak_registry<F>(path: &Path, update: F) -> Result<()>
+where
+    F: FnOnce(&mut Vec<(String, u32)>),
+{
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file: File = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    // the lock is released when `file` is closed
+    flock(file.as_raw_fd(), FlockArg::LockExclusive)?;
+
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let mut entries: Vec<(String, u32)> = contents
+        .lines()
+        .filter_map(|line| {
+            let (enclave_id, pid) = line.split_once(' ')?;
+            Some((enclave_id.to_string(), pid.parse().ok()?))
+        })
+        .collect();
+
+    update(&mut entries);
+
+    let contents: String = entries
+        .iter()
+        .map(|(enclave_id, pid)| format!("{} {}\n", enclave_id, pid))
+        .collect();
+    file.set_len(0)?;
+    file.rewind()?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+/// Terminate every enclave in the leaked enclave registry at `registry_path`
+/// whose owning process is no longer running, and return their IDs. This is
+/// intended to be called at startup, to clean up after a previous incarnation
+/// of the process that crashed or otherwise failed to drop its enclaves.
+/// Enclaves which fail to terminate are left in the registry, but those the
+/// Nitro CLI tool reports to have gone already count as reaped. An owner
+/// that exists but can't be signalled, such as a process of another user,
+/// counts as running. If the Nitro CLI tool can't be run, no more enclaves
+/// are terminated and the error is returned.
+pub fn reap_leaked_enclaves<P: AsRef<Path>>(
+    registry_path: P,
+    nitro_cli_path: &str,
+) -> Result<Vec<String>> {
+    let mut reaped = Vec::new();
+    let mut result = Ok(());
+    update_leak_registry(registry_path.as_ref(), |entries| {
+        entries.retain(|(enclave_id, pid)| {
+            // sending no signal just checks that the process exists, and
+            // only `ESRCH` says that it doesn't
+            let owner_alive = i32::try_from(*pid)
+                .map(|pid| kill(Pid::from_raw(pid), None) != Err(Errno::ESRCH))
+                .unwrap_or(false);
+            if owner_alive || result.is_err() {
+                return true;
+            }
+            match cli_output(Command::new(nitro_cli_path).args([
+                "terminate-enclave",
+                "--enclave-id",
+                enclave_id,
+            ])) {
+                Ok(output) if output.status.success() || already_terminated(&output.stderr) => {
+                    reaped.push(enclave_id.clone());
+                    false
+                }
+                Ok(output) => {
+                    warn!(
+                        "reap_leaked_enclaves failed to terminate {} (exit_status:{:?}): {:?}",
+                        enclave_id,
+                        output.status,
+                        String::from_utf8_lossy(&output.stderr)
+                    );
+                    true
+                }
+                Err(err) => {
+                    result = Err(anyhow!(err));
+                    true
+                }
+            }
+        })
+    })?;
+    result.map(|_| reaped)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Tests.
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use nix::sys::socket::socketpair;
+    use std::os::unix::{fs::PermissionsExt, io::FromRawFd};
+    use tempfile::TempDir;
+
+    /// Shell commands that log the arguments each run of a fake tool is given,
+    /// for `FakeCli::calls`
+    const LOG_CALLS: &str = r#"[ "$1" = --version ] || echo "$*" >> "$(dirname "$0")/calls""#;
+
+    /// A fake Nitro CLI tool: a shell script, run with the tool's arguments
+    struct FakeCli {
+        /// The directory holding the script, removed when dropped
+        dir: TempDir,
+    }
+
+    impl FakeCli {
+        /// Write a fake tool running the shell commands in `script`
+        fn new(script: &str) -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("nitro-cli");
+            fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            let fake = FakeCli { dir };
+            // A child forked by another test while the script was open for
+            // writing holds it open until it execs, and running the script
+            // until then fails with `ETXTBSY`; once it has run, no such
+            // child is left
+            loop {
+                match Command::new(fake.path()).arg("--version").output() {
+                    Err(err) if err.raw_os_error() == Some(libc::ETXTBSY) => {
+                        std::thread::sleep(Duration::from_millis(1))
+                    }
+                    result => break result.map(|_| ()).unwrap(),
+                }
+            }
+            fake
+        }
+
+        /// The fake tool's path
+        fn path(&self) -> String {
+            self.dir
+                .path()
+                .join("nitro-cli")
+                .to_str()
+                .unwrap()
+                .to_string()
+        }
+
+        /// The arguments of each run of a fake tool whose script starts with
+        /// `LOG_CALLS`, in order
+        fn calls(&self) -> Vec<String> {
+            fs::read_to_string(self.dir.path().join("calls"))
+                .unwrap_or_default()
+                .lines()
+                .map(str::to_string)
+                .collect()
+        }
+
+        /// A builder for an enclave launched with the fake tool
+        fn builder(&self) -> NitroEnclaveBuilder {
+            NitroEnclaveBuilder::new("enclave.eif", 512, 5005)
+                .nitro_cli_path(&self.path())
+                .retry_policy(RetryPolicy::None)
+        }
+    }
+
+    /// The `NitroError` that the launch `builder` describes fails with
+    fn launch_error(builder: NitroEnclaveBuilder) -> NitroError {
+        match builder.launch() {
+            Ok(_) => panic!("the launch succeeded"),
+            Err(err) => err.downcast().unwrap(),
+        }
+    }
+
+    /// The `NitroError` that building the command line for `builder` fails
+    /// with
+    fn command_line_error(builder: NitroEnclaveBuilder) -> NitroError {
+        match builder.command_line() {
+            Ok(command_line) => panic!("built the command line {:?}", command_line),
+            Err(err) => err.downcast().unwrap(),
+        }
+    }
+
+    #[test]
+    fn launch_settings_are_validated() {
+        let builder = || NitroEnclaveBuilder::new("enclave.eif", 512, 5005);
+        let invalid = [
+            NitroEnclaveBuilder::new("", 512, 5005),
+            NitroEnclaveBuilder::new("enclave.eif", MIN_ENCLAVE_MEMORY_MIB - 1, 5005),
+            builder().cpu_count(Some(0)),
+            builder().enclave_cid(Some(0)),
+            builder().enclave_cid(Some(MIN_REQUESTED_ENCLAVE_CID - 1)),
+            builder().enclave_cid(Some(vsocket::VMADDR_CID_ANY)),
+        ];
+        for builder in invalid {
+            match command_line_error(builder) {
+                NitroError::InvalidLaunchSettings(_) => (),
+                err => panic!("unexpected error: {:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn cpu_ids_are_validated() {
+        let builder = || NitroEnclaveBuilder::new("enclave.eif", 512, 5005);
+        let invalid = [
+            builder().cpu_ids(&[]),
+            builder().cpu_ids(&[0]),
+            builder().cpu_ids(&[u32::MAX]),
+            builder().cpu_count(Some(2)).cpu_ids(&[1]),
+        ];
+        for builder in invalid {
+            match command_line_error(builder) {
+                NitroError::InvalidCpuIds(_) => (),
+                err => panic!("unexpected error: {:?}", err),
+            }
+        }
+        let host_cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) };
+        if host_cpus > 1 {
+            match command_line_error(builder().cpu_ids(&[1, 1])) {
+                NitroError::InvalidCpuIds(_) => (),
+                err => panic!("unexpected error: {:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn memory_below_the_minimum_is_raised_if_asked() {
+        let command_line = NitroEnclaveBuilder::new("enclave.eif", 1, 5005)
+            .auto_adjust_memory(true)
+            .command_line()
+            .unwrap();
+        let memory = command_line
+            .iter()
+            .position(|arg| arg == "--memory")
+            .unwrap();
+        assert_eq!(command_line[memory + 1], MIN_ENCLAVE_MEMORY_MIB.to_string());
+    }
+
+    #[test]
+    fn run_enclave_args_are_built() {
+        let builder = || {
+            NitroEnclaveBuilder::new("enclave.eif", 512, 5005).nitro_cli_path("/usr/bin/nitro-cli")
+        };
+        let expected = |args: &[&str]| {
+            let mut command_line = vec![
+                "/usr/bin/nitro-cli",
+                "run-enclave",
+                "--eif-path",
+                "enclave.eif",
+            ];
+            command_line.extend_from_slice(args);
+            command_line
+                .into_iter()
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+        let default_cpu_count = DEFAULT_CPU_COUNT.to_string();
+        assert_eq!(
+            builder().command_line().unwrap(),
+            expected(&["--cpu-count", &default_cpu_count, "--memory", "512"])
+        );
+        assert_eq!(
+            builder().cpu_count(None).command_line().unwrap(),
+            expected(&["--memory", "512"])
+        );
+        assert_eq!(
+            builder()
+                .cpu_count(Some(4))
+                .enclave_cid(Some(16))
+                .debug(true)
+                .command_line()
+                .unwrap(),
+            expected(&[
+                "--cpu-count",
+                "4",
+                "--memory",
+                "512",
+                "--enclave-cid",
+                "16",
+                "--debug-mode=true",
+            ])
+        );
+    }
+
+    #[test]
+    fn empty_cli_output_is_reported() {
+        for stdout in ["", "  \n\t"] {
+            let cli = FakeCli::new(&format!("printf '{}'", stdout));
+            match launch_error(cli.builder()) {
+                NitroError::EmptyCliOutput(command) => {
+                    assert!(command.starts_with("run-enclave --eif-path enclave.eif"))
+                }
+                err => panic!("unexpected error: {:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn a_reserved_cid_from_the_cli_is_rejected() {
+        let cli = FakeCli::new(&format!(
+            r#"{}
+            [ "$1" = run-enclave ] && echo '{{"EnclaveID": "i-0-enc-0", "EnclaveCID": 0}}'
+            true"#,
+            LOG_CALLS
+        ));
+        match launch_error(cli.builder()) {
+            NitroError::InvalidCid(0) => (),
+            err => panic!("unexpected error: {:?}", err),
+        }
+        // the enclave was launched, so it must not be left running
+        assert!(cli
+            .calls()
+            .contains(&"terminate-enclave --enclave-id i-0-enc-0".to_string()));
+    }
+
+    #[test]
+    fn only_plausible_enclave_cids_are_accepted() {
+        let any = u64::from(vsocket::VMADDR_CID_ANY);
+        for cid in [0, 1, 2, any, u64::from(u32::MAX) + 1, u64::MAX] {
+            match check_enclave_cid(cid).unwrap_err().downcast() {
+                Ok(NitroError::InvalidCid(invalid)) => assert_eq!(invalid, cid),
+                err => panic!("unexpected error: {:?}", err),
+            }
+        }
+        for cid in [3, 4, 16, any - 1] {
+            assert_eq!(u64::from(check_enclave_cid(cid).unwrap()), cid);
+        }
+    }
+
+    #[test]
+    fn the_debug_mode_described_by_the_cli_wins() {
+        let launched = |flags: &str| {
+            let cli = FakeCli::new(&format!(
+                r#"case "$1" in
+                run-enclave) echo '{{"EnclaveID": "i-0-enc-1", "EnclaveCID": 16}}' ;;
+                describe-enclaves) echo '[{{"EnclaveID": "i-0-enc-1", {}"State": "RUNNING"}}]' ;;
+                esac"#,
+                flags
+            ));
+            let launched = cli.builder().launch().unwrap();
+            let debug = launched.process.debug;
+            drop(launched);
+            debug
+        };
+        assert_eq!(launched(r#""Flags": "DEBUG_MODE", "#), Some(true));
+        assert_eq!(launched(r#""Flags": "NONE", "#), Some(false));
+        // without flags, the mode asked for is all there is to go on
+        assert_eq!(launched(""), Some(false));
+    }
+
+    /// A `NitroEnclave` over one end of a Unix stream socket pair, and the
+    /// other end. There is no Nitro CLI tool to terminate the enclave with,
+    /// so it must be taken apart with `into_socket` when done.
+    pub(crate) fn connected_pair() -> (NitroEnclave, vsocket::VsockSocket) {
+        let (a, b) = socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::empty(),
+        )
+        .unwrap();
+        let (a, b) = unsafe {
+            (
+                vsocket::VsockSocket::from_raw_fd(a),
+                vsocket::VsockSocket::from_raw_fd(b),
+            )
+        };
+        (NitroEnclave::from_parts("i-0-enc-1".to_string(), 16, a), b)
+    }
+
+    #[test]
+    fn an_unknown_debug_mode_counts_as_debug() {
+        let (enclave, _peer) = connected_pair();
+        assert!(enclave.is_debug());
+        enclave.into_socket();
+    }
+
+    /// The ID of a process that has exited
+    fn dead_pid() -> u32 {
+        let mut child = Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        child.id()
+    }
+
+    #[test]
+    fn only_enclaves_of_dead_owners_are_reaped() {
+        let cli = FakeCli::new(
+            r#"[ "$1" = terminate-enclave ] && echo "$3" >> "$(dirname "$0")/terminated""#,
+        );
+        let registry = cli.dir.path().join("registry");
+        // PID 1 always exists, and can't be signalled unless this is root
+        fs::write(
+            &registry,
+            format!(
+                "i-0-enc-dead {}\ni-0-enc-init 1\ni-0-enc-self {}\n",
+                dead_pid(),
+                std::process::id()
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            reap_leaked_enclaves(&registry, &cli.path()).unwrap(),
+            ["i-0-enc-dead"]
+        );
+        assert_eq!(
+            fs::read_to_string(cli.dir.path().join("terminated")).unwrap(),
+            "i-0-enc-dead\n"
+        );
+        assert_eq!(
+            fs::read_to_string(&registry).unwrap(),
+            format!("i-0-enc-init 1\ni-0-enc-self {}\n", std::process::id())
+        );
+    }
+
+    #[test]
+    fn reaping_stops_once_the_cli_fails() {
+        // the fake tool removes itself after the first run
+        let cli = FakeCli::new(r#"[ "$1" = terminate-enclave ] && rm "$0""#);
+        let registry = cli.dir.path().join("registry");
+        let pid = dead_pid();
+        fs::write(
+            &registry,
+            format!("i-0-enc-1 {pid}\ni-0-enc-2 {pid}\ni-0-enc-3 {pid}\n"),
+        )
+        .unwrap();
+        assert!(reap_leaked_enclaves(&registry, &cli.path()).is_err());
+        assert_eq!(
+            fs::read_to_string(&registry).unwrap(),
+            format!("i-0-enc-2 {pid}\ni-0-enc-3 {pid}\n")
+        );
+    }
+
+    #[test]
+    fn the_launch_report_gives_the_allocation_read_back() {
+        let report = |describe: &str| {
+            let cli = FakeCli::new(&format!(
+                r#"case "$1" in
+                run-enclave) echo '{{"EnclaveID": "i-0-enc-1", "EnclaveCID": 16, "MemoryMiB": 600}}' ;;
+                describe-enclaves) echo '[{}]' ;;
+                esac"#,
+                describe
+            ));
+            let (launched, report) = cli.builder().launch_with_report().unwrap();
+            drop(launched);
+            (report.memory_mib, report.cpu_count)
+        };
+        let described = r#"{"EnclaveID": "i-0-enc-1", "MemoryMiB": 640, "NumberOfCPUs": 4}"#;
+        assert_eq!(report(described), (Some(640), Some(4)));
+        // falling back to what `run-enclave` said, then to what was asked for
+        assert_eq!(report(""), (Some(600), Some(DEFAULT_CPU_COUNT)));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn sealed_buffers_need_a_running_enclave() {
+        let key = [7u8; raw_fd::SEALING_KEY_SIZE];
+        let (mut enclave, peer) = connected_pair();
+        enclave.send_buffer_sealed(&key, b"sealed").unwrap();
+        assert_eq!(
+            raw_fd::receive_buffer_sealed(peer.as_raw_fd(), &key).unwrap(),
+            b"sealed"
+        );
+        raw_fd::send_buffer_sealed(peer.as_raw_fd(), &key, b"reply").unwrap();
+        assert_eq!(enclave.receive_buffer_sealed(&key).unwrap(), b"reply");
+        // as after a failed `restart`, but with no Nitro CLI tool to
+        // terminate the enclave with
+        let mut process = enclave.process.take().unwrap();
+        process.terminate_on_drop = false;
+        drop(process);
+        for result in [
+            enclave.send_buffer_sealed(&key, b"sealed"),
+            enclave.receive_buffer_sealed(&key).map(|_| ()),
+        ] {
+            match result.unwrap_err().downcast() {
+                Ok(NitroError::NotRunning) => (),
+                err => panic!("unexpected error: {:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn control_exchanges_say_which_enclave_failed() {
+        let (enclave, peer) = connected_pair();
+        drop(peer);
+        for err in [
+            enclave.ping().map(|_| ()).unwrap_err(),
+            enclave.request_diagnostics().map(|_| ()).unwrap_err(),
+            enclave.verify_connection(b"challenge").unwrap_err(),
+        ] {
+            assert_eq!(err.to_string(), "Enclave i-0-enc-1 (CID 16)");
+            assert!(
+                err.downcast_ref::<RawFdError>().is_some() || err.downcast_ref::<Errno>().is_some()
+            );
+        }
+        enclave.into_socket();
+    }
+
+    #[test]
+    fn an_acked_send_waits_for_the_answer() {
+        let (enclave, peer) = connected_pair();
+        let answer = thread_answering(peer, ACK_FRAME_TYPE);
+        enclave.send_buffer_acked(b"acked").unwrap();
+        assert_eq!(answer.join().unwrap(), b"acked");
+        enclave.into_socket();
+    }
+
+    #[test]
+    fn an_acked_send_to_a_peer_that_hangs_up_fails_at_once() {
+        let (enclave, peer) = connected_pair();
+        let start = Instant::now();
+        let hang_up = std::thread::spawn(move || {
+            raw_fd::receive_typed_buffer(peer.as_raw_fd()).unwrap();
+        });
+        let err = enclave.send_buffer_acked(b"acked").unwrap_err();
+        hang_up.join().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<RawFdError>(),
+            Some(RawFdError::ConnectionClosed)
+        ));
+        assert!(start.elapsed() < ACK_TIMEOUT);
+        enclave.into_socket();
+    }
+
+    /// Start a thread receiving a typed buffer on `peer` and answering it
+    /// with an empty typed buffer of type `frame_type`, returning the
+    /// contents of the buffer received
+    fn thread_answering(
+        peer: vsocket::VsockSocket,
+        frame_type: u8,
+    ) -> std::thread::JoinHandle<Vec<u8>> {
+        std::thread::spawn(move || {
+            let (_, buffer) = raw_fd::receive_typed_buffer(peer.as_raw_fd()).unwrap();
+            raw_fd::send_typed_buffer(peer.as_raw_fd(), frame_type, &[]).unwrap();
+            buffer
+        })
+    }
+
+    /// A fake tool that logs its calls, has `run-enclave --enclave-cid`,
+    /// describes the enclaves in `described` and launches enclaves with
+    /// `launched_cid`
+    fn cid_cli(described: &str, launched_cid: u32) -> FakeCli {
+        FakeCli::new(&format!(
+            r#"{}
+            case "$1 $2" in
+            "run-enclave --help") echo "--enclave-cid <CID>" ;;
+            run-enclave*) echo '{{"EnclaveID": "i-0-enc-0", "EnclaveCID": {}}}' ;;
+            describe-enclaves*) echo '{}' ;;
+            esac"#,
+            LOG_CALLS, launched_cid, described
+        ))
+    }
+
+    /// Whether `cli` was asked to launch an enclave
+    fn launched(cli: &FakeCli) -> bool {
+        cli.calls()
+            .iter()
+            .any(|call| call.starts_with("run-enclave") && call != "run-enclave --help")
+    }
+
+    #[test]
+    fn a_cid_in_use_is_not_launched_with() {
+        let cli = cid_cli(r#"[{"EnclaveID": "i-0-enc-9", "EnclaveCID": 16}]"#, 16);
+        match launch_error(cli.builder().enclave_cid(Some(16))) {
+            NitroError::CidInUse(16) => (),
+            err => panic!("unexpected error: {:?}", err),
+        }
+        assert!(!launched(&cli));
+    }
+
+    #[test]
+    fn a_cid_needs_a_cli_with_the_option() {
+        let cli = FakeCli::new(&format!(
+            r#"{}
+            [ "$1" = describe-enclaves ] && echo '[]'
+            true"#,
+            LOG_CALLS
+        ));
+        match launch_error(cli.builder().enclave_cid(Some(16))) {
+            NitroError::CliOptionUnsupported(option) => {
+                assert_eq!(option, "run-enclave --enclave-cid")
+            }
+            err => panic!("unexpected error: {:?}", err),
+        }
+        assert!(!launched(&cli));
+    }
+
+    #[test]
+    fn an_enclave_given_another_cid_is_terminated() {
+        let cli = cid_cli("[]", 17);
+        match launch_error(cli.builder().enclave_cid(Some(16))) {
+            NitroError::CidMismatch {
+                requested: 16,
+                actual: 17,
+            } => (),
+            err => panic!("unexpected error: {:?}", err),
+        }
+        assert!(launched(&cli));
+        assert!(cli
+            .calls()
+            .contains(&"terminate-enclave --enclave-id i-0-enc-0".to_string()));
+    }
+
+    #[test]
+    fn an_enclave_that_cant_be_connected_to_is_replaced() {
+        // no enclave has the CID, so the connection can only fail
+        let cli = FakeCli::new(&format!(
+            r#"{}
+            case "$1" in
+            run-enclave) echo '{{"EnclaveID": "i-0-enc-0", "EnclaveCID": 16}}' ;;
+            describe-enclaves) echo '[]' ;;
+            esac"#,
+            LOG_CALLS
+        ));
+        let built = cli
+            .builder()
+            .on_connect_failure(ConnectFailurePolicy::TeardownAndRetry(1))
+            .connect_timeout(Duration::from_millis(100))
+            .build();
+        assert!(built.is_err());
+        let calls = cli.calls();
+        let runs = |command: &str| calls.iter().filter(|call| **call == command).count();
+        let launches = calls
+            .iter()
+            .filter(|call| call.starts_with("run-enclave"))
+            .count();
+        assert_eq!(launches, 2);
+        assert_eq!(runs("terminate-enclave --enclave-id i-0-enc-0"), 2);
+        // the first enclave is terminated before the second is launched
+        let first_termination = calls
+            .iter()
+            .position(|call| call.starts_with("terminate-enclave"))
+            .unwrap();
+        let second_launch = calls
+            .iter()
+            .rposition(|call| call.starts_with("run-enclave"))
+            .unwrap();
+        assert!(first_termination < second_launch);
+    }
+}
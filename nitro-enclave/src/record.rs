@@ -0,0 +1,340 @@
+//! Recording and replaying the buffers exchanged with an enclave, for
+//! reproducing protocol bugs without the live enclave.
+//!
+//! A recording is a sequence of entries, one for each buffer sent or
+//! received. Each entry is a direction byte (0 for a buffer sent to the
+//! enclave, 1 for one received from it), the time at which the buffer was
+//! sent or received as a little-endian `u64` count of microseconds since the
+//! Unix epoch, and then the bytes of the buffer exactly as they travelled
+//! over the VSOCK (see `raw_fd::encode_frame`), preceded by their number as
+//! a little-endian `u64`. A received buffer that failed to arrive in full or
+//! to decode is recorded as the bytes that were read of it, so that
+//! replaying it fails in the same way. Concatenating the bytes of the
+//! entries in one direction reconstructs the byte stream in that direction.
+//!
+//! ## Authors
+//!
+//! The Veracruz Development Team.
+//!
+//! ## Licensing and copyright notice
+//!
+//! See the `LICENSE_MIT.markdown` file in the root directory for
+//! information on licensing and copyright.
+
+use crate::{NitroError, Transport};
+use anyhow::{anyhow, Result};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    path::Path,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Which way a recorded buffer went
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// The buffer was sent to the enclave
+    Sent,
+    /// The buffer was received from the enclave
+    Received,
+}
+
+/// A buffer read back from a recording
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordedMessage {
+    /// Which way the buffer went
+    pub direction: Direction,
+    /// When the buffer was sent or received
+    pub timestamp: SystemTime,
+    /// The bytes of the buffer as they travelled over the connection,
+    /// framing and all
+    pub frame: Vec<u8>,
+}
+
+impl RecordedMessage {
+    /// Decode the buffer from its recorded bytes with the same framing
+    /// decoder used on the live connection (`raw_fd::decode_frame`), so that
+    /// a buffer that failed to arrive in full or to parse fails in the same
+    /// way here
+    pub fn buffer(&self) -> Result<Vec<u8>> {
+        raw_fd::decode_frame(&mut &self.frame[..])?
+            .ok_or_else(|| anyhow!(raw_fd::RawFdError::ConnectionClosed))
+    }
+}
+
+/// A `Transport` that passes every buffer through to another, and records
+/// each buffer successfully sent, and the bytes read of each buffer received
+/// (using `Transport::recv_tapped`), to a log. A received buffer is recorded
+/// even if it fails to arrive in full or to decode, as long as any of it was
+/// read. Failing to write to the log is reported, but is not fatal.
+pub struct RecordingTransport<T> {
+    /// The transport the buffers are passed through to
+    inner: T,
+    /// Where the recording is written
+    log: Mutex<Box<dyn Write + Send>>,
+}
+
+impl<T: Transport> RecordingTransport<T> {
+    /// Record the buffers sent and received over `inner` to `log`
+    pub fn new<W: Write + Send + 'static>(inner: T, log: W) -> Self {
+        RecordingTransport {
+            inner,
+            log: Mutex::new(Box::new(log)),
+        }
+    }
+
+    /// Record the buffers sent and received over `inner` to a new file at
+    /// `path`, replacing any file already there
+    pub fn create<P: AsRef<Path>>(inner: T, path: P) -> Result<Self> {
+        Ok(Self::new(inner, BufWriter::new(File::create(path)?)))
+    }
+
+    /// Stop recording, and return the transport the buffers were passed
+    /// through to
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Append an entry for the bytes `frame` of a buffer to the log,
+    /// flushing it so that the recording survives a crash
+    fn record(&self, direction: Direction, frame: &[u8]) {
+        let micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_micros() as u64)
+            .unwrap_or_default();
+        let direction = match direction {
+            Direction::Sent => 0u8,
+            Direction::Received => 1u8,
+        };
+        let mut log = self
+            .log
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let result = log
+            .write_all(&[direction])
+            .and_then(|_| log.write_all(&micros.to_le_bytes()))
+            .and_then(|_| log.write_all(&(frame.len() as u64).to_le_bytes()))
+            .and_then(|_| log.write_all(frame))
+            .and_then(|_| log.flush());
+        if let Err(err) = result {
+            log::warn!("Failed to record buffer: {:?}", err);
+        }
+    }
+}
+
+impl<T: Transport> Transport for RecordingTransport<T> {
+    fn send(&self, buffer: &[u8]) -> Result<()> {
+        self.inner.send(buffer)?;
+        self.record(Direction::Sent, &raw_fd::encode_frame(buffer));
+        Ok(())
+    }
+
+    fn recv(&self) -> Result<Vec<u8>> {
+        self.recv_tapped(&mut |_| ())
+    }
+
+    fn recv_tapped(&self, tap: &mut dyn FnMut(&[u8])) -> Result<Vec<u8>> {
+        let mut frame = Vec::new();
+        let result = self.inner.recv_tapped(&mut |bytes| {
+            frame.extend_from_slice(bytes);
+            tap(bytes);
+        });
+        match &result {
+            // a transport with no connection underneath passes no bytes
+            Ok(buffer) if frame.is_empty() => {
+                self.record(Direction::Received, &raw_fd::encode_frame(buffer))
+            }
+            Err(_) if frame.is_empty() => (),
+            _ => self.record(Direction::Received, &frame),
+        }
+        result
+    }
+}
+
+/// Read the next entry of a recording made by `RecordingTransport` from
+/// `reader`. Returns `None` at the end of the recording, and fails with
+/// `NitroError::MalformedRecording` if an entry is not in the recording
+/// format. The buffer itself isn't decoded: see `RecordedMessage::buffer`.
+pub fn read_recorded_message<R: BufRead>(reader: &mut R) -> Result<Option<RecordedMessage>> {
+    let mut direction = [0u8; 1];
+    if reader.read(&mut direction)? == 0 {
+        return Ok(None);
+    }
+    let direction = match direction[0] {
+        0 => Direction::Sent,
+        1 => Direction::Received,
+        _ => return Err(anyhow!(NitroError::MalformedRecording)),
+    };
+    let mut micros = [0u8; 8];
+    let mut length = [0u8; 8];
+    reader
+        .read_exact(&mut micros)
+        .and_then(|_| reader.read_exact(&mut length))
+        .map_err(|_| anyhow!(NitroError::MalformedRecording))?;
+    let length = u64::from_le_bytes(length);
+    // read through `take` so that a corrupt length can't make us allocate
+    // more than the recording holds
+    let mut frame = Vec::new();
+    reader.by_ref().take(length).read_to_end(&mut frame)?;
+    if frame.len() as u64 != length {
+        return Err(anyhow!(NitroError::MalformedRecording));
+    }
+    Ok(Some(RecordedMessage {
+        direction,
+        timestamp: UNIX_EPOCH + Duration::from_micros(u64::from_le_bytes(micros)),
+        frame,
+    }))
+}
+
+/// Read every entry of the recording in the file at `path`
+pub fn read_recording<P: AsRef<Path>>(path: P) -> Result<Vec<RecordedMessage>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut messages = Vec::new();
+    while let Some(message) = read_recorded_message(&mut reader)? {
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+/// A `Transport` that plays back the buffers received in a recording, in
+/// place of the enclave the recording was made with. Each `recv` decodes the
+/// next buffer that was received (as `RecordedMessage::buffer` does), so one
+/// that failed to arrive in full or to parse fails in the same way again,
+/// and fails with `raw_fd::RawFdError::ConnectionClosed` once they have all
+/// been played back. Buffers sent to it are discarded.
+pub struct ReplayTransport {
+    /// The received buffers still to be played back
+    received: Mutex<VecDeque<RecordedMessage>>,
+}
+
+impl ReplayTransport {
+    /// Play back the buffers received in `messages`
+    pub fn new(messages: Vec<RecordedMessage>) -> Self {
+        ReplayTransport {
+            received: Mutex::new(
+                messages
+                    .into_iter()
+                    .filter(|message| message.direction == Direction::Received)
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Play back the buffers received in the recording in the file at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::new(read_recording(path)?))
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn send(&self, _buffer: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    fn recv(&self) -> Result<Vec<u8>> {
+        self.received
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .pop_front()
+            .ok_or_else(|| anyhow!(raw_fd::RawFdError::ConnectionClosed))?
+            .buffer()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::linux::tests::connected_pair;
+    use raw_fd::RawFdError;
+    use std::os::unix::io::AsRawFd;
+
+    /// The `RawFdError` that `result` failed with
+    fn error_of<T: std::fmt::Debug>(result: Result<T>) -> RawFdError {
+        result.unwrap_err().downcast().unwrap()
+    }
+
+    #[test]
+    fn the_raw_bytes_received_are_recorded_and_replayed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session");
+        let (enclave, peer) = connected_pair();
+        let transport = RecordingTransport::create(enclave, &path).unwrap();
+        transport.send(b"request").unwrap();
+        assert_eq!(
+            raw_fd::receive_buffer(peer.as_raw_fd()).unwrap(),
+            b"request"
+        );
+        raw_fd::send_buffer(peer.as_raw_fd(), b"response").unwrap();
+        raw_fd::send_typed_buffer(peer.as_raw_fd(), 1, b"typed").unwrap();
+        assert_eq!(transport.recv().unwrap(), b"response");
+        assert!(matches!(
+            error_of(transport.recv()),
+            RawFdError::UnexpectedTypedFrame
+        ));
+        // no Nitro CLI tool to terminate the enclave with
+        transport.into_inner().into_socket();
+
+        let messages = read_recording(&path).unwrap();
+        let directions: Vec<Direction> = messages.iter().map(|message| message.direction).collect();
+        assert_eq!(
+            directions,
+            [Direction::Sent, Direction::Received, Direction::Received]
+        );
+        assert_eq!(messages[0].frame, raw_fd::encode_frame(b"request"));
+        assert_eq!(messages[1].frame, raw_fd::encode_frame(b"response"));
+        // only the length prefix of the typed buffer was read
+        assert_eq!(messages[2].frame.len(), raw_fd::LENGTH_PREFIX_SIZE);
+
+        let replay = ReplayTransport::new(messages);
+        assert_eq!(replay.recv().unwrap(), b"response");
+        assert!(matches!(
+            error_of(replay.recv()),
+            RawFdError::UnexpectedTypedFrame
+        ));
+        assert!(matches!(
+            error_of(replay.recv()),
+            RawFdError::ConnectionClosed
+        ));
+    }
+
+    #[test]
+    fn a_truncated_buffer_is_recorded_as_far_as_it_arrived() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session");
+        let (enclave, peer) = connected_pair();
+        let transport = RecordingTransport::create(enclave, &path).unwrap();
+        let frame = raw_fd::encode_frame(b"truncated");
+        nix::unistd::write(peer.as_raw_fd(), &frame[..frame.len() - 3]).unwrap();
+        drop(peer);
+        assert!(matches!(
+            error_of(transport.recv()),
+            RawFdError::UnexpectedEof
+        ));
+        transport.into_inner().into_socket();
+
+        let messages = read_recording(&path).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].frame, frame[..frame.len() - 3]);
+        assert!(matches!(
+            error_of(messages[0].buffer()),
+            RawFdError::UnexpectedEof
+        ));
+    }
+
+    #[test]
+    fn a_truncated_entry_is_malformed() {
+        let mut entry = vec![1];
+        entry.extend_from_slice(&0u64.to_le_bytes());
+        entry.extend_from_slice(&100u64.to_le_bytes());
+        entry.extend_from_slice(b"short");
+        assert!(matches!(
+            read_recorded_message(&mut &entry[..])
+                .unwrap_err()
+                .downcast()
+                .unwrap(),
+            NitroError::MalformedRecording
+        ));
+    }
+}
@@ -0,0 +1,534 @@
+//! Stand-ins for the Nitro enclave management types on platforms other than
+//! Linux, from which Nitro enclaves can't be run. The types and methods
+//! exist so that crates depending on this one build everywhere, but
+//! launching or attaching to an enclave fails with
+//! `NitroError::Unsupported`, so no `LaunchedEnclave` or `NitroEnclave` can
+//! ever be constructed.
+//!
+//! ## Authors
+//!
+//! The Veracruz Development Team.
+//!
+//! ## Licensing and copyright notice
+//!
+//! See the `LICENSE_MIT.markdown` file in the root directory for
+//! information on licensing and copyright.
+
+use crate::{
+    ChildStatusPolicy, ConnectFailurePolicy, DiagnosticsWriter, EnclaveExit, LaunchReport,
+    NitroError, PreflightReport, RetryBackoff, RetryPolicy, StderrClassifier, Transport,
+};
+use anyhow::{anyhow, Result};
+use std::{
+    convert::Infallible,
+    path::Path,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+
+/// Delay before giving up on connecting to the enclave
+pub const NITRO_ENCLAVE_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long each attempt to terminate an enclave may take
+pub const DEFAULT_TERMINATE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An enclave that has been launched but not yet connected to. None can be
+/// launched on this platform.
+pub struct LaunchedEnclave {
+    /// Makes the type uninhabited
+    never: Infallible,
+}
+
+/// a struct for holding all of the information about a nitro enclave. None
+/// can be launched on this platform.
+pub struct NitroEnclave {
+    /// Makes the type uninhabited
+    never: Infallible,
+}
+
+/// A launched enclave that has been connected to, and so can be sent data
+pub type ConnectedEnclave = NitroEnclave;
+
+/// A builder for launching a Nitro enclave with non-default settings. The
+/// settings are accepted and ignored, as launching fails with
+/// `NitroError::Unsupported` on this platform.
+pub struct NitroEnclaveBuilder {}
+
+impl NitroEnclaveBuilder {
+    /// Create a builder for an enclave started with the file in `eif_path`,
+    /// allocated `max_memory_mib` of memory and communicating on `port`
+    pub fn new(_eif_path: &str, _max_memory_mib: u32, _port: u32) -> Self {
+        NitroEnclaveBuilder {}
+    }
+
+    /// Set whether the enclave will be started in debug mode
+    pub fn debug(self, _debug: bool) -> Self {
+        self
+    }
+
+    /// Set the number of CPUs to be allocated to the enclave
+    pub fn cpu_count(self, _cpu_count: Option<u32>) -> Self {
+        self
+    }
+
+    /// Allocate exactly the CPUs with the given IDs to the enclave
+    pub fn cpu_ids(self, _cpu_ids: &[u32]) -> Self {
+        self
+    }
+
+    /// Launch the enclave with the given CID
+    pub fn enclave_cid(self, _cid: Option<u32>) -> Self {
+        self
+    }
+
+    /// Set the path to the Nitro CLI tool
+    pub fn nitro_cli_path(self, _nitro_cli_path: &str) -> Self {
+        self
+    }
+
+    /// Write the raw output of the Nitro CLI tool to `writer`
+    pub fn diagnostics_writer(self, _writer: DiagnosticsWriter) -> Self {
+        self
+    }
+
+    /// Append the raw output of the Nitro CLI tool to the file at `path`
+    pub fn diagnostics_file<P: AsRef<Path>>(self, _path: P) -> Result<Self> {
+        Ok(self)
+    }
+
+    /// Record launches and terminations in the audit log at `path`
+    pub fn audit_log<P: AsRef<Path>>(self, _path: P) -> Self {
+        self
+    }
+
+    /// Set what building does if the enclave can't be connected to
+    pub fn on_connect_failure(self, _policy: ConnectFailurePolicy) -> Self {
+        self
+    }
+
+    /// Set how long building waits for the connection to the enclave
+    pub fn connect_timeout(self, _timeout: Duration) -> Self {
+        self
+    }
+
+    /// Decide from the stderr of a failed launch whether to retry it
+    pub fn stderr_classifier(self, _classifier: StderrClassifier) -> Self {
+        self
+    }
+
+    /// Record the enclave's ID in the registry file at `path`
+    pub fn leak_registry<P: AsRef<Path>>(self, _path: P) -> Self {
+        self
+    }
+
+    /// Set how long to wait between failed attempts to launch the enclave
+    pub fn retry_backoff(self, _retry_backoff: RetryBackoff) -> Self {
+        self
+    }
+
+    /// Set how many times a failed Nitro CLI command is retried
+    pub fn retry_policy(self, _retry_policy: RetryPolicy) -> Self {
+        self
+    }
+
+    /// Check, before launching the enclave, that enough reserved memory is
+    /// free for it
+    pub fn check_reserved_memory(self, _check: bool) -> Self {
+        self
+    }
+
+    /// Set whether a request for less memory than Nitro enclaves can have is
+    /// raised to the minimum, rather than failing
+    pub fn auto_adjust_memory(self, _adjust: bool) -> Self {
+        self
+    }
+
+    /// Set whether dropping the enclave waits for it to be terminated
+    pub fn async_terminate(self, _async_terminate: bool) -> Self {
+        self
+    }
+
+    /// Set how long each attempt to terminate the enclave may take
+    pub fn terminate_timeout(self, _timeout: Duration) -> Self {
+        self
+    }
+
+    /// Abandon launching the enclave once `token` is set
+    pub fn cancellation_token(self, _token: Arc<AtomicBool>) -> Self {
+        self
+    }
+
+    /// Send `config` to the enclave as the first buffer on the connection
+    pub fn initial_config(self, _config: Vec<u8>) -> Self {
+        self
+    }
+
+    /// Send `frame` to the enclave before disconnecting from it
+    pub fn shutdown_frame(self, _frame: Vec<u8>) -> Self {
+        self
+    }
+
+    /// Request a kernel send buffer of `size` bytes for the VSOCK
+    pub fn send_buffer_size(self, _size: usize) -> Self {
+        self
+    }
+
+    /// Request a kernel receive buffer of `size` bytes for the VSOCK
+    pub fn receive_buffer_size(self, _size: usize) -> Self {
+        self
+    }
+
+    /// Wait for `delay` after connecting to the enclave before returning it
+    pub fn warmup_delay(self, _delay: Duration) -> Self {
+        self
+    }
+
+    /// Fails with `NitroError::Unsupported`, as there is no Nitro CLI tool on
+    /// this platform
+    pub fn command_line(&self) -> Result<Vec<String>> {
+        Err(anyhow!(NitroError::Unsupported))
+    }
+
+    /// Fails with `NitroError::Unsupported`
+    pub fn build(self) -> Result<ConnectedEnclave> {
+        Err(anyhow!(NitroError::Unsupported))
+    }
+
+    /// Fails with `NitroError::Unsupported`
+    pub fn build_with_report(self) -> Result<(ConnectedEnclave, LaunchReport)> {
+        Err(anyhow!(NitroError::Unsupported))
+    }
+
+    /// Fails with `NitroError::Unsupported`
+    pub fn launch(self) -> Result<LaunchedEnclave> {
+        Err(anyhow!(NitroError::Unsupported))
+    }
+
+    /// Fails with `NitroError::Unsupported`
+    pub fn launch_with_report(self) -> Result<(LaunchedEnclave, LaunchReport)> {
+        Err(anyhow!(NitroError::Unsupported))
+    }
+}
+
+impl LaunchedEnclave {
+    /// The enclave ID, as generated from the Nitro CLI tool
+    pub fn enclave_id(&self) -> &str {
+        match self.never {}
+    }
+
+    /// Connect to the enclave
+    pub fn connect(self, _timeout: Duration) -> Result<ConnectedEnclave> {
+        match self.never {}
+    }
+
+    /// Connect to the enclave without blocking the thread
+    #[cfg(feature = "tokio")]
+    pub async fn connect_async(self, _timeout: Duration) -> Result<ConnectedEnclave> {
+        match self.never {}
+    }
+}
+
+impl NitroEnclave {
+    /// Fails with `NitroError::Unsupported`
+    pub fn new(_eif_path: &str, _debug: bool, _max_memory_mib: u32, _port: u32) -> Result<Self> {
+        Err(anyhow!(NitroError::Unsupported))
+    }
+
+    /// Fails with `NitroError::Unsupported`
+    pub fn new_from_bytes(
+        _eif: &[u8],
+        _debug: bool,
+        _max_memory_mib: u32,
+        _port: u32,
+    ) -> Result<Self> {
+        Err(anyhow!(NitroError::Unsupported))
+    }
+
+    /// Fails with `NitroError::Unsupported`
+    pub fn attach(_enclave_id: &str, _port: u32, _nitro_cli_path: &str) -> Result<Self> {
+        Err(anyhow!(NitroError::Unsupported))
+    }
+
+    /// Fails with `NitroError::Unsupported`
+    pub fn launch(
+        _eif_path: &str,
+        _debug: bool,
+        _max_memory_mib: u32,
+        _port: u32,
+    ) -> Result<LaunchedEnclave> {
+        Err(anyhow!(NitroError::Unsupported))
+    }
+
+    /// Create a builder for a Nitro enclave
+    pub fn builder(eif_path: &str, max_memory_mib: u32, port: u32) -> NitroEnclaveBuilder {
+        NitroEnclaveBuilder::new(eif_path, max_memory_mib, port)
+    }
+
+    /// The enclave ID, as generated from the Nitro CLI tool
+    pub fn enclave_id(&self) -> &str {
+        match self.never {}
+    }
+
+    /// Whether an enclave is running
+    pub fn is_running(&self) -> bool {
+        match self.never {}
+    }
+
+    /// The CID of the enclave
+    pub fn cid(&self) -> u32 {
+        match self.never {}
+    }
+
+    /// The memory allocated to the enclave, in MiB, as read back from the
+    /// Nitro CLI tool once the enclave was running
+    pub fn allocated_memory_mib(&self) -> Option<u32> {
+        match self.never {}
+    }
+
+    /// The number of CPUs allocated to the enclave, as read back from the
+    /// Nitro CLI tool once the enclave was running
+    pub fn allocated_cpu_count(&self) -> Option<u32> {
+        match self.never {}
+    }
+
+    /// Whether the enclave is running in debug mode, in which case its
+    /// attestation documents must not be trusted. This is true where the
+    /// mode is unknown.
+    pub fn is_debug(&self) -> bool {
+        match self.never {}
+    }
+
+    /// The vsock port the enclave is listening on
+    pub fn port(&self) -> u32 {
+        match self.never {}
+    }
+
+    /// The size of the kernel's send buffer for the VSOCK
+    pub fn send_buffer_size(&self) -> Result<usize> {
+        match self.never {}
+    }
+
+    /// The size of the kernel's receive buffer for the VSOCK
+    pub fn receive_buffer_size(&self) -> Result<usize> {
+        match self.never {}
+    }
+
+    /// Set how long a receive from the enclave may block
+    pub fn set_read_timeout(&self, _timeout: Option<Duration>) -> Result<()> {
+        match self.never {}
+    }
+
+    /// How long a receive from the enclave may block
+    pub fn read_timeout(&self) -> Result<Option<Duration>> {
+        match self.never {}
+    }
+
+    /// Set how long a send to the enclave may block
+    pub fn set_write_timeout(&self, _timeout: Option<Duration>) -> Result<()> {
+        match self.never {}
+    }
+
+    /// How long a send to the enclave may block
+    pub fn write_timeout(&self) -> Result<Option<Duration>> {
+        match self.never {}
+    }
+
+    /// Terminate the enclave and launch a new one in its place
+    pub fn restart(&mut self, _new_eif_path: Option<&str>) -> Result<()> {
+        match self.never {}
+    }
+
+    /// Pause the enclave, stopping its CPUs while keeping its state
+    pub fn pause(&self) -> Result<()> {
+        match self.never {}
+    }
+
+    /// Resume an enclave paused with `pause`
+    pub fn resume(&self) -> Result<()> {
+        match self.never {}
+    }
+
+    /// Wait up to `timeout` for the enclave to exit
+    pub fn wait_exit(&self, _timeout: Duration) -> Result<EnclaveExit> {
+        match self.never {}
+    }
+
+    /// Whether the VSOCK can accept more data right now without blocking
+    pub fn is_writable(&self) -> Result<bool> {
+        match self.never {}
+    }
+
+    /// send a buffer of data to the enclave
+    pub fn send_buffer(&self, _buffer: &[u8]) -> Result<()> {
+        match self.never {}
+    }
+
+    /// receive a buffer of data from the enclave
+    pub fn receive_buffer(&self) -> Result<Vec<u8>> {
+        match self.never {}
+    }
+
+    /// receive buffers of data from the enclave, passing each to `f`, until
+    /// `f` returns true
+    pub fn receive_until<F: FnMut(&[u8]) -> bool>(&self, _f: F) -> Result<usize> {
+        match self.never {}
+    }
+
+    /// Check that the connection is to the enclave this `NitroEnclave` was
+    /// launched as, with a challenge the enclave must echo along with its
+    /// PCR0
+    pub fn verify_connection(&self, _challenge: &[u8]) -> Result<()> {
+        match self.never {}
+    }
+
+    /// Send a buffer of data to the enclave, and wait for it to be
+    /// acknowledged
+    pub fn send_buffer_acked(&self, _buffer: &[u8]) -> Result<()> {
+        match self.never {}
+    }
+
+    /// Collect the chunks of output an enclave streams, until the end of the
+    /// stream
+    pub fn collect_stream(
+        &self,
+        _per_chunk_timeout: Duration,
+        _max_total: Duration,
+    ) -> Result<Vec<Vec<u8>>> {
+        match self.never {}
+    }
+
+    /// Send a request to the enclave and collect all of its responses
+    pub fn transact_multi(&self, _request: &[u8]) -> Result<Vec<Vec<u8>>> {
+        match self.never {}
+    }
+
+    /// Split the connection into a sending half and a receiving half
+    pub fn split(self) -> (EnclaveSender, EnclaveReceiver) {
+        match self.never {}
+    }
+
+    /// Check that the enclave is responsive, returning the round-trip time
+    pub fn ping(&self) -> Result<Duration> {
+        match self.never {}
+    }
+
+    /// Ask the enclave for diagnostics
+    pub fn request_diagnostics(&self) -> Result<Vec<u8>> {
+        match self.never {}
+    }
+
+    /// send a buffer of data to the enclave, encrypted and authenticated with
+    /// the pre-shared `key`
+    #[cfg(feature = "encryption")]
+    pub fn send_buffer_sealed(&self, _key: &[u8; 32], _buffer: &[u8]) -> Result<()> {
+        match self.never {}
+    }
+
+    /// receive a buffer of data from the enclave sealed with the pre-shared
+    /// `key`
+    #[cfg(feature = "encryption")]
+    pub fn receive_buffer_sealed(&self, _key: &[u8; 32]) -> Result<Vec<u8>> {
+        match self.never {}
+    }
+}
+
+impl Transport for NitroEnclave {
+    fn send(&self, buffer: &[u8]) -> Result<()> {
+        self.send_buffer(buffer)
+    }
+
+    fn recv(&self) -> Result<Vec<u8>> {
+        self.receive_buffer()
+    }
+}
+
+/// The sending half of a `NitroEnclave`. None can exist on this platform.
+#[derive(Clone)]
+pub struct EnclaveSender {
+    /// Makes the type uninhabited
+    never: Infallible,
+}
+
+impl EnclaveSender {
+    /// send a buffer of data to the enclave
+    pub fn send_buffer(&self, _buffer: &[u8]) -> Result<()> {
+        match self.never {}
+    }
+}
+
+/// The receiving half of a `NitroEnclave`. None can exist on this platform.
+#[derive(Clone)]
+pub struct EnclaveReceiver {
+    /// Makes the type uninhabited
+    never: Infallible,
+}
+
+impl EnclaveReceiver {
+    /// receive a buffer of data from the enclave
+    pub fn receive_buffer(&self) -> Result<Vec<u8>> {
+        match self.never {}
+    }
+}
+
+/// Report that this host can't run Nitro enclaves, as it isn't running Linux
+pub fn preflight_check(_nitro_cli_path: &str) -> Result<PreflightReport> {
+    Ok(PreflightReport {
+        device_present: false,
+        has_permission: false,
+        reserved_memory_mib: 0,
+        cli_working: false,
+        vsock_supported: false,
+        problems: vec![NitroError::Unsupported.to_string()],
+    })
+}
+
+/// Check that this process may manage enclaves: always fails with
+/// `NitroError::Unsupported`
+pub fn check_enclave_permissions() -> Result<()> {
+    Err(anyhow!(NitroError::Unsupported))
+}
+
+/// Check that the kernel supports VSOCKs: always fails with
+/// `NitroError::Unsupported`
+pub fn check_vsock_support() -> Result<()> {
+    Err(anyhow!(NitroError::Unsupported))
+}
+
+/// Check that the Nitro CLI tool speaks the JSON schema this crate expects:
+/// always fails with `NitroError::Unsupported`
+pub fn verify_cli_compatibility(_nitro_cli_path: &str) -> Result<()> {
+    Err(anyhow!(NitroError::Unsupported))
+}
+
+/// The number of enclaves this process currently holds: always none
+#[cfg(feature = "leak-check")]
+pub fn live_enclave_count() -> usize {
+    0
+}
+
+/// Limit the number of enclaves this process may hold at once: as none can
+/// be held, the limit has no effect
+#[cfg(feature = "leak-check")]
+pub fn set_live_enclave_limit(_limit: Option<usize>) {}
+
+/// Set what is done when a run of the Nitro CLI tool is reaped by something
+/// other than this crate: as the tool is never run, this has no effect
+pub fn set_child_status_policy(_policy: ChildStatusPolicy) {}
+
+/// The VSOCK ports of the enclaves held by this process: always none
+pub fn ports_in_use() -> Vec<u32> {
+    Vec::new()
+}
+
+/// The lowest port from `first` upwards that isn't in use by this process
+pub fn suggest_unused_port(first: u32) -> u32 {
+    first
+}
+
+/// Fails with `NitroError::Unsupported`
+pub fn reap_leaked_enclaves<P: AsRef<Path>>(
+    _registry_path: P,
+    _nitro_cli_path: &str,
+) -> Result<Vec<String>> {
+    Err(anyhow!(NitroError::Unsupported))
+}
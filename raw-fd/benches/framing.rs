@@ -0,0 +1,170 @@
+//! Benchmarks for the throughput and latency of the framing, and for the
+//! cost of the different ways of sending a buffer, over a local socketpair
+//! so that no Nitro hardware is needed.
+//!
+//! ## Authors
+//!
+//! The Veracruz Development Team.
+//!
+//! ## Licensing and copyright notice
+//!
+//! See the `LICENSE_MIT.markdown` file in the root directory for
+//! information on licensing and copyright.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use nix::sys::socket::{socketpair, AddressFamily, SockFlag, SockType};
+use raw_fd::{
+    receive_buffer, send_buffer, send_buffer_ioslices, send_buffer_ioslices_verbose,
+    send_buffer_verbose, FramedReader,
+};
+use std::{io::IoSlice, os::unix::io::RawFd, thread};
+
+/// Number of small buffers sent back to back by `bench_many_small`
+const SMALL_BUFFER_COUNT: usize = 1000;
+
+/// A connected pair of Unix stream sockets
+fn pair() -> (RawFd, RawFd) {
+    socketpair(
+        AddressFamily::Unix,
+        SockType::Stream,
+        None,
+        SockFlag::SOCK_CLOEXEC,
+    )
+    .expect("socketpair")
+}
+
+/// Round trip of a small buffer to a peer that echoes it back
+fn bench_round_trip(c: &mut Criterion) {
+    let (host, peer) = pair();
+    let echo = thread::spawn(move || {
+        while let Ok(buffer) = receive_buffer(peer) {
+            if send_buffer(peer, &buffer).is_err() {
+                break;
+            }
+        }
+        let _ = nix::unistd::close(peer);
+    });
+    let buffer = [0u8; 64];
+    c.bench_function("round_trip_64B", |b| {
+        b.iter(|| {
+            send_buffer(host, &buffer).unwrap();
+            receive_buffer(host).unwrap()
+        })
+    });
+    let _ = nix::unistd::close(host);
+    echo.join().unwrap();
+}
+
+/// One-way transfer of large buffers to a peer that discards them
+fn bench_throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("throughput");
+    group.sample_size(10);
+    for size in [1 << 20, 100 << 20] {
+        let (host, peer) = pair();
+        let sink = thread::spawn(move || {
+            while receive_buffer(peer).is_ok() {}
+            let _ = nix::unistd::close(peer);
+        });
+        let buffer = vec![0u8; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &buffer, |b, buffer| {
+            b.iter(|| send_buffer(host, buffer).unwrap())
+        });
+        let _ = nix::unistd::close(host);
+        sink.join().unwrap();
+    }
+    group.finish();
+}
+
+/// Many small buffers sent back to back, received one `recv` pair at a time
+/// with `receive_buffer` and in large chunks with a `FramedReader`
+fn bench_many_small(c: &mut Criterion) {
+    let mut group = c.benchmark_group("many_small");
+    group.throughput(Throughput::Elements(SMALL_BUFFER_COUNT as u64));
+    group.bench_function("receive_buffer", |b| {
+        b.iter_batched(
+            send_small_buffers,
+            |(host, sender)| {
+                for _ in 0..SMALL_BUFFER_COUNT {
+                    receive_buffer(host).unwrap();
+                }
+                sender.join().unwrap();
+                let _ = nix::unistd::close(host);
+            },
+            criterion::BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("framed_reader", |b| {
+        b.iter_batched(
+            send_small_buffers,
+            |(host, sender)| {
+                let mut reader = FramedReader::new(host);
+                for _ in 0..SMALL_BUFFER_COUNT {
+                    reader.next_frame().unwrap().unwrap();
+                }
+                sender.join().unwrap();
+                let _ = nix::unistd::close(host);
+            },
+            criterion::BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+/// One-way transfer of a buffer sent with a separate `send` for the length
+/// prefix and the body (`send_buffer_verbose`), with the prefix sent with
+/// `MSG_MORE` (`send_buffer`), and with one vectored `sendmsg`
+/// (`send_buffer_ioslices`). The number of system calls each takes is
+/// printed first, as criterion only measures time.
+fn bench_send_modes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("send_modes");
+    for size in [64, 64 << 10] {
+        let (host, peer) = pair();
+        let sink = thread::spawn(move || {
+            while receive_buffer(peer).is_ok() {}
+            let _ = nix::unistd::close(peer);
+        });
+        let buffer = vec![0u8; size];
+        let single = send_buffer_verbose(host, &buffer).unwrap();
+        let vectored = send_buffer_ioslices_verbose(host, &[IoSlice::new(&buffer)]).unwrap();
+        println!(
+            "send_modes/{}: {} syscalls for a single send, {} for a vectored send",
+            size, single.syscalls, vectored.syscalls
+        );
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("separate", size), &buffer, |b, buffer| {
+            b.iter(|| send_buffer_verbose(host, buffer).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("msg_more", size), &buffer, |b, buffer| {
+            b.iter(|| send_buffer(host, buffer).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("vectored", size), &buffer, |b, buffer| {
+            b.iter(|| send_buffer_ioslices(host, &[IoSlice::new(buffer)]).unwrap())
+        });
+        let _ = nix::unistd::close(host);
+        sink.join().unwrap();
+    }
+    group.finish();
+}
+
+/// Start a thread sending `SMALL_BUFFER_COUNT` small buffers over a new
+/// socketpair, returning the receiving end and the thread
+fn send_small_buffers() -> (RawFd, thread::JoinHandle<()>) {
+    let (host, peer) = pair();
+    let sender = thread::spawn(move || {
+        for _ in 0..SMALL_BUFFER_COUNT {
+            send_buffer(peer, &[0u8; 32]).unwrap();
+        }
+        let _ = nix::unistd::close(peer);
+    });
+    (host, sender)
+}
+
+criterion_group!(
+    benches,
+    bench_round_trip,
+    bench_throughput,
+    bench_many_small,
+    bench_send_modes
+);
+criterion_main!(benches);
@@ -12,83 +12,3229 @@
 //! See the `LICENSE_MIT.markdown` file in the root directory for
 //! information on licensing and copyright.
 
+// `err_derive` expands to impl blocks nested inside constants.
+#![allow(non_local_definitions)]
+
 use anyhow::{anyhow, Result};
 use byteorder::{ByteOrder, LittleEndian};
+use err_derive::Error;
 use nix::{
-    errno::Errno::EINTR,
-    sys::socket::{recv, send, MsgFlags},
+    cmsg_space,
+    errno::Errno::{self, EAGAIN, EINTR},
+    fcntl::{fcntl, FcntlArg, OFlag, SealFlag},
+    poll::{poll, PollFd, PollFlags},
+    sys::{
+        memfd::{memfd_create, MemFdCreateFlag},
+        mman::{mmap, munmap, MapFlags, ProtFlags},
+        socket::{
+            getsockname, recvmsg, sendmsg, AddressFamily, ControlMessage, ControlMessageOwned,
+            MsgFlags, SockaddrLike, SockaddrStorage,
+        },
+        stat::fstat,
+    },
+    unistd::ftruncate,
+};
+use std::{
+    io::{self, BufRead, IoSlice, IoSliceMut, Read},
+    mem::MaybeUninit,
+    num::NonZeroUsize,
+    ops::ControlFlow,
+    os::unix::io::RawFd,
+    time::{Duration, Instant},
+    vec::Vec,
 };
-use std::{os::unix::io::RawFd, vec::Vec};
+
+#[cfg(any(test, feature = "testing"))]
+mod testing;
+#[cfg(any(test, feature = "testing"))]
+pub use testing::{Fault, FaultyTransport};
+
+////////////////////////////////////////////////////////////////////////////////
+// Constants.
+////////////////////////////////////////////////////////////////////////////////
+
+/// Default maximum number of consecutive `EINTR` results tolerated by the
+/// send and receive loops before giving up.
+pub const DEFAULT_MAX_EINTR_RETRIES: usize = 1024;
+
+/// Default maximum size in bytes of a buffer that will be sent or received.
+pub const DEFAULT_MAX_PAYLOAD_LEN: usize = 1 << 30;
+
+/// Size in bytes of the chunks in which an oversized buffer is discarded.
+const DRAIN_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Size in bytes of the length prefix sent ahead of each buffer.
+pub const LENGTH_PREFIX_SIZE: usize = 9;
+
+/// Value of the last byte of the length prefix of a typed buffer, which is
+/// zero padding in the prefix of an untyped buffer. It encodes the version of
+/// the typed framing (currently 1) in its low nibble.
+const TYPED_FRAME_MARKER: u8 = 0xa1;
+
+/// Default size of the internal buffer of a `FramedReader`.
+pub const DEFAULT_FRAMED_READER_CAPACITY: usize = 64 * 1024;
+
+/// Maximum size in bytes of a LEB128-encoded `u64`.
+const MAX_VARINT_SIZE: usize = 10;
+
+/// The marker sent ahead of each buffer by `send_buffer_synced`, which
+/// `FramedReader::resynchronize` scans for to find the next buffer after the
+/// stream has been corrupted
+pub const SYNC_MARKER: [u8; 4] = [0xa5, 0x5a, 0xc3, 0x3c];
+
+/// The longest frame header `receive_buffer_with_codec` will read while
+/// waiting for a `FrameCodec` to decode it
+const MAX_FRAME_HEADER_SIZE: usize = 64;
+
+/// The most parts a single vectored `sendmsg` may be given (the kernel's
+/// `UIO_MAXIOV`)
+const MAX_IOVECS: usize = 1024;
+
+/// Size in bytes of the key used by `send_buffer_sealed` and
+/// `receive_buffer_sealed`.
+#[cfg(feature = "encryption")]
+pub const SEALING_KEY_SIZE: usize = 32;
+
+/// Size in bytes of the random nonce sent ahead of each sealed buffer.
+#[cfg(feature = "encryption")]
+const SEALING_NONCE_SIZE: usize = 24;
+
+/// Size in bytes of the authentication tag appended to each sealed buffer.
+#[cfg(feature = "encryption")]
+const SEALING_TAG_SIZE: usize = 16;
+
+/// The `MSG_MORE` flag to `send`, which `nix` doesn't define: more data
+/// follows, so the kernel may hold this data back to send it together.
+// SAFETY: the flag is only ever passed straight through to `send`
+const MSG_MORE: MsgFlags = unsafe { MsgFlags::from_bits_unchecked(libc::MSG_MORE) };
+
+/// Maximum number of file descriptors that can be passed with one buffer
+/// (the kernel's `SCM_MAX_FD`).
+pub const MAX_FDS_PER_BUFFER: usize = 253;
+
+////////////////////////////////////////////////////////////////////////////////
+// Errors.
+////////////////////////////////////////////////////////////////////////////////
+
+/// Errors generated by the raw file descriptor transport
+#[derive(Debug, Error)]
+pub enum RawFdError {
+    /// A `send` or `recv` was interrupted by a signal too many times in a row
+    /// without making any progress
+    #[error(
+        display = "RawFd: interrupted {} consecutive times without making progress",
+        _0
+    )]
+    InterruptedTooManyTimes(usize),
+    /// File descriptors can only be passed over transports that support
+    /// `SCM_RIGHTS` ancillary data, i.e. Unix domain sockets
+    #[error(display = "RawFd: the transport does not support passing file descriptors")]
+    AncillaryDataUnsupported,
+    /// More file descriptors were passed with a buffer than could be received
+    #[error(
+        display = "RawFd: too many file descriptors (the maximum is {})",
+        MAX_FDS_PER_BUFFER
+    )]
+    TooManyFds,
+    /// The peer of `receive_shared_buffer` didn't pass a single sealed
+    /// `memfd` at least as long as the buffer it announced
+    #[error(display = "RawFd: the peer did not pass a valid shared buffer")]
+    MalformedSharedBuffer,
+    /// The peer announced a buffer longer than the caller is willing to accept
+    #[error(
+        display = "RawFd: message of {} bytes exceeds the maximum of {} bytes",
+        length,
+        max_len
+    )]
+    MessageTooLarge {
+        /// The length announced by the peer
+        length: usize,
+        /// The maximum length the caller would accept
+        max_len: usize,
+    },
+    /// The peer sent a buffer longer than the caller is willing to accept,
+    /// which has been read and discarded so that the next buffer can be
+    /// received
+    #[error(
+        display = "RawFd: skipped a message of {} bytes exceeding the maximum of {} bytes",
+        length,
+        max_len
+    )]
+    MessageTooLargeSkipped {
+        /// The length announced by the peer
+        length: usize,
+        /// The maximum length the caller would accept
+        max_len: usize,
+    },
+    /// The caller tried to send a buffer longer than the configured maximum
+    #[error(
+        display = "RawFd: payload of {} bytes exceeds the maximum of {} bytes",
+        length,
+        max_len
+    )]
+    PayloadTooLarge {
+        /// The length of the buffer the caller tried to send
+        length: usize,
+        /// The maximum length that may be sent
+        max_len: usize,
+    },
+    /// The peer closed the connection part way through a buffer
+    #[error(display = "RawFd: connection closed part way through a message")]
+    UnexpectedEof,
+    /// A variable-length (LEB128) length prefix was malformed or overflowed
+    #[error(display = "RawFd: malformed varint length prefix")]
+    InvalidVarint,
+    /// The peer closed the connection cleanly, before sending any part of
+    /// the next buffer
+    #[error(display = "RawFd: connection closed")]
+    ConnectionClosed,
+    /// The length prefix of a buffer failed its checksum, so the stream has
+    /// been corrupted or has lost its alignment
+    #[error(display = "RawFd: length prefix checksum mismatch (framing desynchronised)")]
+    FramingDesync,
+    /// A `recv` reported reading more bytes than it was asked for, which
+    /// would otherwise overrun the buffer being filled
+    #[error(
+        display = "RawFd: recv returned {} bytes when at most {} were requested",
+        returned,
+        requested
+    )]
+    ReceiveOverrun {
+        /// The most bytes that could have been read
+        requested: usize,
+        /// The number of bytes `recv` claimed to have read
+        returned: usize,
+    },
+    /// A typed buffer (sent by `send_typed_buffer`) was received where an
+    /// untyped one was expected
+    #[error(display = "RawFd: received a typed buffer where an untyped one was expected")]
+    UnexpectedTypedFrame,
+    /// An untyped buffer was received where a typed one (sent by
+    /// `send_typed_buffer`) was expected
+    #[error(display = "RawFd: received an untyped buffer where a typed one was expected")]
+    UnexpectedUntypedFrame,
+    /// No data arrived from the peer for longer than the idle timeout
+    #[error(display = "RawFd: no data received for {:?}", _0)]
+    IdleTimeout(Duration),
+    /// A buffer received by `receive_header_and_payload` announced a header
+    /// longer than itself, or a header that failed to deserialize
+    #[error(display = "RawFd: malformed header in a header and payload buffer")]
+    MalformedHeader,
+    /// A sealed buffer was not sealed with the expected key, or was modified
+    /// in transit
+    #[error(display = "RawFd: sealed buffer failed authentication")]
+    AuthenticationFailed,
+    /// The file descriptor is not open: it was closed (most likely by this
+    /// process) before or during the operation
+    #[error(display = "RawFd: the file descriptor is not open (has it been closed?)")]
+    InvalidFd,
+    /// Reading a buffer took more `recv` calls than
+    /// `TransferConfig::max_reads_per_message` allows
+    #[error(display = "RawFd: buffer not received within {} reads", _0)]
+    TooManyReads(usize),
+    /// The flags passed to a receive can't be used with the framing
+    #[error(display = "RawFd: {:?} can't be used to receive a framed buffer", _0)]
+    IncompatibleFlags(MsgFlags),
+    /// The peer announced a buffer of a different length from the one
+    /// expected
+    #[error(
+        display = "RawFd: expected a buffer of {} bytes, but the peer announced {}",
+        expected,
+        actual
+    )]
+    UnexpectedLength {
+        /// The length that was expected
+        expected: usize,
+        /// The length the peer announced
+        actual: usize,
+    },
+    /// The socket's receive timeout (`SO_RCVTIMEO`) expired before the
+    /// buffer arrived
+    #[error(display = "RawFd: receive timed out")]
+    ReceiveTimeout,
+    /// The deadline of a `DeadlineSession` passed before the operation
+    /// finished
+    #[error(display = "RawFd: deadline exceeded")]
+    DeadlineExceeded,
+    /// A chunked send was stopped by its caller part way through a buffer,
+    /// leaving the stream mid-frame
+    #[error(display = "RawFd: send stopped after {} of {} bytes", sent, total)]
+    SendAborted {
+        /// The number of bytes of the buffer that were sent
+        sent: usize,
+        /// The length of the buffer
+        total: usize,
+    },
+}
+
+/// Convert a failed system call's error, reporting `EBADF` as
+/// `RawFdError::InvalidFd` so that using a connection after closing it is
+/// recognisable
+fn fd_error(err: Errno) -> anyhow::Error {
+    match err {
+        Errno::EBADF => anyhow!(RawFdError::InvalidFd),
+        err => anyhow!(err),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Configuration.
+////////////////////////////////////////////////////////////////////////////////
+
+/// Tunable parameters for sending and receiving buffers
+#[derive(Clone, Debug)]
+pub struct TransferConfig {
+    /// The maximum number of consecutive `EINTR` results tolerated before the
+    /// operation fails with `RawFdError::InterruptedTooManyTimes`. The count
+    /// is reset whenever a `send` or `recv` transfers some data.
+    pub max_eintr_retries: usize,
+    /// The maximum size of a buffer. Sending a longer buffer fails with
+    /// `RawFdError::PayloadTooLarge` before anything is written, and a peer
+    /// announcing a longer buffer fails with `RawFdError::MessageTooLarge`
+    /// before anything is allocated. Both sides default to
+    /// `DEFAULT_MAX_PAYLOAD_LEN` so they agree on the largest buffer.
+    pub max_payload_len: usize,
+    /// Whether the last byte of each length prefix, otherwise zero padding,
+    /// carries a checksum of the length. A receiver with this set fails with
+    /// `RawFdError::FramingDesync` as soon as it reads a corrupt or
+    /// misaligned prefix, rather than going on to read a wildly wrong number
+    /// of bytes. Receivers without it set accept prefixes with or without a
+    /// checksum, so a sender can turn it on first; both sides must agree
+    /// before a receiver turns it on. Defaults to `false`.
+    pub prefix_checksum: bool,
+    /// The most `recv` calls that reading one buffer (its length prefix and
+    /// body together) may take, or `None` for no limit. A receiver with a
+    /// limit fails with `RawFdError::TooManyReads` once it is exceeded, which
+    /// bounds the work a peer can cause by sending a buffer a byte at a time.
+    /// Only `receive_buffer_with_config` applies it. Defaults to `None`.
+    pub max_reads_per_message: Option<usize>,
+    /// The most bytes a single `recv` is asked for, or `None` (the default)
+    /// to ask for all the rest of the buffer at once. Smaller requests make
+    /// progress callbacks fire more often during a large buffer, and can
+    /// suit the kernel's buffering better. A value of 0 is treated as 1.
+    pub max_recv_chunk: Option<usize>,
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        TransferConfig {
+            max_eintr_retries: DEFAULT_MAX_EINTR_RETRIES,
+            max_payload_len: DEFAULT_MAX_PAYLOAD_LEN,
+            prefix_checksum: false,
+            max_reads_per_message: None,
+            max_recv_chunk: None,
+        }
+    }
+}
 
 ////////////////////////////////////////////////////////////////////////////////
 // Sending and receiving data.
 ////////////////////////////////////////////////////////////////////////////////
 
-/// Send a buffer of data (using a length, buffer protocol) to the file
-/// descriptor `fd`
-pub fn send_buffer(fd: RawFd, buffer: &[u8]) -> Result<()> {
+/// Counts of the work done to transfer a buffer, for profiling the transport
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TransferStats {
+    /// The number of bytes transferred, including the length prefix
+    pub bytes: usize,
+    /// The number of `send` or `recv` calls that transferred some data
+    pub syscalls: usize,
+    /// The number of calls that were interrupted by a signal and retried
+    pub eintr_retries: usize,
+}
+
+/// `send` some of `buffer` to the file descriptor `fd`, subject to any
+/// faults scripted for `fd` with `FaultyTransport`
+fn send(fd: RawFd, buffer: &[u8], flags: MsgFlags) -> nix::Result<usize> {
+    #[cfg(any(test, feature = "testing"))]
+    let buffer = match testing::inject(fd, testing::Op::Write, buffer.len()) {
+        testing::Injection::Call(len) => &buffer[..len],
+        testing::Injection::Return(result) => return result,
+    };
+    nix::sys::socket::send(fd, buffer, flags)
+}
+
+/// `recv` into `buffer` from the file descriptor `fd`, subject to any faults
+/// scripted for `fd` with `FaultyTransport`
+fn recv(fd: RawFd, buffer: &mut [u8], flags: MsgFlags) -> nix::Result<usize> {
+    #[cfg(any(test, feature = "testing"))]
+    let buffer = match testing::inject(fd, testing::Op::Read, buffer.len()) {
+        testing::Injection::Call(len) => &mut buffer[..len],
+        testing::Injection::Return(result) => return result,
+    };
+    nix::sys::socket::recv(fd, buffer, flags)
+}
+
+/// As `recv`, into uninitialised memory. Returns the number of bytes `recv`
+/// reported, which could (from a buggy transport) exceed `buffer.len()`:
+/// only that many bytes, up to `buffer.len()`, have been written.
+fn recv_uninit(fd: RawFd, buffer: &mut [MaybeUninit<u8>], flags: MsgFlags) -> nix::Result<usize> {
+    #[cfg(any(test, feature = "testing"))]
+    let buffer = match testing::inject(fd, testing::Op::Read, buffer.len()) {
+        testing::Injection::Call(len) => &mut buffer[..len],
+        testing::Injection::Return(result) => return result,
+    };
+    // SAFETY: `buffer` is valid for writes of `buffer.len()` bytes, and
+    // `recv` writes no more than that
+    let result = unsafe { libc::recv(fd, buffer.as_mut_ptr().cast(), buffer.len(), flags.bits()) };
+    Errno::result(result).map(|size| size as usize)
+}
+
+/// Send all of `buffer` to the file descriptor `fd`, retrying on `EINTR`
+/// within the budget set by `config`. `on_progress` is called with the total
+/// number of bytes sent so far after every successful `send`.
+fn send_all<F: FnMut(usize)>(
+    fd: RawFd,
+    buffer: &[u8],
+    config: &TransferConfig,
+    on_progress: F,
+) -> Result<()> {
+    send_all_with_stats(
+        fd,
+        buffer,
+        config,
+        MsgFlags::empty(),
+        on_progress,
+        &mut TransferStats::default(),
+    )
+}
+
+/// As `send_all`, passing `flags` to every `send` and adding the work done
+/// to `stats`
+fn send_all_with_stats<F: FnMut(usize)>(
+    fd: RawFd,
+    buffer: &[u8],
+    config: &TransferConfig,
+    flags: MsgFlags,
+    mut on_progress: F,
+    stats: &mut TransferStats,
+) -> Result<()> {
     let len = buffer.len();
-    // first, send the length of the buffer
-    {
-        let mut buf = [0u8; 9];
-        LittleEndian::write_u64(&mut buf, buffer.len() as u64);
-        let mut sent_bytes = 0;
-        while sent_bytes < buf.len() {
-            sent_bytes += send(fd, &buf[sent_bytes..buf.len()], MsgFlags::empty())?;
+    let mut sent_bytes = 0;
+    let mut interrupts = 0;
+    while sent_bytes < len {
+        match send(fd, &buffer[sent_bytes..len], flags) {
+            Ok(size) => {
+                sent_bytes += size;
+                interrupts = 0;
+                stats.bytes += size;
+                stats.syscalls += 1;
+                on_progress(sent_bytes);
+            }
+            Err(EINTR) => {
+                stats.eintr_retries += 1;
+                interrupts += 1;
+                if interrupts > config.max_eintr_retries {
+                    return Err(anyhow!(RawFdError::InterruptedTooManyTimes(interrupts)));
+                }
+            }
+            Err(err) => {
+                return Err(fd_error(err));
+            }
         }
     }
-    // next, send the buffer
-    {
-        let mut sent_bytes = 0;
-        while sent_bytes < len {
-            let size = match send(fd, &buffer[sent_bytes..len], MsgFlags::empty()) {
-                Ok(size) => size,
-                Err(EINTR) => 0,
-                Err(err) => {
-                    return Err(anyhow!(err));
+    Ok(())
+}
+
+/// Fill all of `buffer` with data read from the file descriptor `fd`,
+/// retrying on `EINTR` within the budget set by `config`. `on_progress` is
+/// called with the total number of bytes received so far after every
+/// successful `recv`. If the peer closes the connection then this fails with
+/// `RawFdError::ConnectionClosed` if `buffer` is the start of a message and
+/// nothing has been read, and with `RawFdError::UnexpectedEof` otherwise.
+fn receive_exact<F: FnMut(usize)>(
+    fd: RawFd,
+    buffer: &mut [u8],
+    message_start: bool,
+    config: &TransferConfig,
+    on_progress: F,
+) -> Result<()> {
+    receive_exact_counted(
+        fd,
+        buffer,
+        message_start,
+        config,
+        MsgFlags::empty(),
+        on_progress,
+        &mut 0,
+    )
+}
+
+/// Count a `recv` made for the current message in `reads`, failing with
+/// `RawFdError::TooManyReads` if that takes it over
+/// `config.max_reads_per_message`
+fn count_read(reads: &mut usize, config: &TransferConfig) -> Result<()> {
+    *reads += 1;
+    match config.max_reads_per_message {
+        Some(max_reads) if *reads > max_reads => Err(anyhow!(RawFdError::TooManyReads(max_reads))),
+        _ => Ok(()),
+    }
+}
+
+/// How many of the `remaining` bytes of a buffer to ask the next `recv` for,
+/// under `config.max_recv_chunk`
+fn recv_chunk_len(remaining: usize, config: &TransferConfig) -> usize {
+    match config.max_recv_chunk {
+        Some(chunk) => remaining.min(chunk.max(1)),
+        None => remaining,
+    }
+}
+
+/// As `receive_exact`, passing `flags` to every `recv` and counting each
+/// `recv` in `reads`, which is shared between the reads of the parts of a
+/// message
+fn receive_exact_counted<F: FnMut(usize)>(
+    fd: RawFd,
+    buffer: &mut [u8],
+    message_start: bool,
+    config: &TransferConfig,
+    flags: MsgFlags,
+    mut on_progress: F,
+    reads: &mut usize,
+) -> Result<()> {
+    let len = buffer.len();
+    let mut received_bytes = 0;
+    let mut interrupts = 0;
+    while received_bytes < len {
+        count_read(reads, config)?;
+        let requested = recv_chunk_len(len - received_bytes, config);
+        let end = received_bytes + requested;
+        match recv(fd, &mut buffer[received_bytes..end], flags) {
+            Ok(0) if message_start && received_bytes == 0 => {
+                return Err(anyhow!(RawFdError::ConnectionClosed));
+            }
+            Ok(0) => {
+                return Err(anyhow!(RawFdError::UnexpectedEof));
+            }
+            Ok(size) if size > requested => {
+                return Err(anyhow!(RawFdError::ReceiveOverrun {
+                    requested,
+                    returned: size,
+                }));
+            }
+            Ok(size) => {
+                received_bytes += size;
+                interrupts = 0;
+                on_progress(received_bytes);
+            }
+            Err(EINTR) => {
+                interrupts += 1;
+                if interrupts > config.max_eintr_retries {
+                    return Err(anyhow!(RawFdError::InterruptedTooManyTimes(interrupts)));
                 }
-            };
-            sent_bytes += size;
+            }
+            Err(EAGAIN) => wait_after_eagain(fd, flags, config)?,
+            Err(err) => return Err(fd_error(err)),
         }
     }
     Ok(())
 }
 
-/// Read a buffer of data (using a length, buffer protocol) from the file
+/// Read exactly `length` bytes from the file descriptor `fd` into a new
+/// vector, as `receive_exact` does for the body of a message, but without
+/// zero-filling the vector first: the bytes are received straight into its
+/// spare capacity, and its length only ever covers bytes `recv` has written.
+fn receive_vec<F: FnMut(usize)>(
+    fd: RawFd,
+    length: usize,
+    config: &TransferConfig,
+    on_progress: F,
+) -> Result<Vec<u8>> {
+    receive_vec_counted(fd, length, config, MsgFlags::empty(), on_progress, &mut 0)
+}
+
+/// As `receive_vec`, passing `flags` to every `recv` and counting each
+/// `recv` in `reads`
+fn receive_vec_counted<F: FnMut(usize)>(
+    fd: RawFd,
+    length: usize,
+    config: &TransferConfig,
+    flags: MsgFlags,
+    mut on_progress: F,
+    reads: &mut usize,
+) -> Result<Vec<u8>> {
+    let mut buffer: Vec<u8> = Vec::with_capacity(length);
+    let mut interrupts = 0;
+    while buffer.len() < length {
+        count_read(reads, config)?;
+        let requested = recv_chunk_len(length - buffer.len(), config);
+        let spare = &mut buffer.spare_capacity_mut()[..requested];
+        match recv_uninit(fd, spare, flags) {
+            Ok(0) => {
+                return Err(anyhow!(RawFdError::UnexpectedEof));
+            }
+            Ok(size) if size > requested => {
+                return Err(anyhow!(RawFdError::ReceiveOverrun {
+                    requested,
+                    returned: size,
+                }));
+            }
+            Ok(size) => {
+                // SAFETY: `recv` has initialised the `size` bytes following
+                // the current length
+                unsafe { buffer.set_len(buffer.len() + size) };
+                interrupts = 0;
+                on_progress(buffer.len());
+            }
+            Err(EINTR) => {
+                interrupts += 1;
+                if interrupts > config.max_eintr_retries {
+                    return Err(anyhow!(RawFdError::InterruptedTooManyTimes(interrupts)));
+                }
+            }
+            Err(EAGAIN) => wait_after_eagain(fd, flags, config)?,
+            Err(err) => {
+                return Err(fd_error(err));
+            }
+        }
+    }
+    Ok(buffer)
+}
+
+/// Deal with a `recv` on `fd` failing with `EAGAIN`. If `fd` is in
+/// non-blocking mode, wait for it to become readable so that the `recv` can
+/// be retried. Otherwise, the socket's receive timeout (`SO_RCVTIMEO`) has
+/// expired, and this fails with `RawFdError::ReceiveTimeout`; and if the
+/// caller asked for `MSG_DONTWAIT`, the `EAGAIN` is passed on as it is.
+fn wait_after_eagain(fd: RawFd, flags: MsgFlags, config: &TransferConfig) -> Result<()> {
+    if flags.contains(MsgFlags::MSG_DONTWAIT) {
+        return Err(fd_error(EAGAIN));
+    }
+    let status = OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL).map_err(fd_error)?);
+    if !status.contains(OFlag::O_NONBLOCK) {
+        return Err(anyhow!(RawFdError::ReceiveTimeout));
+    }
+    let mut interrupts = 0;
+    loop {
+        let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+        match poll(&mut fds, -1) {
+            Ok(_) if has_nval(&fds[0]) => return Err(anyhow!(RawFdError::InvalidFd)),
+            Ok(_) => return Ok(()),
+            Err(EINTR) => {
+                interrupts += 1;
+                if interrupts > config.max_eintr_retries {
+                    return Err(anyhow!(RawFdError::InterruptedTooManyTimes(interrupts)));
+                }
+            }
+            Err(err) => return Err(fd_error(err)),
+        }
+    }
+}
+
+/// The checksum of the eight length bytes of a length prefix. Its top two
+/// bits are always set, so it can't be mistaken for zero padding or for
+/// `TYPED_FRAME_MARKER`.
+fn prefix_checksum(length_bytes: &[u8]) -> u8 {
+    let folded = length_bytes[..LENGTH_PREFIX_SIZE - 1]
+        .iter()
+        .fold(0u8, |acc, byte| acc.rotate_left(1) ^ byte);
+    0xc0 | (folded & 0x3f)
+}
+
+/// Encode the length prefix sent ahead of a buffer of `len` bytes: the length
+/// as a little-endian `u64`, followed by a padding byte, or by a checksum of
+/// the length if `config.prefix_checksum` is set
+fn length_prefix(len: usize, config: &TransferConfig) -> [u8; LENGTH_PREFIX_SIZE] {
+    let mut buf = [0u8; LENGTH_PREFIX_SIZE];
+    LittleEndian::write_u64(&mut buf, len as u64);
+    if config.prefix_checksum {
+        buf[LENGTH_PREFIX_SIZE - 1] = prefix_checksum(&buf);
+    }
+    buf
+}
+
+/// Decode the length prefix of an untyped buffer. Fails with
+/// `RawFdError::UnexpectedTypedFrame` if it is the prefix of a typed buffer,
+/// rather than reading the marker as part of the length, and (if
+/// `config.prefix_checksum` is set) with `RawFdError::FramingDesync` if the
+/// checksum doesn't match.
+fn decode_length_prefix(prefix: &[u8], config: &TransferConfig) -> Result<usize> {
+    let last = prefix[LENGTH_PREFIX_SIZE - 1];
+    if last == TYPED_FRAME_MARKER {
+        return Err(anyhow!(RawFdError::UnexpectedTypedFrame));
+    }
+    if config.prefix_checksum && last != prefix_checksum(prefix) {
+        return Err(anyhow!(RawFdError::FramingDesync));
+    }
+    Ok(LittleEndian::read_u64(prefix) as usize)
+}
+
+/// Check that a buffer of `length` bytes may be sent under `config`
+fn check_payload_len(length: usize, config: &TransferConfig) -> Result<()> {
+    if length > config.max_payload_len {
+        return Err(anyhow!(RawFdError::PayloadTooLarge {
+            length,
+            max_len: config.max_payload_len,
+        }));
+    }
+    Ok(())
+}
+
+/// Check that the peer has announced a buffer of at most `max_len` bytes
+fn check_received_len(length: usize, max_len: usize) -> Result<()> {
+    if length > max_len {
+        return Err(anyhow!(RawFdError::MessageTooLarge { length, max_len }));
+    }
+    Ok(())
+}
+
+/// Read the length prefix from the file descriptor `fd`
+fn receive_length(fd: RawFd, config: &TransferConfig) -> Result<usize> {
+    let mut buf = [0u8; LENGTH_PREFIX_SIZE];
+    receive_exact(fd, &mut buf, true, config, |_| ())?;
+    decode_length_prefix(&buf, config)
+}
+
+/// Send a buffer of data (using a length, buffer protocol) to the file
 /// descriptor `fd`
+pub fn send_buffer(fd: RawFd, buffer: &[u8]) -> Result<()> {
+    send_buffer_with_config(fd, buffer, &TransferConfig::default())
+}
+
+/// Send a buffer of data (using a length, buffer protocol) to the file
+/// descriptor `fd`, using the parameters in `config`
+pub fn send_buffer_with_config(fd: RawFd, buffer: &[u8], config: &TransferConfig) -> Result<()> {
+    check_payload_len(buffer.len(), config)?;
+    // first, send the length of the buffer, hinting that the buffer follows
+    // so that the kernel can put a small buffer in the same segment (but not
+    // if there's nothing to follow, or the hint would hold the length back)
+    let flags = if buffer.is_empty() {
+        MsgFlags::empty()
+    } else {
+        MSG_MORE
+    };
+    send_all_with_stats(
+        fd,
+        &length_prefix(buffer.len(), config),
+        config,
+        flags,
+        |_| (),
+        &mut TransferStats::default(),
+    )?;
+    // next, send the buffer
+    send_all(fd, buffer, config, |_| ())
+}
+
+/// Read a buffer of data (using a length, buffer protocol) from the file
+/// descriptor `fd`. The socket may be in non-blocking mode, in which case
+/// this waits for the data to arrive all the same. On a blocking socket with
+/// a receive timeout (`SO_RCVTIMEO`), this fails with
+/// `RawFdError::ReceiveTimeout` if the timeout expires.
 pub fn receive_buffer(fd: RawFd) -> Result<Vec<u8>> {
+    receive_buffer_with_config(fd, &TransferConfig::default())
+}
+
+/// Read a buffer of data (using a length, buffer protocol) from the file
+/// descriptor `fd`, using the parameters in `config`
+pub fn receive_buffer_with_config(fd: RawFd, config: &TransferConfig) -> Result<Vec<u8>> {
+    let mut reads = 0;
     // first, read the length
-    let length = {
-        let mut buf = [0u8; 9];
-        let len = buf.len();
-        let mut received_bytes = 0;
-        while received_bytes < len {
-            received_bytes += match recv(fd, &mut buf[received_bytes..len], MsgFlags::empty()) {
-                Ok(size) => size,
-                Err(EINTR) => 0,
-                Err(err) => {
-                    println!("I have experienced an error:{:?}", err);
-                    return Err(anyhow!(err));
+    let mut prefix = [0u8; LENGTH_PREFIX_SIZE];
+    let flags = MsgFlags::empty();
+    receive_exact_counted(fd, &mut prefix, true, config, flags, |_| (), &mut reads)?;
+    let length = decode_length_prefix(&prefix, config)?;
+    check_received_len(length, config.max_payload_len)?;
+    // next, read the buffer
+    receive_vec_counted(fd, length, config, flags, |_| (), &mut reads)
+}
+
+/// Read buffers of data (using a length, buffer protocol) from the file
+/// descriptor `fd` one after another, passing each to `f` as it arrives,
+/// until `f` returns true, for streaming responses in which the peer sends
+/// any number of intermediate buffers (progress updates, say) followed by a
+/// final one. Nothing in the framing marks the final buffer: it is an
+/// ordinary buffer, and the protocol must let `f` recognise it from its
+/// contents (by a leading tag byte, for example). Returns the number of
+/// buffers received, including the final one. If the peer closes the
+/// connection first, this fails with `RawFdError::ConnectionClosed`.
+pub fn receive_until<F: FnMut(&[u8]) -> bool>(fd: RawFd, mut f: F) -> Result<usize> {
+    let mut received = 0;
+    loop {
+        let buffer = receive_buffer(fd)?;
+        received += 1;
+        if f(&buffer) {
+            return Ok(received);
+        }
+    }
+}
+
+/// Send a buffer of data (using a length, buffer protocol) to the file
+/// descriptor `fd`, passing `flags` to every `send`, for flags the other
+/// functions don't offer. `MSG_NOSIGNAL` (report a closed connection as
+/// `EPIPE` rather than raising SIGPIPE) and `MSG_MORE` (hint that more data
+/// follows the buffer) are compatible with the framing. `MSG_DONTWAIT` is
+/// not: the send fails with `EAGAIN` part way through the buffer if the
+/// socket fills up, leaving the stream mid-frame (see `try_send_buffer` for a
+/// resumable non-blocking send).
+pub fn send_buffer_with_flags(fd: RawFd, buffer: &[u8], flags: MsgFlags) -> Result<()> {
+    let config = TransferConfig::default();
+    check_payload_len(buffer.len(), &config)?;
+    let prefix_flags = if buffer.is_empty() {
+        flags
+    } else {
+        flags | MSG_MORE
+    };
+    let mut stats = TransferStats::default();
+    let prefix = length_prefix(buffer.len(), &config);
+    send_all_with_stats(fd, &prefix, &config, prefix_flags, |_| (), &mut stats)?;
+    send_all_with_stats(fd, buffer, &config, flags, |_| (), &mut stats)
+}
+
+/// Read a buffer of data (using a length, buffer protocol) from the file
+/// descriptor `fd`, passing `flags` to every `recv`. `MSG_WAITALL` (have the
+/// kernel fill each read completely where it can) is compatible with the
+/// framing. `MSG_PEEK` would read the same bytes again on every pass of the
+/// loop, and is rejected with `RawFdError::IncompatibleFlags`. `MSG_DONTWAIT`
+/// fails with `EAGAIN` part way through a buffer that hasn't fully arrived,
+/// losing the framing, so should only be used once the whole buffer is known
+/// to be waiting.
+pub fn receive_buffer_with_flags(fd: RawFd, flags: MsgFlags) -> Result<Vec<u8>> {
+    if flags.contains(MsgFlags::MSG_PEEK) {
+        return Err(anyhow!(RawFdError::IncompatibleFlags(flags)));
+    }
+    let config = TransferConfig::default();
+    let mut reads = 0;
+    let mut prefix = [0u8; LENGTH_PREFIX_SIZE];
+    receive_exact_counted(fd, &mut prefix, true, &config, flags, |_| (), &mut reads)?;
+    let length = decode_length_prefix(&prefix, &config)?;
+    check_received_len(length, config.max_payload_len)?;
+    receive_vec_counted(fd, length, &config, flags, |_| (), &mut reads)
+}
+
+/// Send a buffer of data (using a length, buffer protocol) to the file
+/// descriptor `fd`, as `send_buffer` does, and report how many `send` calls
+/// it took and how many were interrupted by signals. Many calls suggest the
+/// kernel's send buffer is small compared to the buffer; many interruptions
+/// suggest signals are interfering with the transfer.
+pub fn send_buffer_verbose(fd: RawFd, buffer: &[u8]) -> Result<TransferStats> {
+    let config = TransferConfig::default();
+    check_payload_len(buffer.len(), &config)?;
+    let mut stats = TransferStats::default();
+    let prefix = length_prefix(buffer.len(), &config);
+    send_all_with_stats(fd, &prefix, &config, MsgFlags::empty(), |_| (), &mut stats)?;
+    send_all_with_stats(fd, buffer, &config, MsgFlags::empty(), |_| (), &mut stats)?;
+    Ok(stats)
+}
+
+/// Send a buffer of data (using a length, buffer protocol) to the file
+/// descriptor `fd`, calling `on_progress` with `(bytes_sent, total)` as the
+/// body of the buffer is sent
+pub fn send_buffer_with_progress<F: FnMut(usize, usize)>(
+    fd: RawFd,
+    buffer: &[u8],
+    mut on_progress: F,
+) -> Result<()> {
+    let config = TransferConfig::default();
+    let total = buffer.len();
+    check_payload_len(total, &config)?;
+    send_all(fd, &length_prefix(total, &config), &config, |_| ())?;
+    send_all(fd, buffer, &config, |sent| on_progress(sent, total))
+}
+
+/// Send a buffer of data (using a length, buffer protocol) to the file
+/// descriptor `fd` in chunks of at most `chunk_size` bytes, calling
+/// `between_chunks` with `(bytes_sent, total)` after each chunk but the last,
+/// so that a single-threaded caller can do other work (or check whether to
+/// give up) during a large blocking send. If `between_chunks` returns
+/// `ControlFlow::Break`, the send stops with `RawFdError::SendAborted`; the
+/// peer has then received part of a buffer, so the connection must be
+/// abandoned.
+pub fn send_buffer_chunked<F: FnMut(usize, usize) -> ControlFlow<()>>(
+    fd: RawFd,
+    buffer: &[u8],
+    chunk_size: usize,
+    mut between_chunks: F,
+) -> Result<()> {
+    let config = TransferConfig::default();
+    let total = buffer.len();
+    check_payload_len(total, &config)?;
+    send_all(fd, &length_prefix(total, &config), &config, |_| ())?;
+    let mut sent = 0;
+    for chunk in buffer.chunks(chunk_size.max(1)) {
+        send_all(fd, chunk, &config, |_| ())?;
+        sent += chunk.len();
+        if sent < total && between_chunks(sent, total).is_break() {
+            return Err(anyhow!(RawFdError::SendAborted { sent, total }));
+        }
+    }
+    Ok(())
+}
+
+/// Read a buffer of data (using a length, buffer protocol) from the file
+/// descriptor `fd`, as `receive_buffer` does, and return it together with
+/// the raw bytes of its length prefix as they were read, for comparing
+/// against what a peer (written in another language, say) meant to send. If
+/// reading the buffer fails after the prefix has been read, the prefix is
+/// given in the context of the error.
+#[cfg(feature = "debug-framing")]
+pub fn receive_buffer_debug(fd: RawFd) -> Result<(Vec<u8>, [u8; LENGTH_PREFIX_SIZE])> {
+    use anyhow::Context;
+
+    let config = TransferConfig::default();
+    let mut prefix = [0u8; LENGTH_PREFIX_SIZE];
+    receive_exact(fd, &mut prefix, true, &config, |_| ())?;
+    let context = || format!("length prefix {:02x?}", prefix);
+    let length = decode_length_prefix(&prefix, &config).with_context(context)?;
+    check_received_len(length, config.max_payload_len).with_context(context)?;
+    let buffer = receive_vec(fd, length, &config, |_| ()).with_context(context)?;
+    Ok((buffer, prefix))
+}
+
+/// Read a buffer of data (using a length, buffer protocol) from the file
+/// descriptor `fd`, as `receive_buffer` does, passing `tap` the raw bytes
+/// read from `fd`: first those of the length prefix, then those of the
+/// body. Whatever was read of a buffer that fails to arrive in full or to
+/// decode is passed as well, so that a caller can capture exactly what the
+/// peer sent, for instance to record a session for debugging.
+pub fn receive_buffer_tapped<F: FnMut(&[u8])>(fd: RawFd, mut tap: F) -> Result<Vec<u8>> {
+    let config = TransferConfig::default();
+    let mut prefix = [0u8; LENGTH_PREFIX_SIZE];
+    let mut received = 0;
+    let result = receive_exact(fd, &mut prefix, true, &config, |total| received = total);
+    tap(&prefix[..received]);
+    result?;
+    let length = decode_length_prefix(&prefix, &config)?;
+    check_received_len(length, config.max_payload_len)?;
+    // zero-filled, unlike in `receive_vec`, so that a partly received body
+    // can be passed on
+    let mut buffer = vec![0; length];
+    let mut received = 0;
+    let result = receive_exact(fd, &mut buffer, false, &config, |total| received = total);
+    tap(&buffer[..received]);
+    result.map(|_| buffer)
+}
+
+/// Read a buffer of data (using a length, buffer protocol) from the file
+/// descriptor `fd`, calling `on_progress` with `(bytes_received, total)` as
+/// the body of the buffer arrives. Fails with `RawFdError::MessageTooLarge`,
+/// before reading the body, if the peer announces more than `max_len` bytes.
+pub fn receive_buffer_with_progress<F: FnMut(usize, usize)>(
+    fd: RawFd,
+    max_len: usize,
+    mut on_progress: F,
+) -> Result<Vec<u8>> {
+    let config = TransferConfig::default();
+    let length = receive_length(fd, &config)?;
+    check_received_len(length, max_len)?;
+    receive_vec(fd, length, &config, |received| {
+        on_progress(received, length)
+    })
+}
+
+/// Read a buffer of data (using a length, buffer protocol) from the file
+/// descriptor `fd`, for protocols in which its length is known in advance.
+/// Fails with `RawFdError::UnexpectedLength`, before reading the body, if the
+/// peer announces any length other than `expected_len`, which suggests the
+/// peer speaks a different version of the protocol. As the body is left
+/// unread, the connection must then be abandoned.
+pub fn receive_buffer_expect(fd: RawFd, expected_len: usize) -> Result<Vec<u8>> {
+    let config = TransferConfig::default();
+    let length = receive_length(fd, &config)?;
+    if length != expected_len {
+        return Err(anyhow!(RawFdError::UnexpectedLength {
+            expected: expected_len,
+            actual: length,
+        }));
+    }
+    receive_vec(fd, length, &config, |_| ())
+}
+
+/// Send the concatenation of `slices` as one buffer of data (using a length,
+/// buffer protocol) to the file descriptor `fd`, for callers that already
+/// hold their data as `IoSlice`s. The length prefix and the slices are
+/// written together with vectored `sendmsg` calls, without copying them into
+/// one buffer first, and the peer receives a single buffer as if from
+/// `send_buffer`.
+pub fn send_buffer_ioslices(fd: RawFd, slices: &[IoSlice]) -> Result<()> {
+    send_buffer_ioslices_verbose(fd, slices).map(|_| ())
+}
+
+/// Send the concatenation of `slices` as one buffer of data, as
+/// `send_buffer_ioslices` does, and report how many `sendmsg` calls it took
+/// and how many were interrupted by signals, for comparison with
+/// `send_buffer_verbose`
+pub fn send_buffer_ioslices_verbose(fd: RawFd, slices: &[IoSlice]) -> Result<TransferStats> {
+    let config = TransferConfig::default();
+    let length = slices.iter().map(|slice| slice.len()).sum();
+    check_payload_len(length, &config)?;
+    let prefix = length_prefix(length, &config);
+    let parts: Vec<&[u8]> = std::iter::once(&prefix[..])
+        .chain(slices.iter().map(|slice| &slice[..]))
+        .collect();
+    let total = LENGTH_PREFIX_SIZE + length;
+    let mut stats = TransferStats::default();
+    let mut sent_bytes = 0;
+    let mut interrupts = 0;
+    while sent_bytes < total {
+        // the parts still to be sent
+        let mut skip = sent_bytes;
+        let mut iov = Vec::with_capacity(parts.len());
+        for part in &parts {
+            if iov.len() == MAX_IOVECS {
+                break;
+            }
+            if skip >= part.len() {
+                skip -= part.len();
+                continue;
+            }
+            iov.push(IoSlice::new(&part[skip..]));
+            skip = 0;
+        }
+        match sendmsg::<()>(fd, &iov, &[], MsgFlags::empty(), None) {
+            Ok(size) => {
+                sent_bytes += size;
+                interrupts = 0;
+                stats.bytes += size;
+                stats.syscalls += 1;
+            }
+            Err(EINTR) => {
+                stats.eintr_retries += 1;
+                interrupts += 1;
+                if interrupts > config.max_eintr_retries {
+                    return Err(anyhow!(RawFdError::InterruptedTooManyTimes(interrupts)));
                 }
             }
+            Err(err) => {
+                return Err(fd_error(err));
+            }
         }
-        LittleEndian::read_u64(&buf) as usize
-    };
-    let mut buffer: Vec<u8> = vec![0; length];
-    // next, read the buffer
-    {
-        let mut received_bytes: usize = 0;
-        while received_bytes < length {
-            received_bytes += match recv(fd, &mut buffer[received_bytes..length], MsgFlags::empty())
-            {
-                Ok(size) => size,
-                Err(EINTR) => 0,
-                Err(err) => {
-                    return Err(anyhow!(err));
+    }
+    Ok(stats)
+}
+
+/// Read a buffer of data (using a length, buffer protocol) from the file
+/// descriptor `fd` straight into `bufs`, filling each in turn, for messages
+/// made of several distinct parts such as a fixed-size header and a body.
+/// Returns the length of the buffer, i.e. the number of bytes written across
+/// `bufs`; anything after that is left untouched. Fails with
+/// `RawFdError::MessageTooLarge`, before reading the body, if the peer
+/// announces more bytes than `bufs` can hold, in which case the connection
+/// must be abandoned.
+pub fn receive_buffer_vectored(fd: RawFd, bufs: &mut [&mut [u8]]) -> Result<usize> {
+    let config = TransferConfig::default();
+    let length = receive_length(fd, &config)?;
+    let capacity = bufs.iter().map(|buf| buf.len()).sum();
+    check_received_len(length, capacity)?;
+    let mut received_bytes = 0;
+    let mut interrupts = 0;
+    while received_bytes < length {
+        // the parts of `bufs` still to be filled
+        let mut skip = received_bytes;
+        let mut requested = length - received_bytes;
+        let mut iov = Vec::with_capacity(bufs.len());
+        for buf in bufs.iter_mut() {
+            if requested == 0 {
+                break;
+            }
+            if skip >= buf.len() {
+                skip -= buf.len();
+                continue;
+            }
+            let part = &mut buf[skip..];
+            skip = 0;
+            let part_len = part.len().min(requested);
+            requested -= part_len;
+            iov.push(IoSliceMut::new(&mut part[..part_len]));
+        }
+        let requested = length - received_bytes;
+        match recvmsg::<()>(fd, &mut iov, None, MsgFlags::empty()) {
+            Ok(msg) if msg.bytes == 0 => {
+                return Err(anyhow!(RawFdError::UnexpectedEof));
+            }
+            Ok(msg) if msg.bytes > requested => {
+                return Err(anyhow!(RawFdError::ReceiveOverrun {
+                    requested,
+                    returned: msg.bytes,
+                }));
+            }
+            Ok(msg) => {
+                received_bytes += msg.bytes;
+                interrupts = 0;
+            }
+            Err(EINTR) => {
+                interrupts += 1;
+                if interrupts > config.max_eintr_retries {
+                    return Err(anyhow!(RawFdError::InterruptedTooManyTimes(interrupts)));
+                }
+            }
+            Err(err) => {
+                return Err(fd_error(err));
+            }
+        }
+    }
+    Ok(length)
+}
+
+/// The result of a non-blocking send
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendOutcome {
+    /// The whole buffer, and its length prefix, has been sent
+    Complete,
+    /// The socket's send buffer filled up part way through. `bytes_sent`
+    /// counts the bytes sent so far, including the length prefix; pass it to
+    /// `try_send_buffer_from`, once the socket is writable again, to carry
+    /// on from where the send stopped.
+    WouldBlock {
+        /// The number of bytes of the length prefix and buffer sent so far
+        bytes_sent: usize,
+    },
+}
+
+/// Send a buffer of data (using a length, buffer protocol) to the file
+/// descriptor `fd` without blocking, returning `SendOutcome::WouldBlock` if
+/// the socket's send buffer fills up. This suits event loops that must not
+/// stall: register for writability and call `try_send_buffer_from` to
+/// resume.
+pub fn try_send_buffer(fd: RawFd, buffer: &[u8]) -> Result<SendOutcome> {
+    try_send_buffer_from(fd, buffer, 0)
+}
+
+/// Resume a non-blocking send of `buffer` to the file descriptor `fd`
+/// started by `try_send_buffer`, skipping the first `bytes_sent` bytes of the
+/// length prefix and buffer, which have already been sent. The same `buffer`
+/// must be passed each time.
+pub fn try_send_buffer_from(fd: RawFd, buffer: &[u8], bytes_sent: usize) -> Result<SendOutcome> {
+    let config = TransferConfig::default();
+    check_payload_len(buffer.len(), &config)?;
+    let prefix = length_prefix(buffer.len(), &config);
+    let total = LENGTH_PREFIX_SIZE + buffer.len();
+    let mut sent_bytes = bytes_sent.min(total);
+    let mut interrupts = 0;
+    while sent_bytes < total {
+        let remaining = if sent_bytes < LENGTH_PREFIX_SIZE {
+            &prefix[sent_bytes..]
+        } else {
+            &buffer[sent_bytes - LENGTH_PREFIX_SIZE..]
+        };
+        match send(fd, remaining, MsgFlags::MSG_DONTWAIT) {
+            Ok(size) => {
+                sent_bytes += size;
+                interrupts = 0;
+            }
+            Err(EAGAIN) => {
+                return Ok(SendOutcome::WouldBlock {
+                    bytes_sent: sent_bytes,
+                });
+            }
+            Err(EINTR) => {
+                interrupts += 1;
+                if interrupts > config.max_eintr_retries {
+                    return Err(anyhow!(RawFdError::InterruptedTooManyTimes(interrupts)));
+                }
+            }
+            Err(err) => {
+                return Err(fd_error(err));
+            }
+        }
+    }
+    Ok(SendOutcome::Complete)
+}
+
+/// Wait until `fd` is readable, or until `deadline`. Fails with
+/// `RawFdError::IdleTimeout` (reporting `idle`) if the deadline passes first.
+fn wait_readable(
+    fd: RawFd,
+    deadline: Instant,
+    idle: Duration,
+    config: &TransferConfig,
+) -> Result<()> {
+    if !poll_until(fd, PollFlags::POLLIN, deadline, config)? {
+        return Err(anyhow!(RawFdError::IdleTimeout(idle)));
+    }
+    Ok(())
+}
+
+/// Wait until the file descriptor `fd` is ready for `events` (as for
+/// `poll`), or until `deadline`, returning false if the deadline passes
+/// first. Waits interrupted by signals are retried within the budget set by
+/// `config`, and a closed `fd` fails with `RawFdError::InvalidFd`. A peer
+/// hanging up counts as ready, so that the transfer that follows reports
+/// it.
+pub fn poll_until(
+    fd: RawFd,
+    events: PollFlags,
+    deadline: Instant,
+    config: &TransferConfig,
+) -> Result<bool> {
+    let mut interrupts = 0;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        // round up, so that a sub-millisecond remainder doesn't spin
+        let timeout_ms = remaining
+            .as_nanos()
+            .div_ceil(1_000_000)
+            .min(i32::MAX as u128) as i32;
+        let mut fds = [PollFd::new(fd, events)];
+        match poll(&mut fds, timeout_ms) {
+            Ok(0) => return Ok(false),
+            // `poll` reports a closed descriptor in its events, not an error
+            Ok(_) if has_nval(&fds[0]) => return Err(anyhow!(RawFdError::InvalidFd)),
+            Ok(_) => return Ok(true),
+            Err(EINTR) => {
+                interrupts += 1;
+                if interrupts > config.max_eintr_retries {
+                    return Err(anyhow!(RawFdError::InterruptedTooManyTimes(interrupts)));
                 }
             }
+            Err(err) => return Err(fd_error(err)),
         }
     }
+}
+
+/// As `receive_exact`, but failing with `RawFdError::IdleTimeout` if no data
+/// arrives for `idle`. The timeout restarts whenever some data is received.
+fn receive_exact_with_idle_timeout(
+    fd: RawFd,
+    buffer: &mut [u8],
+    message_start: bool,
+    idle: Duration,
+    config: &TransferConfig,
+) -> Result<()> {
+    let len = buffer.len();
+    let mut received_bytes = 0;
+    let mut interrupts = 0;
+    let mut deadline = Instant::now() + idle;
+    while received_bytes < len {
+        wait_readable(fd, deadline, idle, config)?;
+        match recv(fd, &mut buffer[received_bytes..len], MsgFlags::empty()) {
+            Ok(0) if message_start && received_bytes == 0 => {
+                return Err(anyhow!(RawFdError::ConnectionClosed));
+            }
+            Ok(0) => {
+                return Err(anyhow!(RawFdError::UnexpectedEof));
+            }
+            Ok(size) if size > len - received_bytes => {
+                return Err(anyhow!(RawFdError::ReceiveOverrun {
+                    requested: len - received_bytes,
+                    returned: size,
+                }));
+            }
+            Ok(size) => {
+                received_bytes += size;
+                interrupts = 0;
+                deadline = Instant::now() + idle;
+            }
+            Err(EINTR) => {
+                interrupts += 1;
+                if interrupts > config.max_eintr_retries {
+                    return Err(anyhow!(RawFdError::InterruptedTooManyTimes(interrupts)));
+                }
+            }
+            Err(err) => {
+                return Err(fd_error(err));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read a buffer of data (using a length, buffer protocol) from the file
+/// descriptor `fd`, failing with `RawFdError::IdleTimeout` only if the peer
+/// sends nothing for longer than `idle`. Unlike an overall timeout, this
+/// lets a large buffer that is arriving slowly but steadily finish, while
+/// still detecting a stalled peer. The timeout applies equally while waiting
+/// for the buffer to start and part way through it.
+pub fn receive_buffer_with_idle_timeout(fd: RawFd, idle: Duration) -> Result<Vec<u8>> {
+    let config = TransferConfig::default();
+    let mut prefix = [0u8; LENGTH_PREFIX_SIZE];
+    receive_exact_with_idle_timeout(fd, &mut prefix, true, idle, &config)?;
+    let length = decode_length_prefix(&prefix, &config)?;
+    check_received_len(length, config.max_payload_len)?;
+    let mut buffer: Vec<u8> = vec![0; length];
+    receive_exact_with_idle_timeout(fd, &mut buffer, false, idle, &config)?;
     Ok(buffer)
 }
 
+/// A sequence of sends and receives on the file descriptor `fd` (using a
+/// length, buffer protocol) bounded as a whole by a single deadline, for
+/// multi-buffer exchanges such as a request of several buffers and its
+/// response. Each operation waits only as long as is left before the
+/// deadline, so time spent in earlier operations counts against later ones,
+/// and whichever operation runs out of time fails with
+/// `RawFdError::DeadlineExceeded`. An operation that fails part way through a
+/// buffer leaves the connection mid-frame, so it must then be abandoned.
+pub struct DeadlineSession {
+    /// The file descriptor the buffers are sent and received on
+    fd: RawFd,
+    /// When the whole exchange must be finished by
+    deadline: Instant,
+    /// The parameters for sending and receiving
+    config: TransferConfig,
+}
+
+impl DeadlineSession {
+    /// Start an exchange on `fd` that must be finished by `deadline`
+    pub fn new(fd: RawFd, deadline: Instant) -> Self {
+        DeadlineSession {
+            fd,
+            deadline,
+            config: TransferConfig::default(),
+        }
+    }
+
+    /// The time left before the deadline
+    pub fn remaining(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+
+    /// Send a buffer of data, as `send_buffer` does, by the deadline
+    pub fn send(&self, buffer: &[u8]) -> Result<()> {
+        check_payload_len(buffer.len(), &self.config)?;
+        self.send_all(&length_prefix(buffer.len(), &self.config))?;
+        self.send_all(buffer)
+    }
+
+    /// Receive a buffer of data, as `receive_buffer` does, by the deadline
+    pub fn receive(&self) -> Result<Vec<u8>> {
+        let mut prefix = [0u8; LENGTH_PREFIX_SIZE];
+        self.receive_exact(&mut prefix, true)?;
+        let length = decode_length_prefix(&prefix, &self.config)?;
+        check_received_len(length, self.config.max_payload_len)?;
+        let mut buffer = vec![0; length];
+        self.receive_exact(&mut buffer, false)?;
+        Ok(buffer)
+    }
+
+    /// Receive a buffer sent by `send_typed_buffer`, as
+    /// `receive_typed_buffer` does, by the deadline
+    pub fn receive_typed(&self) -> Result<(u8, Vec<u8>)> {
+        let mut prefix = [0u8; LENGTH_PREFIX_SIZE + 1];
+        self.receive_exact(&mut prefix, true)?;
+        if prefix[LENGTH_PREFIX_SIZE - 1] != TYPED_FRAME_MARKER {
+            return Err(anyhow!(RawFdError::UnexpectedUntypedFrame));
+        }
+        let length = LittleEndian::read_u64(&prefix) as usize;
+        check_received_len(length, self.config.max_payload_len)?;
+        let mut buffer = vec![0; length];
+        self.receive_exact(&mut buffer, false)?;
+        Ok((prefix[LENGTH_PREFIX_SIZE], buffer))
+    }
+
+    /// Wait for `fd` to be ready for `events`, failing with
+    /// `RawFdError::DeadlineExceeded` if the deadline passes first
+    fn wait(&self, events: PollFlags) -> Result<()> {
+        if !poll_until(self.fd, events, self.deadline, &self.config)? {
+            return Err(anyhow!(RawFdError::DeadlineExceeded));
+        }
+        Ok(())
+    }
+
+    /// Send all of `buffer`, never blocking past the deadline
+    fn send_all(&self, buffer: &[u8]) -> Result<()> {
+        let mut sent_bytes = 0;
+        let mut interrupts = 0;
+        while sent_bytes < buffer.len() {
+            self.wait(PollFlags::POLLOUT)?;
+            match send(self.fd, &buffer[sent_bytes..], MsgFlags::MSG_DONTWAIT) {
+                Ok(size) => {
+                    sent_bytes += size;
+                    interrupts = 0;
+                }
+                Err(EAGAIN) => continue,
+                Err(EINTR) => {
+                    interrupts += 1;
+                    if interrupts > self.config.max_eintr_retries {
+                        return Err(anyhow!(RawFdError::InterruptedTooManyTimes(interrupts)));
+                    }
+                }
+                Err(err) => return Err(fd_error(err)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill all of `buffer`, never blocking past the deadline, failing as
+    /// `receive_exact` does if the peer closes the connection
+    fn receive_exact(&self, buffer: &mut [u8], message_start: bool) -> Result<()> {
+        let len = buffer.len();
+        let mut received_bytes = 0;
+        let mut interrupts = 0;
+        while received_bytes < len {
+            self.wait(PollFlags::POLLIN)?;
+            match recv(
+                self.fd,
+                &mut buffer[received_bytes..],
+                MsgFlags::MSG_DONTWAIT,
+            ) {
+                Ok(0) if message_start && received_bytes == 0 => {
+                    return Err(anyhow!(RawFdError::ConnectionClosed));
+                }
+                Ok(0) => {
+                    return Err(anyhow!(RawFdError::UnexpectedEof));
+                }
+                Ok(size) if size > len - received_bytes => {
+                    return Err(anyhow!(RawFdError::ReceiveOverrun {
+                        requested: len - received_bytes,
+                        returned: size,
+                    }));
+                }
+                Ok(size) => {
+                    received_bytes += size;
+                    interrupts = 0;
+                }
+                Err(EAGAIN) => continue,
+                Err(EINTR) => {
+                    interrupts += 1;
+                    if interrupts > self.config.max_eintr_retries {
+                        return Err(anyhow!(RawFdError::InterruptedTooManyTimes(interrupts)));
+                    }
+                }
+                Err(err) => return Err(fd_error(err)),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether `poll` found the file descriptor of `fd` not to be open
+fn has_nval(fd: &PollFd) -> bool {
+    fd.revents()
+        .is_some_and(|revents| revents.contains(PollFlags::POLLNVAL))
+}
+
+/// Whether `fd` can accept more data right now, i.e. whether a `send` would
+/// make progress without blocking, found by polling it for `POLLOUT` without
+/// waiting. A producer can use this to decide whether to send now or keep
+/// buffering.
+pub fn is_writable(fd: RawFd) -> Result<bool> {
+    let mut fds = [PollFd::new(fd, PollFlags::POLLOUT)];
+    loop {
+        match poll(&mut fds, 0) {
+            Ok(0) => return Ok(false),
+            Ok(_) if has_nval(&fds[0]) => return Err(anyhow!(RawFdError::InvalidFd)),
+            Ok(_) => {
+                return Ok(fds[0]
+                    .revents()
+                    .is_some_and(|revents| revents.contains(PollFlags::POLLOUT)))
+            }
+            Err(EINTR) => continue,
+            Err(err) => return Err(fd_error(err)),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Framing over other byte streams.
+////////////////////////////////////////////////////////////////////////////////
+
+/// Frame `buffer` as `send_buffer` would send it, for writing to a byte
+/// stream of the caller's own (a QUIC stream, an HTTP body, and so on). The
+/// frame is a 9-byte header followed by the buffer itself. The first eight
+/// bytes of the header are the length of the buffer as a little-endian
+/// `u64`, and the ninth is zero. (A ninth byte of `0xa1` marks a typed
+/// buffer, as sent by `send_typed_buffer`, and one with its top two bits set
+/// is a checksum of the length, as sent with
+/// `TransferConfig::prefix_checksum`.)
+pub fn encode_frame(buffer: &[u8]) -> Vec<u8> {
+    let config = TransferConfig::default();
+    let mut frame = Vec::with_capacity(LENGTH_PREFIX_SIZE + buffer.len());
+    frame.extend_from_slice(&length_prefix(buffer.len(), &config));
+    frame.extend_from_slice(buffer);
+    frame
+}
+
+/// Read a buffer framed as by `encode_frame` (or sent by `send_buffer`) from
+/// `reader`. Returns `None` if the stream ends cleanly before the next frame,
+/// and fails with `RawFdError::UnexpectedEof` if it ends part way through
+/// one. As with `receive_buffer`, buffers longer than
+/// `DEFAULT_MAX_PAYLOAD_LEN` are rejected with `RawFdError::MessageTooLarge`,
+/// and typed buffers with `RawFdError::UnexpectedTypedFrame`.
+pub fn decode_frame(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>> {
+    loop {
+        match reader.fill_buf() {
+            Ok([]) => return Ok(None),
+            Ok(_) => break,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(anyhow!(err)),
+        }
+    }
+    let config = TransferConfig::default();
+    let mut prefix = [0u8; LENGTH_PREFIX_SIZE];
+    reader
+        .read_exact(&mut prefix)
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => anyhow!(RawFdError::UnexpectedEof),
+            _ => anyhow!(err),
+        })?;
+    let length = decode_length_prefix(&prefix, &config)?;
+    check_received_len(length, config.max_payload_len)?;
+    // read through `take` so that a bogus length can't make us allocate the
+    // whole buffer before any of it has arrived
+    let mut buffer = Vec::new();
+    reader.take(length as u64).read_to_end(&mut buffer)?;
+    if buffer.len() != length {
+        return Err(anyhow!(RawFdError::UnexpectedEof));
+    }
+    Ok(Some(buffer))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Typed framing.
+////////////////////////////////////////////////////////////////////////////////
+
+/// Send a buffer of data to the file descriptor `fd`, tagged with
+/// `frame_type` so the peer can tell different kinds of message apart. The
+/// length prefix is as for `send_buffer`, except that its last byte is a
+/// marker identifying the buffer as typed; the type tag follows it. An
+/// untyped receive (such as `receive_buffer`) of a typed buffer fails with
+/// `RawFdError::UnexpectedTypedFrame`, so mixing the two framings on one
+/// connection is caught rather than silently misreading the stream.
+pub fn send_typed_buffer(fd: RawFd, frame_type: u8, buffer: &[u8]) -> Result<()> {
+    let config = TransferConfig::default();
+    check_payload_len(buffer.len(), &config)?;
+    let mut prefix = [0u8; LENGTH_PREFIX_SIZE + 1];
+    LittleEndian::write_u64(&mut prefix, buffer.len() as u64);
+    prefix[LENGTH_PREFIX_SIZE - 1] = TYPED_FRAME_MARKER;
+    prefix[LENGTH_PREFIX_SIZE] = frame_type;
+    send_all(fd, &prefix, &config, |_| ())?;
+    send_all(fd, buffer, &config, |_| ())
+}
+
+/// Read a buffer sent by `send_typed_buffer` from the file descriptor `fd`,
+/// returning its type tag and contents. Fails with
+/// `RawFdError::UnexpectedUntypedFrame` if the peer sent an untyped buffer.
+pub fn receive_typed_buffer(fd: RawFd) -> Result<(u8, Vec<u8>)> {
+    let config = TransferConfig::default();
+    let mut prefix = [0u8; LENGTH_PREFIX_SIZE];
+    receive_exact(fd, &mut prefix, true, &config, |_| ())?;
+    if prefix[LENGTH_PREFIX_SIZE - 1] != TYPED_FRAME_MARKER {
+        return Err(anyhow!(RawFdError::UnexpectedUntypedFrame));
+    }
+    let length = LittleEndian::read_u64(&prefix) as usize;
+    check_received_len(length, config.max_payload_len)?;
+    let mut frame_type = [0u8; 1];
+    receive_exact(fd, &mut frame_type, false, &config, |_| ())?;
+    let buffer = receive_vec(fd, length, &config, |_| ())?;
+    Ok((frame_type[0], buffer))
+}
+
+/// Read a buffer of data (using a length, buffer protocol) from the file
+/// descriptor `fd`, rejecting it if the peer announces more than `max_len`
+/// bytes. The body of a rejected buffer is still in the socket, so unless it
+/// is removed the next receive would start part way through it. If `drain`
+/// is true, it is read and discarded (without being held in memory) and this
+/// fails with `RawFdError::MessageTooLargeSkipped`, leaving the connection
+/// ready for the next buffer. Otherwise this fails with
+/// `RawFdError::MessageTooLarge` and the connection must be abandoned.
+pub fn receive_buffer_bounded(fd: RawFd, max_len: usize, drain: bool) -> Result<Vec<u8>> {
+    let config = TransferConfig::default();
+    let length = receive_length(fd, &config)?;
+    if length > max_len && drain {
+        let mut scratch = vec![0; DRAIN_CHUNK_SIZE.min(length)];
+        let mut remaining = length;
+        while remaining > 0 {
+            let chunk = remaining.min(scratch.len());
+            receive_exact(fd, &mut scratch[..chunk], false, &config, |_| ())?;
+            remaining -= chunk;
+        }
+        return Err(anyhow!(RawFdError::MessageTooLargeSkipped {
+            length,
+            max_len
+        }));
+    }
+    check_received_len(length, max_len)?;
+    receive_vec(fd, length, &config, |_| ())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Frame codecs.
+////////////////////////////////////////////////////////////////////////////////
+
+/// The encoding of the header that announces the length of each buffer, for
+/// use with `send_buffer_with_codec` and `receive_buffer_with_codec`. This
+/// crate provides `LengthPrefixCodec` (the framing of `send_buffer`),
+/// `U32Codec` and `VarintCodec`; a peer with its own framing can be served by
+/// implementing this trait.
+pub trait FrameCodec {
+    /// Encode the header of a buffer of `len` bytes
+    fn encode_header(&self, len: usize) -> Vec<u8>;
+
+    /// Decode a header from the start of `bytes`, returning the length of
+    /// the buffer it announces and the number of bytes of the header. Returns
+    /// `Ok(None)` if `bytes` is only the start of a header, and an error if it
+    /// can't be the start of one.
+    fn decode_header(&self, bytes: &[u8]) -> Result<Option<(usize, usize)>>;
+
+    /// The length of the shortest header, which is read before the first
+    /// call to `decode_header`. Further bytes are then read one at a time
+    /// until the header is complete.
+    fn min_header_len(&self) -> usize {
+        1
+    }
+}
+
+/// The framing of `send_buffer` and `receive_buffer`: the length as a
+/// little-endian `u64`, followed by a padding byte, or by a checksum of the
+/// length if `checksum` is set (see `TransferConfig::prefix_checksum`)
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LengthPrefixCodec {
+    /// Whether the padding byte carries a checksum of the length
+    pub checksum: bool,
+}
+
+impl LengthPrefixCodec {
+    /// The configuration `length_prefix` and `decode_length_prefix` expect
+    fn config(&self) -> TransferConfig {
+        TransferConfig {
+            prefix_checksum: self.checksum,
+            ..TransferConfig::default()
+        }
+    }
+}
+
+impl FrameCodec for LengthPrefixCodec {
+    fn encode_header(&self, len: usize) -> Vec<u8> {
+        length_prefix(len, &self.config()).to_vec()
+    }
+
+    fn decode_header(&self, bytes: &[u8]) -> Result<Option<(usize, usize)>> {
+        if bytes.len() < LENGTH_PREFIX_SIZE {
+            return Ok(None);
+        }
+        let length = decode_length_prefix(&bytes[..LENGTH_PREFIX_SIZE], &self.config())?;
+        Ok(Some((length, LENGTH_PREFIX_SIZE)))
+    }
+
+    fn min_header_len(&self) -> usize {
+        LENGTH_PREFIX_SIZE
+    }
+}
+
+/// A framing with the length as a little-endian `u32`, as used by many
+/// protocols outside Rust. Buffers are limited to `u32::MAX` bytes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct U32Codec;
+
+impl FrameCodec for U32Codec {
+    fn encode_header(&self, len: usize) -> Vec<u8> {
+        let mut header = vec![0u8; 4];
+        LittleEndian::write_u32(&mut header, len as u32);
+        header
+    }
+
+    fn decode_header(&self, bytes: &[u8]) -> Result<Option<(usize, usize)>> {
+        if bytes.len() < 4 {
+            return Ok(None);
+        }
+        Ok(Some((LittleEndian::read_u32(bytes) as usize, 4)))
+    }
+
+    fn min_header_len(&self) -> usize {
+        4
+    }
+}
+
+/// The framing of `send_buffer_varint` and `receive_buffer_varint`: the
+/// length as an unsigned LEB128 varint
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VarintCodec;
+
+impl FrameCodec for VarintCodec {
+    fn encode_header(&self, len: usize) -> Vec<u8> {
+        encode_varint(len)
+    }
+
+    fn decode_header(&self, bytes: &[u8]) -> Result<Option<(usize, usize)>> {
+        decode_varint(bytes)
+    }
+}
+
+/// Send a buffer of data to the file descriptor `fd`, with its length in a
+/// header encoded by `codec`
+pub fn send_buffer_with_codec<C: FrameCodec + ?Sized>(
+    fd: RawFd,
+    buffer: &[u8],
+    codec: &C,
+) -> Result<()> {
+    let config = TransferConfig::default();
+    check_payload_len(buffer.len(), &config)?;
+    let header = codec.encode_header(buffer.len());
+    if codec.decode_header(&header)? != Some((buffer.len(), header.len())) {
+        // the codec can't represent this length
+        return Err(anyhow!(RawFdError::PayloadTooLarge {
+            length: buffer.len(),
+            max_len: config.max_payload_len,
+        }));
+    }
+    let flags = if buffer.is_empty() {
+        MsgFlags::empty()
+    } else {
+        MSG_MORE
+    };
+    send_all_with_stats(
+        fd,
+        &header,
+        &config,
+        flags,
+        |_| (),
+        &mut TransferStats::default(),
+    )?;
+    send_all(fd, buffer, &config, |_| ())
+}
+
+/// Read a buffer of data sent by `send_buffer_with_codec` with the same
+/// codec from the file descriptor `fd`. Fails with whatever error `codec`
+/// reports for a malformed header, with `RawFdError::FramingDesync` if the
+/// header grows past `MAX_FRAME_HEADER_SIZE` bytes without being decoded,
+/// and with `RawFdError::MessageTooLarge` if the length exceeds
+/// `DEFAULT_MAX_PAYLOAD_LEN`.
+pub fn receive_buffer_with_codec<C: FrameCodec + ?Sized>(fd: RawFd, codec: &C) -> Result<Vec<u8>> {
+    let config = TransferConfig::default();
+    let mut header = vec![0u8; codec.min_header_len()];
+    receive_exact(fd, &mut header, true, &config, |_| ())?;
+    let (length, consumed) = loop {
+        if let Some(decoded) = codec.decode_header(&header)? {
+            break decoded;
+        }
+        if header.len() >= MAX_FRAME_HEADER_SIZE {
+            return Err(anyhow!(RawFdError::FramingDesync));
+        }
+        header.push(0);
+        let last = header.len() - 1;
+        receive_exact(fd, &mut header[last..], false, &config, |_| ())?;
+    };
+    check_received_len(length, config.max_payload_len)?;
+    // a codec may decode a header shorter than the bytes it was given, in
+    // which case the rest already belongs to the buffer
+    let early = &header[consumed.min(header.len())..];
+    if early.len() >= length {
+        return Ok(early[..length].to_vec());
+    }
+    let mut buffer = early.to_vec();
+    buffer.extend(receive_vec(fd, length - early.len(), &config, |_| ())?);
+    Ok(buffer)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Variable-length framing.
+////////////////////////////////////////////////////////////////////////////////
+
+/// Encode `len` as an unsigned LEB128 varint: seven bits per byte, least
+/// significant group first, with the top bit set on every byte but the last
+fn encode_varint(len: usize) -> Vec<u8> {
+    let mut value = len as u64;
+    let mut encoded = Vec::with_capacity(MAX_VARINT_SIZE);
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            encoded.push(byte);
+            return encoded;
+        }
+        encoded.push(byte | 0x80);
+    }
+}
+
+/// Decode an unsigned LEB128 varint from the start of `bytes`, returning its
+/// value and its length in bytes, or `None` if `bytes` ends part way through
+/// it. Fails with `RawFdError::InvalidVarint` if it overflows 64 bits.
+fn decode_varint(bytes: &[u8]) -> Result<Option<(usize, usize)>> {
+    let mut length: u64 = 0;
+    for (index, byte) in bytes.iter().take(MAX_VARINT_SIZE).enumerate() {
+        let group = u64::from(byte & 0x7f);
+        let shift = 7 * index as u32;
+        // the tenth byte may only contribute the single remaining bit
+        if shift == 63 && group > 1 {
+            return Err(anyhow!(RawFdError::InvalidVarint));
+        }
+        length |= group << shift;
+        if byte & 0x80 == 0 {
+            let length = usize::try_from(length).unwrap_or(usize::MAX);
+            return Ok(Some((length, index + 1)));
+        }
+    }
+    if bytes.len() >= MAX_VARINT_SIZE {
+        return Err(anyhow!(RawFdError::InvalidVarint));
+    }
+    Ok(None)
+}
+
+/// Send a buffer of data to the file descriptor `fd`, prefixed with its length
+/// as an unsigned LEB128 varint rather than a fixed-size integer. This framing
+/// is simple to implement in other languages, for enclave components not
+/// written in Rust.
+pub fn send_buffer_varint(fd: RawFd, buffer: &[u8]) -> Result<()> {
+    send_buffer_with_codec(fd, buffer, &VarintCodec)
+}
+
+/// Read a buffer of data sent by `send_buffer_varint` from the file descriptor
+/// `fd`. Fails with `RawFdError::InvalidVarint` if the length prefix is
+/// malformed, and with `RawFdError::MessageTooLarge` if it exceeds
+/// `DEFAULT_MAX_PAYLOAD_LEN`.
+pub fn receive_buffer_varint(fd: RawFd) -> Result<Vec<u8>> {
+    receive_buffer_with_codec(fd, &VarintCodec)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Passing file descriptors.
+////////////////////////////////////////////////////////////////////////////////
+
+/// Check that `fd` is a Unix domain socket, the only transport that can carry
+/// `SCM_RIGHTS` ancillary data. In particular, vsock cannot.
+fn check_supports_fd_passing(fd: RawFd) -> Result<()> {
+    let address: SockaddrStorage = getsockname(fd)?;
+    if address.family() != Some(AddressFamily::Unix) {
+        return Err(anyhow!(RawFdError::AncillaryDataUnsupported));
+    }
+    Ok(())
+}
+
+/// Send a buffer of data (using a length, buffer protocol) to the file
+/// descriptor `fd`, passing the file descriptors in `fds` to the peer
+/// alongside it. Fails with `RawFdError::AncillaryDataUnsupported` unless
+/// `fd` is a Unix domain socket.
+pub fn send_with_fds(fd: RawFd, buffer: &[u8], fds: &[RawFd]) -> Result<()> {
+    check_supports_fd_passing(fd)?;
+    if fds.len() > MAX_FDS_PER_BUFFER {
+        return Err(anyhow!(RawFdError::TooManyFds));
+    }
+    let config = TransferConfig::default();
+    check_payload_len(buffer.len(), &config)?;
+    let buf = length_prefix(buffer.len(), &config);
+    // the file descriptors travel with the first byte of the length
+    let cmsgs = [ControlMessage::ScmRights(fds)];
+    let mut interrupts = 0;
+    let sent_bytes = loop {
+        match sendmsg::<()>(fd, &[IoSlice::new(&buf)], &cmsgs, MsgFlags::empty(), None) {
+            Ok(size) => break size,
+            Err(EINTR) => {
+                interrupts += 1;
+                if interrupts > config.max_eintr_retries {
+                    return Err(anyhow!(RawFdError::InterruptedTooManyTimes(interrupts)));
+                }
+            }
+            Err(err) => {
+                return Err(fd_error(err));
+            }
+        }
+    };
+    send_all(fd, &buf[sent_bytes..], &config, |_| ())?;
+    send_all(fd, buffer, &config, |_| ())
+}
+
+/// Read a buffer of data (using a length, buffer protocol) from the file
+/// descriptor `fd`, along with any file descriptors the peer passed with it.
+/// The received descriptors are owned by the caller, and are marked
+/// close-on-exec. Fails with `RawFdError::AncillaryDataUnsupported` unless
+/// `fd` is a Unix domain socket.
+pub fn receive_with_fds(fd: RawFd) -> Result<(Vec<u8>, Vec<RawFd>)> {
+    check_supports_fd_passing(fd)?;
+    let config = TransferConfig::default();
+    let mut buf = [0u8; LENGTH_PREFIX_SIZE];
+    let mut cmsg_buffer = cmsg_space!([RawFd; MAX_FDS_PER_BUFFER]);
+    let mut fds = Vec::new();
+    let mut interrupts = 0;
+    let received_bytes = loop {
+        let mut iov = [IoSliceMut::new(&mut buf)];
+        match recvmsg::<()>(
+            fd,
+            &mut iov,
+            Some(&mut cmsg_buffer),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        ) {
+            Ok(msg) if msg.bytes == 0 => {
+                return Err(anyhow!(RawFdError::ConnectionClosed));
+            }
+            Ok(msg) => {
+                for cmsg in msg.cmsgs() {
+                    if let ControlMessageOwned::ScmRights(received) = cmsg {
+                        fds.extend(received);
+                    }
+                }
+                if msg.flags.contains(MsgFlags::MSG_CTRUNC) {
+                    // don't leak the descriptors that did make it through
+                    close_all(&fds);
+                    return Err(anyhow!(RawFdError::TooManyFds));
+                }
+                break msg.bytes;
+            }
+            Err(EINTR) => {
+                interrupts += 1;
+                if interrupts > config.max_eintr_retries {
+                    return Err(anyhow!(RawFdError::InterruptedTooManyTimes(interrupts)));
+                }
+            }
+            Err(err) => {
+                return Err(fd_error(err));
+            }
+        }
+    };
+    let body = (|| {
+        receive_exact(fd, &mut buf[received_bytes..], false, &config, |_| ())?;
+        let length = decode_length_prefix(&buf, &config)?;
+        check_received_len(length, config.max_payload_len)?;
+        let mut buffer: Vec<u8> = vec![0; length];
+        receive_exact(fd, &mut buffer, false, &config, |_| ())?;
+        Ok(buffer)
+    })();
+    match body {
+        Ok(buffer) => Ok((buffer, fds)),
+        Err(err) => {
+            close_all(&fds);
+            Err(err)
+        }
+    }
+}
+
+/// Close every file descriptor in `fds`, ignoring failures
+fn close_all(fds: &[RawFd]) {
+    for fd in fds {
+        let _ = nix::unistd::close(*fd);
+    }
+}
+
+/// The seals a shared buffer's `memfd` carries once it has been sent, so
+/// that neither side can change its contents or size
+const SHARED_BUFFER_SEALS: SealFlag = SealFlag::F_SEAL_SHRINK
+    .union(SealFlag::F_SEAL_GROW)
+    .union(SealFlag::F_SEAL_WRITE)
+    .union(SealFlag::F_SEAL_SEAL);
+
+/// A `memfd`, and where (if anywhere) it is mapped into this process
+struct SharedMapping {
+    /// The `memfd` holding the buffer
+    memfd: RawFd,
+    /// Where the buffer is mapped, or null if it isn't (as it is empty)
+    ptr: *mut u8,
+    /// The length of the buffer
+    len: usize,
+}
+
+impl SharedMapping {
+    /// Map the first `len` bytes of `memfd` with `prot`, taking ownership of
+    /// `memfd`
+    fn map(memfd: RawFd, len: usize, prot: ProtFlags) -> Result<Self> {
+        let mut mapping = SharedMapping {
+            memfd,
+            ptr: std::ptr::null_mut(),
+            len,
+        };
+        if let Some(length) = NonZeroUsize::new(len) {
+            mapping.ptr =
+                unsafe { mmap(None, length, prot, MapFlags::MAP_SHARED, memfd, 0) }? as *mut u8;
+        }
+        Ok(mapping)
+    }
+
+    /// Unmap the buffer, if it is mapped
+    fn unmap(&mut self) {
+        if !self.ptr.is_null() {
+            let _ = unsafe { munmap(self.ptr as *mut _, self.len) };
+            self.ptr = std::ptr::null_mut();
+        }
+    }
+
+    /// The contents of the buffer
+    fn as_slice(&self) -> &[u8] {
+        if self.ptr.is_null() {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for SharedMapping {
+    fn drop(&mut self) {
+        self.unmap();
+        let _ = nix::unistd::close(self.memfd);
+    }
+}
+
+// SAFETY: the mapping is owned by the `SharedMapping`, and is only reached
+// through it
+unsafe impl Send for SharedMapping {}
+unsafe impl Sync for SharedMapping {}
+
+/// A buffer in shared memory to be passed to the peer of a Unix domain
+/// socket with `send_shared_buffer`, rather than copied through the socket,
+/// for moving large buffers between processes on one host (such as a test
+/// harness standing in for an enclave over the local transport). The buffer
+/// is a `memfd` mapped into this process, to be filled in place. VSOCKs
+/// can't pass file descriptors, so this can't be used with a real enclave.
+pub struct SharedBuffer(SharedMapping);
+
+impl SharedBuffer {
+    /// Create a zero-filled shared buffer of `len` bytes
+    pub fn new(len: usize) -> Result<Self> {
+        let memfd = memfd_create(
+            c"raw-fd-shared-buffer",
+            MemFdCreateFlag::MFD_CLOEXEC | MemFdCreateFlag::MFD_ALLOW_SEALING,
+        )?;
+        if let Err(err) = ftruncate(memfd, len as i64) {
+            let _ = nix::unistd::close(memfd);
+            return Err(anyhow!(err));
+        }
+        Ok(SharedBuffer(SharedMapping::map(
+            memfd,
+            len,
+            ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+        )?))
+    }
+
+    /// The contents of the buffer
+    pub fn as_slice(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+
+    /// The contents of the buffer, to be filled in before it is sent
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        if self.0.ptr.is_null() {
+            return &mut [];
+        }
+        unsafe { std::slice::from_raw_parts_mut(self.0.ptr, self.0.len) }
+    }
+}
+
+/// A shared buffer received with `receive_shared_buffer`. The memory is
+/// mapped read-only, and is sealed so that the sender can no longer change
+/// it either.
+pub struct ReceivedSharedBuffer(SharedMapping);
+
+impl ReceivedSharedBuffer {
+    /// The contents of the buffer
+    pub fn as_slice(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+}
+
+/// Pass `buffer` to the peer of the Unix domain socket `fd` without copying
+/// its contents: the buffer is sealed against further changes, and only its
+/// length (as a buffer of 8 little-endian bytes) travels over the socket,
+/// with its `memfd` passed alongside (as by `send_with_fds`). Fails with
+/// `RawFdError::AncillaryDataUnsupported` unless `fd` is a Unix domain
+/// socket.
+pub fn send_shared_buffer(fd: RawFd, buffer: SharedBuffer) -> Result<()> {
+    check_supports_fd_passing(fd)?;
+    let mut mapping = buffer.0;
+    // a memfd can't be sealed against writes while it is mapped writable
+    mapping.unmap();
+    fcntl(mapping.memfd, FcntlArg::F_ADD_SEALS(SHARED_BUFFER_SEALS)).map_err(fd_error)?;
+    send_with_fds(fd, &(mapping.len as u64).to_le_bytes(), &[mapping.memfd])
+}
+
+/// Receive a buffer passed by `send_shared_buffer` from the Unix domain
+/// socket `fd`, mapping it into this process rather than copying it. Fails
+/// with `RawFdError::MalformedSharedBuffer` if the peer passed anything but a
+/// sealed `memfd` holding the buffer it announced, and with
+/// `RawFdError::AncillaryDataUnsupported` unless `fd` is a Unix domain
+/// socket.
+pub fn receive_shared_buffer(fd: RawFd) -> Result<ReceivedSharedBuffer> {
+    let (buffer, fds) = receive_with_fds(fd)?;
+    let memfd = match (buffer.len(), fds.as_slice()) {
+        (8, [memfd]) => *memfd,
+        _ => {
+            close_all(&fds);
+            return Err(anyhow!(RawFdError::MalformedSharedBuffer));
+        }
+    };
+    let len = LittleEndian::read_u64(&buffer) as usize;
+    // unless the sender can no longer shrink the memfd, reading the mapping
+    // could fault
+    let sealed = fcntl(memfd, FcntlArg::F_GET_SEALS)
+        .is_ok_and(|seals| SealFlag::from_bits_truncate(seals).contains(SHARED_BUFFER_SEALS));
+    let long_enough = fstat(memfd).is_ok_and(|stat| stat.st_size as u64 >= len as u64);
+    if !sealed || !long_enough {
+        close_all(&fds);
+        return Err(anyhow!(RawFdError::MalformedSharedBuffer));
+    }
+    Ok(ReceivedSharedBuffer(SharedMapping::map(
+        memfd,
+        len,
+        ProtFlags::PROT_READ,
+    )?))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Buffered reading.
+////////////////////////////////////////////////////////////////////////////////
+
+/// Reads a sequence of buffers (sent using the length, buffer protocol) from
+/// a file descriptor, reading from it in large chunks rather than making
+/// separate `recv` calls for each length and buffer. This is much cheaper when
+/// many small buffers arrive back to back.
+pub struct FramedReader {
+    /// The file descriptor being read from
+    fd: RawFd,
+    /// Data read from `fd` but not yet handed out. Only `buffer[start..end]`
+    /// is valid.
+    buffer: Vec<u8>,
+    /// Start of the valid data in `buffer`
+    start: usize,
+    /// End of the valid data in `buffer`
+    end: usize,
+    /// Parameters for reading from `fd`
+    config: TransferConfig,
+    /// Whether each buffer is preceded by `SYNC_MARKER`
+    sync_markers: bool,
+}
+
+impl FramedReader {
+    /// Create a reader for `fd` with a buffer of
+    /// `DEFAULT_FRAMED_READER_CAPACITY` bytes
+    pub fn new(fd: RawFd) -> Self {
+        Self::with_capacity(fd, DEFAULT_FRAMED_READER_CAPACITY)
+    }
+
+    /// Create a reader for `fd` with a buffer of `capacity` bytes, which is
+    /// the most that will be requested from each `recv`. This is raised to
+    /// the size of the length prefix if it is smaller.
+    pub fn with_capacity(fd: RawFd, capacity: usize) -> Self {
+        FramedReader {
+            fd,
+            buffer: vec![0; capacity.max(LENGTH_PREFIX_SIZE)],
+            start: 0,
+            end: 0,
+            config: TransferConfig::default(),
+            sync_markers: false,
+        }
+    }
+
+    /// Read buffers sent by `send_buffer_synced`, each preceded by
+    /// `SYNC_MARKER` and a checksummed length prefix. `next_frame` then fails
+    /// with `RawFdError::FramingDesync` if a buffer doesn't start with the
+    /// marker or its prefix fails the checksum, after which `resynchronize`
+    /// can find the next buffer.
+    pub fn with_sync_markers(mut self) -> Self {
+        self.sync_markers = true;
+        self.config.prefix_checksum = true;
+        if self.buffer.len() < self.header_len() {
+            self.buffer.resize(self.header_len(), 0);
+        }
+        self
+    }
+
+    /// The length of the header preceding each buffer
+    fn header_len(&self) -> usize {
+        if self.sync_markers {
+            SYNC_MARKER.len() + LENGTH_PREFIX_SIZE
+        } else {
+            LENGTH_PREFIX_SIZE
+        }
+    }
+
+    /// Number of bytes buffered but not yet handed out
+    fn available(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Move any buffered data to the front of the buffer and then make one
+    /// `recv` into the free space after it. Returns the number of bytes read,
+    /// which is zero if the peer has closed the connection.
+    fn fill(&mut self) -> Result<usize> {
+        self.buffer.copy_within(self.start..self.end, 0);
+        self.end -= self.start;
+        self.start = 0;
+        let mut interrupts = 0;
+        loop {
+            let requested = self.buffer.len() - self.end;
+            match recv(self.fd, &mut self.buffer[self.end..], MsgFlags::empty()) {
+                Ok(size) if size > requested => {
+                    return Err(anyhow!(RawFdError::ReceiveOverrun {
+                        requested,
+                        returned: size,
+                    }));
+                }
+                Ok(size) => {
+                    self.end += size;
+                    return Ok(size);
+                }
+                Err(EINTR) => {
+                    interrupts += 1;
+                    if interrupts > self.config.max_eintr_retries {
+                        return Err(anyhow!(RawFdError::InterruptedTooManyTimes(interrupts)));
+                    }
+                }
+                Err(err) => {
+                    return Err(fd_error(err));
+                }
+            }
+        }
+    }
+
+    /// Return the next buffer sent by the peer, or `None` if the peer closed
+    /// the connection cleanly between buffers. Fails with
+    /// `RawFdError::UnexpectedEof` if the peer closed the connection part way
+    /// through a buffer, and with `RawFdError::MessageTooLarge`, before
+    /// anything is allocated, if the peer announced a buffer longer than
+    /// `DEFAULT_MAX_PAYLOAD_LEN`.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        let header_len = self.header_len();
+        while self.available() < header_len {
+            if self.fill()? == 0 {
+                if self.available() == 0 {
+                    return Ok(None);
+                }
+                return Err(anyhow!(RawFdError::UnexpectedEof));
+            }
+        }
+        let mut prefix = &self.buffer[self.start..self.end];
+        if self.sync_markers {
+            if prefix[..SYNC_MARKER.len()] != SYNC_MARKER {
+                return Err(anyhow!(RawFdError::FramingDesync));
+            }
+            prefix = &prefix[SYNC_MARKER.len()..];
+        }
+        let length = decode_length_prefix(prefix, &self.config)?;
+        check_received_len(length, self.config.max_payload_len)?;
+        self.start += header_len;
+
+        let mut frame = Vec::with_capacity(length);
+        loop {
+            let take = self.available().min(length - frame.len());
+            frame.extend_from_slice(&self.buffer[self.start..self.start + take]);
+            self.start += take;
+            if frame.len() == length {
+                return Ok(Some(frame));
+            }
+            if self.fill()? == 0 {
+                return Err(anyhow!(RawFdError::UnexpectedEof));
+            }
+        }
+    }
+
+    /// After `next_frame` has failed with `RawFdError::FramingDesync`,
+    /// discard data up to the start of the next buffer, so that reading can
+    /// carry on without reconnecting. Every byte before the next occurrence
+    /// of `SYNC_MARKER` followed by a length prefix that passes its checksum
+    /// is thrown away, including the rest of any buffer that was damaged,
+    /// and the number of bytes discarded is returned. A buffer whose body
+    /// happens to contain such a sequence could be mistaken for a boundary,
+    /// which the checksum makes unlikely but not impossible. Only available
+    /// with `with_sync_markers`; fails with `RawFdError::UnexpectedEof` if
+    /// the peer closes the connection before another buffer is found.
+    pub fn resynchronize(&mut self) -> Result<usize> {
+        if !self.sync_markers {
+            return Err(anyhow!(RawFdError::FramingDesync));
+        }
+        let header_len = self.header_len();
+        let mut discarded = 0;
+        // the current position is where the damaged buffer was found
+        let mut skip = 1;
+        loop {
+            let candidate = self.buffer[self.start..self.end]
+                .windows(SYNC_MARKER.len())
+                .skip(skip)
+                .position(|window| window == SYNC_MARKER)
+                .map(|offset| offset + skip);
+            let offset = match candidate {
+                // keep enough bytes to hold a marker split across reads
+                None => (self.available() + 1).saturating_sub(SYNC_MARKER.len()),
+                Some(offset) => offset,
+            };
+            self.start += offset;
+            discarded += offset;
+            if offset > 0 {
+                skip = 0;
+            }
+            if candidate.is_some() {
+                while self.available() < header_len {
+                    if self.fill()? == 0 {
+                        return Err(anyhow!(RawFdError::UnexpectedEof));
+                    }
+                }
+                let prefix = &self.buffer[self.start + SYNC_MARKER.len()..self.end];
+                if decode_length_prefix(prefix, &self.config).is_ok() {
+                    return Ok(discarded);
+                }
+                // a false boundary: keep looking after it
+                skip = 1;
+                continue;
+            }
+            if self.fill()? == 0 {
+                return Err(anyhow!(RawFdError::UnexpectedEof));
+            }
+        }
+    }
+}
+
+/// Send a buffer of data to the file descriptor `fd` preceded by
+/// `SYNC_MARKER` and a checksummed length prefix, for reading with a
+/// `FramedReader` created `with_sync_markers`. The marker lets the reader
+/// recover from a corrupted stream with `FramedReader::resynchronize`.
+pub fn send_buffer_synced(fd: RawFd, buffer: &[u8]) -> Result<()> {
+    let config = TransferConfig {
+        prefix_checksum: true,
+        ..TransferConfig::default()
+    };
+    check_payload_len(buffer.len(), &config)?;
+    let mut header = SYNC_MARKER.to_vec();
+    header.extend_from_slice(&length_prefix(buffer.len(), &config));
+    let flags = if buffer.is_empty() {
+        MsgFlags::empty()
+    } else {
+        MSG_MORE
+    };
+    send_all_with_stats(
+        fd,
+        &header,
+        &config,
+        flags,
+        |_| (),
+        &mut TransferStats::default(),
+    )?;
+    send_all(fd, buffer, &config, |_| ())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Integration with the `bytes` crate.
+////////////////////////////////////////////////////////////////////////////////
+
+/// Send a `Bytes` buffer (using a length, buffer protocol) to the file
+/// descriptor `fd`. The framing is identical to `send_buffer`.
+#[cfg(feature = "bytes")]
+pub fn send_bytes(fd: RawFd, buffer: &bytes::Bytes) -> Result<()> {
+    send_buffer(fd, buffer)
+}
+
+/// Read a buffer of data (using a length, buffer protocol) from the file
+/// descriptor `fd` into a `Bytes`. The framing is identical to
+/// `receive_buffer`.
+#[cfg(feature = "bytes")]
+pub fn receive_bytes(fd: RawFd) -> Result<bytes::Bytes> {
+    let config = TransferConfig::default();
+    let length = receive_length(fd, &config)?;
+    check_received_len(length, config.max_payload_len)?;
+    let mut buffer = bytes::BytesMut::zeroed(length);
+    receive_exact(fd, &mut buffer, false, &config, |_| ())?;
+    Ok(buffer.freeze())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Serialized headers.
+////////////////////////////////////////////////////////////////////////////////
+
+/// The size of the length of the header in a buffer sent by
+/// `send_header_and_payload`
+#[cfg(feature = "serde")]
+pub const HEADER_LENGTH_SIZE: usize = 4;
+
+/// A buffer received by `receive_header_and_payload`: the deserialized
+/// header, and the payload, which is left in place in the received buffer
+#[cfg(feature = "serde")]
+pub struct HeaderAndPayload<H> {
+    /// The header
+    pub header: H,
+    /// The whole buffer received, header included
+    buffer: Vec<u8>,
+    /// Where the payload starts in `buffer`
+    payload_start: usize,
+}
+
+#[cfg(feature = "serde")]
+impl<H> HeaderAndPayload<H> {
+    /// The payload that followed the header
+    pub fn payload(&self) -> &[u8] {
+        &self.buffer[self.payload_start..]
+    }
+}
+
+/// Send a structured header and a raw payload to the file descriptor `fd`
+/// as one buffer (using a length, buffer protocol), for messages made of
+/// some metadata and a bulk blob. The header is serialized as JSON, and the
+/// buffer holds the length of the serialized header (`HEADER_LENGTH_SIZE`
+/// bytes, little-endian), the serialized header, and then the payload. The
+/// parts are sent as with `send_buffer_ioslices`, so the payload is never
+/// copied.
+#[cfg(feature = "serde")]
+pub fn send_header_and_payload<H: serde::Serialize>(
+    fd: RawFd,
+    header: &H,
+    payload: &[u8],
+) -> Result<()> {
+    let header = serde_json::to_vec(header)?;
+    let header_length = u32::try_from(header.len())
+        .map_err(|_| {
+            anyhow!(RawFdError::PayloadTooLarge {
+                length: header.len(),
+                max_len: u32::MAX as usize,
+            })
+        })?
+        .to_le_bytes();
+    send_buffer_ioslices(
+        fd,
+        &[
+            IoSlice::new(&header_length),
+            IoSlice::new(&header),
+            IoSlice::new(payload),
+        ],
+    )
+}
+
+/// Read a buffer sent by `send_header_and_payload` from the file descriptor
+/// `fd`, deserializing its header. The payload is not copied out of the
+/// received buffer: see `HeaderAndPayload::payload`. Fails with
+/// `RawFdError::MalformedHeader` if the header can't be read.
+#[cfg(feature = "serde")]
+pub fn receive_header_and_payload<H: serde::de::DeserializeOwned>(
+    fd: RawFd,
+) -> Result<HeaderAndPayload<H>> {
+    let buffer = receive_buffer(fd)?;
+    let malformed = || anyhow!(RawFdError::MalformedHeader);
+    let header_length = buffer.get(..HEADER_LENGTH_SIZE).ok_or_else(malformed)?;
+    let payload_start = HEADER_LENGTH_SIZE + LittleEndian::read_u32(header_length) as usize;
+    let header = buffer
+        .get(HEADER_LENGTH_SIZE..payload_start)
+        .ok_or_else(malformed)?;
+    let header = serde_json::from_slice(header).map_err(|_| malformed())?;
+    Ok(HeaderAndPayload {
+        header,
+        buffer,
+        payload_start,
+    })
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Authenticated encryption.
+////////////////////////////////////////////////////////////////////////////////
+
+/// Encrypt and authenticate `buffer` with the pre-shared `key`, and send it
+/// (using a length, buffer protocol) to the file descriptor `fd`. The body of
+/// the frame is a fresh random nonce followed by the XChaCha20-Poly1305
+/// ciphertext and tag.
+///
+/// The key must be established out of band (for instance, provisioned to the
+/// enclave after attestation) and shared only by the two peers. Nonces are
+/// 192 bits, drawn from the operating system's random number generator for
+/// every buffer, so they will not repeat however many buffers are sealed
+/// with one key, and the sender does not need to keep any state. Neither
+/// replayed nor reordered buffers are detected: if that matters, the protocol
+/// on top should include sequence numbers in the buffers.
+#[cfg(feature = "encryption")]
+pub fn send_buffer_sealed(fd: RawFd, key: &[u8; SEALING_KEY_SIZE], buffer: &[u8]) -> Result<()> {
+    use chacha20poly1305::{
+        aead::{Aead, AeadCore, KeyInit, OsRng},
+        XChaCha20Poly1305,
+    };
+
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, buffer)
+        .map_err(|_| anyhow!(RawFdError::AuthenticationFailed))?;
+    let mut sealed = Vec::with_capacity(SEALING_NONCE_SIZE + ciphertext.len());
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    send_buffer(fd, &sealed)
+}
+
+/// Read a buffer sent by `send_buffer_sealed` from the file descriptor `fd`,
+/// and decrypt it with the pre-shared `key`. Fails with
+/// `RawFdError::AuthenticationFailed` if the buffer was sealed with a
+/// different key or has been tampered with.
+#[cfg(feature = "encryption")]
+pub fn receive_buffer_sealed(fd: RawFd, key: &[u8; SEALING_KEY_SIZE]) -> Result<Vec<u8>> {
+    use chacha20poly1305::{
+        aead::{Aead, KeyInit},
+        XChaCha20Poly1305, XNonce,
+    };
+
+    let sealed = receive_buffer(fd)?;
+    if sealed.len() < SEALING_NONCE_SIZE + SEALING_TAG_SIZE {
+        return Err(anyhow!(RawFdError::AuthenticationFailed));
+    }
+    let (nonce, ciphertext) = sealed.split_at(SEALING_NONCE_SIZE);
+    XChaCha20Poly1305::new(key.into())
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!(RawFdError::AuthenticationFailed))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Tests.
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::{
+        sys::{
+            signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
+            socket::{setsockopt, socketpair, sockopt, SockFlag, SockType},
+            time::{TimeVal, TimeValLike},
+        },
+        unistd::close,
+    };
+    use std::{os::unix::thread::JoinHandleExt, thread};
+
+    /// A connected pair of Unix stream sockets, closed when dropped
+    struct Pair {
+        /// One end
+        a: RawFd,
+        /// The other end
+        b: RawFd,
+    }
+
+    impl Pair {
+        fn new() -> Self {
+            let (a, b) = socketpair(
+                AddressFamily::Unix,
+                SockType::Stream,
+                None,
+                SockFlag::empty(),
+            )
+            .unwrap();
+            Pair { a, b }
+        }
+
+        /// Close the first end, as a peer going away would
+        fn close_a(&mut self) {
+            close(self.a).unwrap();
+            self.a = -1;
+        }
+    }
+
+    impl Drop for Pair {
+        fn drop(&mut self) {
+            for fd in [self.a, self.b] {
+                if fd >= 0 {
+                    let _ = close(fd);
+                }
+            }
+        }
+    }
+
+    /// The `RawFdError` that `result` failed with
+    fn error_of<T: std::fmt::Debug>(result: Result<T>) -> RawFdError {
+        result.unwrap_err().downcast().unwrap()
+    }
+
+    /// Put `fd` into non-blocking mode
+    fn set_nonblocking(fd: RawFd) {
+        let status = OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL).unwrap());
+        fcntl(fd, FcntlArg::F_SETFL(status | OFlag::O_NONBLOCK)).unwrap();
+    }
+
+    #[test]
+    fn short_transfers_are_completed() {
+        let pair = Pair::new();
+        let buffer: Vec<u8> = (0..=255).collect();
+        let writes = FaultyTransport::new(pair.a).script_writes([
+            Fault::Short(1),
+            Fault::Short(3),
+            Fault::Short(100),
+        ]);
+        send_buffer(pair.a, &buffer).unwrap();
+        assert_eq!(writes.pending_writes(), 0);
+        let reads = FaultyTransport::new(pair.b).script_reads([
+            Fault::Short(2),
+            Fault::Short(7),
+            Fault::Short(1),
+            Fault::Short(50),
+        ]);
+        assert_eq!(receive_buffer(pair.b).unwrap(), buffer);
+        assert_eq!(reads.pending_reads(), 0);
+    }
+
+    #[test]
+    fn interrupted_transfers_are_retried() {
+        let pair = Pair::new();
+        let _writes = FaultyTransport::new(pair.a).script_writes([
+            Fault::Interrupt,
+            Fault::Short(4),
+            Fault::Interrupt,
+            Fault::Interrupt,
+        ]);
+        send_buffer(pair.a, b"interrupted").unwrap();
+        let _reads = FaultyTransport::new(pair.b).script_reads([
+            Fault::Interrupt,
+            Fault::Short(5),
+            Fault::Interrupt,
+            Fault::Short(6),
+            Fault::Interrupt,
+        ]);
+        assert_eq!(receive_buffer(pair.b).unwrap(), b"interrupted");
+    }
+
+    #[test]
+    fn would_block_waits_on_a_non_blocking_socket() {
+        let pair = Pair::new();
+        send_buffer(pair.a, b"later").unwrap();
+        set_nonblocking(pair.b);
+        let _reads = FaultyTransport::new(pair.b).script_reads([
+            Fault::WouldBlock,
+            Fault::Short(3),
+            Fault::WouldBlock,
+        ]);
+        assert_eq!(receive_buffer(pair.b).unwrap(), b"later");
+    }
+
+    #[test]
+    fn would_block_on_a_blocking_socket_is_a_timeout() {
+        let pair = Pair::new();
+        send_buffer(pair.a, b"late").unwrap();
+        let _reads = FaultyTransport::new(pair.b).script_reads([Fault::WouldBlock]);
+        assert!(matches!(
+            error_of(receive_buffer(pair.b)),
+            RawFdError::ReceiveTimeout
+        ));
+    }
+
+    #[test]
+    fn a_non_blocking_socket_waits_for_a_late_message() {
+        let pair = Pair::new();
+        set_nonblocking(pair.b);
+        let a = pair.a;
+        let sender = thread::spawn(move || {
+            let frame = encode_frame(b"late!");
+            let (prefix, body) = frame.split_at(LENGTH_PREFIX_SIZE);
+            thread::sleep(Duration::from_millis(50));
+            send(a, prefix, MsgFlags::empty()).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            send(a, body, MsgFlags::empty()).unwrap();
+        });
+        assert_eq!(receive_buffer(pair.b).unwrap(), b"late!");
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn an_expired_receive_timeout_is_reported() {
+        let pair = Pair::new();
+        setsockopt(pair.b, sockopt::ReceiveTimeout, &TimeVal::milliseconds(50)).unwrap();
+        assert!(matches!(
+            error_of(receive_buffer(pair.b)),
+            RawFdError::ReceiveTimeout
+        ));
+    }
+
+    #[test]
+    fn eof_before_a_buffer_is_a_clean_close() {
+        let pair = Pair::new();
+        let _reads = FaultyTransport::new(pair.b).script_reads([Fault::Eof]);
+        assert!(matches!(
+            error_of(receive_buffer(pair.b)),
+            RawFdError::ConnectionClosed
+        ));
+    }
+
+    #[test]
+    fn eof_part_way_through_a_buffer_is_unexpected() {
+        let pair = Pair::new();
+        send_buffer(pair.a, b"truncated").unwrap();
+        let _reads = FaultyTransport::new(pair.b)
+            .script_reads([Fault::Short(LENGTH_PREFIX_SIZE), Fault::Eof]);
+        assert!(matches!(
+            error_of(receive_buffer(pair.b)),
+            RawFdError::UnexpectedEof
+        ));
+    }
+
+    #[test]
+    fn close_before_any_data_is_a_clean_close() {
+        let mut pair = Pair::new();
+        pair.close_a();
+        assert!(matches!(
+            error_of(receive_buffer(pair.b)),
+            RawFdError::ConnectionClosed
+        ));
+        assert!(matches!(
+            error_of(receive_buffer_with_idle_timeout(
+                pair.b,
+                Duration::from_secs(1)
+            )),
+            RawFdError::ConnectionClosed
+        ));
+    }
+
+    #[test]
+    fn close_part_way_through_the_prefix_is_unexpected() {
+        let mut pair = Pair::new();
+        send(
+            pair.a,
+            &length_prefix(100, &TransferConfig::default())[..4],
+            MsgFlags::empty(),
+        )
+        .unwrap();
+        pair.close_a();
+        assert!(matches!(
+            error_of(receive_buffer(pair.b)),
+            RawFdError::UnexpectedEof
+        ));
+    }
+
+    #[test]
+    fn close_part_way_through_the_idle_timeout_prefix_is_unexpected() {
+        let mut pair = Pair::new();
+        send(pair.a, &[1, 0, 0], MsgFlags::empty()).unwrap();
+        pair.close_a();
+        assert!(matches!(
+            error_of(receive_buffer_with_idle_timeout(
+                pair.b,
+                Duration::from_secs(1)
+            )),
+            RawFdError::UnexpectedEof
+        ));
+    }
+
+    #[test]
+    fn varint_lengths_round_trip_across_size_boundaries() {
+        // the largest lengths taking 1, 2 and 3 bytes, and the next ones up
+        let lengths = [
+            (0, 1),
+            (127, 1),
+            (128, 2),
+            (16383, 2),
+            (16384, 3),
+            (2097151, 3),
+            (2097152, 4),
+        ];
+        for (length, encoded_len) in lengths {
+            assert_eq!(encode_varint(length).len(), encoded_len);
+            assert_eq!(
+                decode_varint(&encode_varint(length)).unwrap(),
+                Some((length, encoded_len))
+            );
+            let pair = Pair::new();
+            let buffer: Vec<u8> = (0..length).map(|index| index as u8).collect();
+            let fd = pair.a;
+            let sent = buffer.clone();
+            let sender = thread::spawn(move || send_buffer_varint(fd, &sent));
+            assert_eq!(receive_buffer_varint(pair.b).unwrap(), buffer);
+            sender.join().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn oversized_varint_lengths_are_rejected() {
+        let pair = Pair::new();
+        let prefix = encode_varint(DEFAULT_MAX_PAYLOAD_LEN + 1);
+        send(pair.a, &prefix, MsgFlags::empty()).unwrap();
+        assert!(matches!(
+            error_of(receive_buffer_varint(pair.b)),
+            RawFdError::MessageTooLarge { length, .. } if length == DEFAULT_MAX_PAYLOAD_LEN + 1
+        ));
+    }
+
+    #[test]
+    fn overflowing_varints_are_malformed() {
+        let pair = Pair::new();
+        send(pair.a, &[0xff; MAX_VARINT_SIZE], MsgFlags::empty()).unwrap();
+        assert!(matches!(
+            error_of(receive_buffer_varint(pair.b)),
+            RawFdError::InvalidVarint
+        ));
+        assert_eq!(decode_varint(&[0x80, 0x80]).unwrap(), None);
+    }
+
+    #[test]
+    fn a_full_send_buffer_is_not_writable() {
+        let pair = Pair::new();
+        assert!(is_writable(pair.a).unwrap());
+        let chunk = [0u8; 64 * 1024];
+        while send(pair.a, &chunk, MsgFlags::MSG_DONTWAIT).is_ok() {}
+        assert!(!is_writable(pair.a).unwrap());
+        let mut sink = vec![0u8; 64 * 1024];
+        while recv(pair.b, &mut sink, MsgFlags::MSG_DONTWAIT).is_ok() {}
+        assert!(is_writable(pair.a).unwrap());
+    }
+
+    #[test]
+    fn an_overrunning_recv_of_the_prefix_is_rejected() {
+        let pair = Pair::new();
+        send_buffer(pair.a, b"overrun").unwrap();
+        let _reads = FaultyTransport::new(pair.b).script_reads([Fault::Overrun]);
+        assert!(matches!(
+            error_of(receive_buffer(pair.b)),
+            RawFdError::ReceiveOverrun {
+                requested: LENGTH_PREFIX_SIZE,
+                returned
+            } if returned == LENGTH_PREFIX_SIZE + 1
+        ));
+    }
+
+    #[test]
+    fn an_overrunning_recv_of_the_body_is_rejected() {
+        let pair = Pair::new();
+        send_buffer(pair.a, b"overrun").unwrap();
+        let _reads = FaultyTransport::new(pair.b).script_reads([
+            Fault::Short(LENGTH_PREFIX_SIZE),
+            Fault::Short(2),
+            Fault::Overrun,
+        ]);
+        assert!(matches!(
+            error_of(receive_buffer(pair.b)),
+            RawFdError::ReceiveOverrun {
+                requested: 5,
+                returned: 6
+            }
+        ));
+    }
+
+    #[test]
+    fn an_overrunning_recv_is_rejected_by_every_reader() {
+        let pair = Pair::new();
+        send_buffer(pair.a, b"overrun").unwrap();
+        let _reads = FaultyTransport::new(pair.b).script_reads([Fault::Overrun; 3]);
+        let overran =
+            |result: Result<Vec<u8>>| matches!(error_of(result), RawFdError::ReceiveOverrun { .. });
+        assert!(overran(
+            FramedReader::new(pair.b)
+                .next_frame()
+                .map(Option::unwrap_or_default)
+        ));
+        assert!(overran(receive_buffer_with_idle_timeout(
+            pair.b,
+            Duration::from_secs(1)
+        )));
+        let deadline = Instant::now() + Duration::from_secs(1);
+        assert!(overran(DeadlineSession::new(pair.b, deadline).receive()));
+    }
+
+    /// A configuration tolerating at most `max_eintr_retries` consecutive
+    /// interruptions
+    fn eintr_budget(max_eintr_retries: usize) -> TransferConfig {
+        TransferConfig {
+            max_eintr_retries,
+            ..TransferConfig::default()
+        }
+    }
+
+    #[test]
+    fn interruptions_beyond_the_budget_fail() {
+        let pair = Pair::new();
+        send_buffer(pair.a, b"never read").unwrap();
+        let _reads = FaultyTransport::new(pair.b).script_reads([Fault::Interrupt; 3]);
+        assert!(matches!(
+            error_of(receive_buffer_with_config(pair.b, &eintr_budget(2))),
+            RawFdError::InterruptedTooManyTimes(3)
+        ));
+        let _writes = FaultyTransport::new(pair.a).script_writes([Fault::Interrupt; 3]);
+        assert!(matches!(
+            error_of(send_buffer_with_config(pair.a, b"unsent", &eintr_budget(2))),
+            RawFdError::InterruptedTooManyTimes(3)
+        ));
+    }
+
+    #[test]
+    fn progress_resets_the_interruption_budget() {
+        let pair = Pair::new();
+        let config = eintr_budget(2);
+        let interrupted_pairs = [
+            Fault::Interrupt,
+            Fault::Interrupt,
+            Fault::Short(4),
+            Fault::Interrupt,
+            Fault::Interrupt,
+            Fault::Short(4),
+            Fault::Interrupt,
+            Fault::Interrupt,
+        ];
+        let _writes = FaultyTransport::new(pair.a).script_writes(interrupted_pairs);
+        send_buffer_with_config(pair.a, b"made progress", &config).unwrap();
+        let _reads = FaultyTransport::new(pair.b).script_reads(interrupted_pairs);
+        assert_eq!(
+            receive_buffer_with_config(pair.b, &config).unwrap(),
+            b"made progress"
+        );
+    }
+
+    extern "C" fn ignore_signal(_: libc::c_int) {}
+
+    #[test]
+    fn a_signal_storm_surfaces_as_an_error() {
+        // without `SA_RESTART`, so that each signal interrupts the `recv`
+        let action = SigAction::new(
+            SigHandler::Handler(ignore_signal),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        unsafe { sigaction(Signal::SIGUSR1, &action) }.unwrap();
+        let pair = Pair::new();
+        let fd = pair.b;
+        let receiver = thread::spawn(move || receive_buffer_with_config(fd, &eintr_budget(3)));
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !receiver.is_finished() && Instant::now() < deadline {
+            // the thread can't have been joined yet, so its ID is still valid
+            unsafe { libc::pthread_kill(receiver.as_pthread_t(), libc::SIGUSR1) };
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(matches!(
+            error_of(receiver.join().unwrap()),
+            RawFdError::InterruptedTooManyTimes(4)
+        ));
+    }
+
+    #[test]
+    fn eof_while_sending_fails() {
+        let pair = Pair::new();
+        let _writes = FaultyTransport::new(pair.a).script_writes([Fault::Short(2), Fault::Eof]);
+        let err = send_buffer(pair.a, b"unsent").unwrap_err();
+        assert_eq!(err.downcast_ref::<Errno>(), Some(&Errno::EPIPE));
+    }
+
+    #[test]
+    fn several_ioslices_arrive_as_one_frame() {
+        let pair = Pair::new();
+        let slices = [
+            IoSlice::new(b"hello"),
+            IoSlice::new(b""),
+            IoSlice::new(b", "),
+            IoSlice::new(b"world"),
+        ];
+        send_buffer_ioslices(pair.a, &slices).unwrap();
+        assert_eq!(receive_buffer(pair.b).unwrap(), b"hello, world");
+    }
+
+    #[test]
+    fn more_ioslices_than_one_sendmsg_takes_arrive_as_one_frame() {
+        let pair = Pair::new();
+        let parts: Vec<Vec<u8>> = (0..2 * MAX_IOVECS + 3)
+            .map(|index| vec![index as u8; 100])
+            .collect();
+        let buffer = parts.concat();
+        let fd = pair.a;
+        let sender = thread::spawn(move || {
+            let slices: Vec<IoSlice> = parts.iter().map(|part| IoSlice::new(part)).collect();
+            send_buffer_ioslices(fd, &slices)
+        });
+        assert_eq!(receive_buffer(pair.b).unwrap(), buffer);
+        sender.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn checksummed_prefixes_round_trip() {
+        let pair = Pair::new();
+        let config = TransferConfig {
+            prefix_checksum: true,
+            ..TransferConfig::default()
+        };
+        send_buffer_with_config(pair.a, b"checked", &config).unwrap();
+        assert_eq!(
+            receive_buffer_with_config(pair.b, &config).unwrap(),
+            b"checked"
+        );
+        // a receiver not checking accepts a checksummed prefix as well
+        send_buffer_with_config(pair.a, b"unchecked", &config).unwrap();
+        assert_eq!(receive_buffer(pair.b).unwrap(), b"unchecked");
+    }
+
+    #[test]
+    fn a_corrupt_prefix_is_a_desync() {
+        let pair = Pair::new();
+        let config = TransferConfig {
+            prefix_checksum: true,
+            ..TransferConfig::default()
+        };
+        let mut prefix = length_prefix(7, &config);
+        prefix[0] ^= 0x10;
+        send(pair.a, &prefix, MsgFlags::empty()).unwrap();
+        assert!(matches!(
+            error_of(receive_buffer_with_config(pair.b, &config)),
+            RawFdError::FramingDesync
+        ));
+    }
+
+    #[test]
+    fn a_misaligned_stream_is_a_desync() {
+        let pair = Pair::new();
+        let config = TransferConfig {
+            prefix_checksum: true,
+            ..TransferConfig::default()
+        };
+        // with the first byte lost, the receiver reads the prefix one byte
+        // late, taking the first byte of the body as its checksum
+        let frame = {
+            let mut frame = length_prefix(4, &config).to_vec();
+            frame.extend_from_slice(b"\x00abc");
+            frame
+        };
+        send(pair.a, &frame[1..], MsgFlags::empty()).unwrap();
+        assert!(matches!(
+            error_of(receive_buffer_with_config(pair.b, &config)),
+            RawFdError::FramingDesync
+        ));
+    }
+
+    #[test]
+    fn an_unchecksummed_prefix_is_a_desync_when_checking() {
+        let pair = Pair::new();
+        let config = TransferConfig {
+            prefix_checksum: true,
+            ..TransferConfig::default()
+        };
+        send_buffer(pair.a, b"plain").unwrap();
+        assert!(matches!(
+            error_of(receive_buffer_with_config(pair.b, &config)),
+            RawFdError::FramingDesync
+        ));
+    }
+
+    #[test]
+    fn an_oversized_send_is_refused_before_writing() {
+        let pair = Pair::new();
+        let config = TransferConfig {
+            max_payload_len: 4,
+            ..TransferConfig::default()
+        };
+        assert!(matches!(
+            error_of(send_buffer_with_config(pair.a, b"too long", &config)),
+            RawFdError::PayloadTooLarge {
+                length: 8,
+                max_len: 4
+            }
+        ));
+        send_buffer_with_config(pair.a, b"fits", &config).unwrap();
+        // nothing of the refused buffer was written ahead of this one
+        assert_eq!(receive_buffer(pair.b).unwrap(), b"fits");
+    }
+
+    #[test]
+    fn an_oversized_announcement_is_refused() {
+        let pair = Pair::new();
+        let config = TransferConfig {
+            max_payload_len: 4,
+            ..TransferConfig::default()
+        };
+        send_buffer(pair.a, b"too long").unwrap();
+        assert!(matches!(
+            error_of(receive_buffer_with_config(pair.b, &config)),
+            RawFdError::MessageTooLarge {
+                length: 8,
+                max_len: 4
+            }
+        ));
+    }
+
+    #[test]
+    fn a_huge_announcement_is_refused_without_allocating() {
+        let pair = Pair::new();
+        send(
+            pair.a,
+            &length_prefix(usize::MAX >> 8, &TransferConfig::default()),
+            MsgFlags::empty(),
+        )
+        .unwrap();
+        assert!(matches!(
+            error_of(receive_buffer(pair.b)),
+            RawFdError::MessageTooLarge { .. }
+        ));
+    }
+
+    #[test]
+    fn a_session_exchanges_several_buffers_by_its_deadline() {
+        let pair = Pair::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let sender = DeadlineSession::new(pair.a, deadline);
+        let receiver = DeadlineSession::new(pair.b, deadline);
+        sender.send(b"first").unwrap();
+        sender.send(b"second").unwrap();
+        assert_eq!(receiver.receive().unwrap(), b"first");
+        assert_eq!(receiver.receive().unwrap(), b"second");
+    }
+
+    #[test]
+    fn a_session_fails_once_its_deadline_passes() {
+        let pair = Pair::new();
+        let start = Instant::now();
+        let session = DeadlineSession::new(pair.b, start + Duration::from_millis(50));
+        assert!(matches!(
+            error_of(session.receive()),
+            RawFdError::DeadlineExceeded
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(session.remaining(), Duration::ZERO);
+    }
+
+    #[test]
+    fn the_deadline_covers_the_whole_buffer() {
+        let pair = Pair::new();
+        let frame = encode_frame(b"unfinished");
+        let session = DeadlineSession::new(pair.b, Instant::now() + Duration::from_millis(50));
+        send(pair.a, &frame[..frame.len() - 1], MsgFlags::empty()).unwrap();
+        assert!(matches!(
+            error_of(session.receive()),
+            RawFdError::DeadlineExceeded
+        ));
+    }
+
+    #[test]
+    fn a_session_stops_sending_into_a_full_buffer_at_its_deadline() {
+        let pair = Pair::new();
+        let session = DeadlineSession::new(pair.a, Instant::now() + Duration::from_millis(50));
+        // far more than the socket buffer holds, with nothing reading it
+        let buffer = vec![0; 16 << 20];
+        assert!(matches!(
+            error_of(session.send(&buffer)),
+            RawFdError::DeadlineExceeded
+        ));
+    }
+
+    #[test]
+    fn typed_buffers_round_trip() {
+        let pair = Pair::new();
+        send_typed_buffer(pair.a, 0x42, b"typed").unwrap();
+        assert_eq!(
+            receive_typed_buffer(pair.b).unwrap(),
+            (0x42, b"typed".to_vec())
+        );
+    }
+
+    #[test]
+    fn a_typed_buffer_is_rejected_where_an_untyped_one_is_expected() {
+        type Receive = fn(RawFd) -> Result<Vec<u8>>;
+        let receivers: [Receive; 3] = [
+            receive_buffer,
+            |fd| receive_buffer_with_config(fd, &TransferConfig::default()),
+            |fd| DeadlineSession::new(fd, Instant::now() + Duration::from_secs(5)).receive(),
+        ];
+        for receive in receivers {
+            let pair = Pair::new();
+            send_typed_buffer(pair.a, 0x42, b"typed").unwrap();
+            assert!(matches!(
+                error_of(receive(pair.b)),
+                RawFdError::UnexpectedTypedFrame
+            ));
+        }
+    }
+
+    #[test]
+    fn an_untyped_buffer_is_rejected_where_a_typed_one_is_expected() {
+        type Receive = fn(RawFd) -> Result<(u8, Vec<u8>)>;
+        let receivers: [Receive; 2] = [receive_typed_buffer, |fd| {
+            DeadlineSession::new(fd, Instant::now() + Duration::from_secs(5)).receive_typed()
+        }];
+        for receive in receivers {
+            let pair = Pair::new();
+            send_buffer(pair.a, b"untyped").unwrap();
+            assert!(matches!(
+                error_of(receive(pair.b)),
+                RawFdError::UnexpectedUntypedFrame
+            ));
+        }
+    }
+
+    #[test]
+    fn a_huge_announcement_is_refused_by_a_framed_reader() {
+        let pair = Pair::new();
+        send(
+            pair.a,
+            &length_prefix(usize::MAX, &TransferConfig::default()),
+            MsgFlags::empty(),
+        )
+        .unwrap();
+        let mut reader = FramedReader::new(pair.b);
+        assert!(matches!(
+            error_of(reader.next_frame()),
+            RawFdError::MessageTooLarge {
+                length: usize::MAX,
+                max_len: DEFAULT_MAX_PAYLOAD_LEN
+            }
+        ));
+    }
+
+    #[test]
+    fn polling_a_closed_fd_is_an_invalid_fd() {
+        // far above any file descriptor a test opens, so never reused
+        let fd = RawFd::MAX - 1;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert!(matches!(
+            error_of(poll_until(
+                fd,
+                PollFlags::POLLIN,
+                deadline,
+                &TransferConfig::default()
+            )),
+            RawFdError::InvalidFd
+        ));
+    }
+
+    #[test]
+    fn polling_gives_up_at_the_deadline() {
+        let pair = Pair::new();
+        let config = TransferConfig::default();
+        let deadline = Instant::now() + Duration::from_millis(20);
+        assert!(!poll_until(pair.b, PollFlags::POLLIN, deadline, &config).unwrap());
+        send_buffer(pair.a, b"ready").unwrap();
+        assert!(poll_until(pair.b, PollFlags::POLLIN, deadline, &config).unwrap());
+    }
+
+    /// A synced frame holding `body`, as `send_buffer_synced` writes it
+    fn synced_frame(body: &[u8]) -> Vec<u8> {
+        let config = TransferConfig {
+            prefix_checksum: true,
+            ..TransferConfig::default()
+        };
+        let mut frame = SYNC_MARKER.to_vec();
+        frame.extend_from_slice(&length_prefix(body.len(), &config));
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    #[test]
+    fn garbage_between_synced_frames_is_discarded() {
+        let pair = Pair::new();
+        let garbage = b"not a frame at all";
+        send_buffer_synced(pair.a, b"first").unwrap();
+        send(pair.a, garbage, MsgFlags::empty()).unwrap();
+        send_buffer_synced(pair.a, b"second").unwrap();
+        let mut reader = FramedReader::new(pair.b).with_sync_markers();
+        assert_eq!(reader.next_frame().unwrap().unwrap(), b"first");
+        assert!(matches!(
+            error_of(reader.next_frame()),
+            RawFdError::FramingDesync
+        ));
+        assert_eq!(reader.resynchronize().unwrap(), garbage.len());
+        assert_eq!(reader.next_frame().unwrap().unwrap(), b"second");
+    }
+
+    #[test]
+    fn a_marker_with_a_corrupt_prefix_is_skipped() {
+        let pair = Pair::new();
+        let mut fake = synced_frame(b"fake");
+        fake[SYNC_MARKER.len()] ^= 0x10;
+        let mut stream = b"junk".to_vec();
+        stream.extend_from_slice(&fake);
+        stream.extend_from_slice(&synced_frame(b"real"));
+        send(pair.a, &stream, MsgFlags::empty()).unwrap();
+        let mut reader = FramedReader::new(pair.b).with_sync_markers();
+        assert!(matches!(
+            error_of(reader.next_frame()),
+            RawFdError::FramingDesync
+        ));
+        assert_eq!(reader.resynchronize().unwrap(), 4 + fake.len());
+        assert_eq!(reader.next_frame().unwrap().unwrap(), b"real");
+    }
+
+    #[test]
+    fn a_marker_split_across_reads_is_found() {
+        let pair = Pair::new();
+        let frame = synced_frame(b"body");
+        let mut first = b"garbage before the frame".to_vec();
+        let garbage_len = first.len();
+        first.extend_from_slice(&frame[..2]);
+        send(pair.a, &first, MsgFlags::empty()).unwrap();
+        let mut reader = FramedReader::new(pair.b).with_sync_markers();
+        assert!(matches!(
+            error_of(reader.next_frame()),
+            RawFdError::FramingDesync
+        ));
+        // the rest of the marker only arrives once the scan has begun
+        send(pair.a, &frame[2..], MsgFlags::empty()).unwrap();
+        assert_eq!(reader.resynchronize().unwrap(), garbage_len);
+        assert_eq!(reader.next_frame().unwrap().unwrap(), b"body");
+    }
+
+    #[test]
+    fn a_peer_closing_during_a_resync_is_an_unexpected_eof() {
+        let mut pair = Pair::new();
+        send(pair.a, b"garbage and nothing after it", MsgFlags::empty()).unwrap();
+        pair.close_a();
+        let mut reader = FramedReader::new(pair.b).with_sync_markers();
+        assert!(matches!(
+            error_of(reader.next_frame()),
+            RawFdError::FramingDesync
+        ));
+        assert!(matches!(
+            error_of(reader.resynchronize()),
+            RawFdError::UnexpectedEof
+        ));
+    }
+
+    #[test]
+    fn only_a_synced_reader_can_resynchronize() {
+        let pair = Pair::new();
+        send(pair.a, b"garbage", MsgFlags::empty()).unwrap();
+        let mut reader = FramedReader::new(pair.b);
+        assert!(matches!(
+            error_of(reader.resynchronize()),
+            RawFdError::FramingDesync
+        ));
+    }
+}
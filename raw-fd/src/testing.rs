@@ -0,0 +1,177 @@
+//! Fault injection for the `send` and `recv` calls this crate makes on a
+//! file descriptor, for exercising the error handling of the framing loops
+//! (`EINTR` retries, partial transfers, `EAGAIN`, a peer going away part way
+//! through a buffer) deterministically over a real socket.
+//!
+//! ## Authors
+//!
+//! The Veracruz Development Team.
+//!
+//! ## Licensing and copyright notice
+//!
+//! See the `LICENSE_MIT.markdown` file in the root directory for
+//! information on licensing and copyright.
+
+use nix::errno::Errno;
+use std::{
+    collections::VecDeque,
+    os::unix::io::RawFd,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard,
+    },
+};
+
+/// What one `send` or `recv` on a file descriptor with a `FaultyTransport`
+/// does
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Make the real call, but ask it for at most the given number of bytes
+    /// (and at least one)
+    Short(usize),
+    /// Fail with `EINTR` without making the call
+    Interrupt,
+    /// Fail with `EAGAIN` without making the call
+    WouldBlock,
+    /// Behave as if the peer had gone away, without making the call: a
+    /// `recv` returns 0 and a `send` fails with `EPIPE`
+    Eof,
+    /// Claim to have transferred one byte more than was asked for, without
+    /// making the call, as a buggy transport might
+    Overrun,
+}
+
+/// A script of faults for the `send` and `recv` calls this crate makes on
+/// one file descriptor. While it exists, each `recv` (or `send`) on the file
+/// descriptor takes the next fault from the read (or write) script; once a
+/// script runs out, calls go straight to the kernel again. Dropping it stops
+/// the injection.
+///
+/// Scripts are kept per file descriptor number, for the whole process, so
+/// the file descriptor must stay open while this exists: otherwise the
+/// faults would apply to whatever reuses its number. Only the calls made by
+/// the functions of this crate are affected.
+#[derive(Debug)]
+pub struct FaultyTransport {
+    /// The file descriptor the faults apply to
+    fd: RawFd,
+}
+
+/// The faults still to be injected on one file descriptor
+struct Script {
+    /// The file descriptor the faults apply to
+    fd: RawFd,
+    /// The faults for the `recv` calls to come
+    reads: VecDeque<Fault>,
+    /// The faults for the `send` calls to come
+    writes: VecDeque<Fault>,
+}
+
+/// The scripts of all the `FaultyTransport`s that exist
+static SCRIPTS: Mutex<Vec<Script>> = Mutex::new(Vec::new());
+
+/// The number of `FaultyTransport`s that exist, so that calls can skip
+/// looking for a script when there are none
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Lock `SCRIPTS`, carrying on if a thread panicked while holding the lock
+fn scripts() -> MutexGuard<'static, Vec<Script>> {
+    SCRIPTS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl FaultyTransport {
+    /// Start injecting faults on `fd`, with empty scripts. Any script
+    /// already set up for `fd` is replaced.
+    pub fn new(fd: RawFd) -> Self {
+        let mut scripts = scripts();
+        scripts.retain(|script| script.fd != fd);
+        scripts.push(Script {
+            fd,
+            reads: VecDeque::new(),
+            writes: VecDeque::new(),
+        });
+        ACTIVE.fetch_add(1, Ordering::SeqCst);
+        FaultyTransport { fd }
+    }
+
+    /// Add `faults` to the end of the script for `recv` calls
+    pub fn script_reads<I: IntoIterator<Item = Fault>>(self, faults: I) -> Self {
+        self.with_script(|script| script.reads.extend(faults));
+        self
+    }
+
+    /// Add `faults` to the end of the script for `send` calls
+    pub fn script_writes<I: IntoIterator<Item = Fault>>(self, faults: I) -> Self {
+        self.with_script(|script| script.writes.extend(faults));
+        self
+    }
+
+    /// The number of faults scripted for `recv` calls not yet injected
+    pub fn pending_reads(&self) -> usize {
+        self.with_script(|script| script.reads.len())
+    }
+
+    /// The number of faults scripted for `send` calls not yet injected
+    pub fn pending_writes(&self) -> usize {
+        self.with_script(|script| script.writes.len())
+    }
+
+    /// Run `f` on the script for this transport's file descriptor
+    fn with_script<T, F: FnOnce(&mut Script) -> T>(&self, f: F) -> T {
+        let mut scripts = scripts();
+        let script = scripts
+            .iter_mut()
+            .find(|script| script.fd == self.fd)
+            .expect("the script of a FaultyTransport is kept until it is dropped");
+        f(script)
+    }
+}
+
+impl Drop for FaultyTransport {
+    fn drop(&mut self) {
+        scripts().retain(|script| script.fd != self.fd);
+        ACTIVE.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Which kind of call a fault is injected into
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Op {
+    /// A `recv`
+    Read,
+    /// A `send`
+    Write,
+}
+
+/// What a `send` or `recv` should do, according to its script
+pub(crate) enum Injection {
+    /// Make the real call, for at most the given number of bytes
+    Call(usize),
+    /// Return the given result without making the call
+    Return(nix::Result<usize>),
+}
+
+/// What a call of kind `op` for `len` bytes on `fd` should do
+pub(crate) fn inject(fd: RawFd, op: Op, len: usize) -> Injection {
+    if ACTIVE.load(Ordering::SeqCst) == 0 {
+        return Injection::Call(len);
+    }
+    let fault = scripts()
+        .iter_mut()
+        .find(|script| script.fd == fd)
+        .and_then(|script| match op {
+            Op::Read => script.reads.pop_front(),
+            Op::Write => script.writes.pop_front(),
+        });
+    match fault {
+        None => Injection::Call(len),
+        Some(Fault::Short(max)) => Injection::Call(len.min(max.max(1))),
+        Some(Fault::Interrupt) => Injection::Return(Err(Errno::EINTR)),
+        Some(Fault::WouldBlock) => Injection::Return(Err(Errno::EAGAIN)),
+        Some(Fault::Eof) if op == Op::Read => Injection::Return(Ok(0)),
+        Some(Fault::Eof) => Injection::Return(Err(Errno::EPIPE)),
+        Some(Fault::Overrun) => Injection::Return(Ok(len + 1)),
+    }
+}
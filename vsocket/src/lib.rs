@@ -12,17 +12,22 @@
 //! information on licensing and copyright.
 
 use nix::{
+    errno::Errno,
+    fcntl::{fcntl, FcntlArg, OFlag},
+    libc::time_t,
+    poll::{poll, PollFd, PollFlags},
     sys::socket::{
-        connect, setsockopt, shutdown, socket,
-        sockopt::{ReuseAddr, ReusePort},
-        AddressFamily, Shutdown, VsockAddr, SockFlag, SockType,
+        accept, bind, connect, getsockopt, listen, setsockopt, shutdown, socket,
+        sockopt::{RcvBuf, ReceiveTimeout, ReuseAddr, ReusePort, SendTimeout, SndBuf, SocketError},
+        AddressFamily, Shutdown, SockFlag, SockType, VsockAddr,
     },
+    sys::time::TimeVal,
     unistd::close,
 };
 use std::{
-    os::unix::io::{AsRawFd, RawFd},
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
     thread::sleep,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -32,6 +37,15 @@ use std::{
 /// Maximum number of connection attempts to make before erroring out
 const MAX_CONNECTION_ATTEMPTS: usize = 5;
 
+/// The CID by which an enclave addresses its parent instance, i.e. the host
+pub const PARENT_CID: u32 = 3;
+
+/// The wildcard CID, for binding to whichever CID the host has
+pub const VMADDR_CID_ANY: u32 = u32::MAX;
+
+/// Number of pending connections a `VsockListener` will queue
+const LISTEN_BACKLOG: usize = 128;
+
 ////////////////////////////////////////////////////////////////////////////////
 // Virtual sockets.
 ////////////////////////////////////////////////////////////////////////////////
@@ -83,6 +97,257 @@ impl VsockSocket {
         // In case of success this should never be reached.
         Err(err)
     }
+
+    /// As `connect`, but giving up with `ETIMEDOUT` once `timeout` has
+    /// passed, rather than after however long the attempts and the back-off
+    /// between them take. Each attempt is a non-blocking connect awaited
+    /// with `poll` for no longer than the time remaining, and the back-off
+    /// is cut short at the deadline. The returned socket is in blocking
+    /// mode, as if from `connect`.
+    pub fn connect_with_timeout<T>(
+        cid: T,
+        port: T,
+        timeout: Duration,
+    ) -> Result<VsockSocket, nix::Error>
+    where
+        T: Into<u32>,
+    {
+        let sockaddr = VsockAddr::new(cid.into(), port.into());
+        let deadline = Instant::now() + timeout;
+        let mut err = Errno::ETIMEDOUT;
+
+        for i in 0..MAX_CONNECTION_ATTEMPTS {
+            let vsocket = VsockSocket::new(socket(
+                AddressFamily::Vsock,
+                SockType::Stream,
+                SockFlag::SOCK_NONBLOCK,
+                None,
+            )?);
+
+            setsockopt(vsocket.as_raw_fd(), ReuseAddr, &true)?;
+            setsockopt(vsocket.as_raw_fd(), ReusePort, &true)?;
+
+            let result = match connect(vsocket.as_raw_fd(), &sockaddr) {
+                Err(Errno::EINPROGRESS) => wait_connected(vsocket.as_raw_fd(), deadline),
+                result => result,
+            };
+            match result {
+                Ok(()) => {
+                    fcntl(vsocket.as_raw_fd(), FcntlArg::F_SETFL(OFlag::empty()))?;
+                    return Ok(vsocket);
+                }
+                Err(e) => err = e,
+            }
+
+            // Exponentially backoff before retrying, but not past the deadline
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(Errno::ETIMEDOUT);
+            }
+            sleep(remaining.min(Duration::from_secs(1 << i)));
+        }
+
+        Err(err)
+    }
+
+    /// As `connect`, but without blocking the thread: the connection is
+    /// made on a non-blocking socket whose completion is awaited on the
+    /// tokio runtime, and the back-off between attempts is a tokio sleep.
+    /// Dropping the future (for instance, when it loses a `tokio::select!`)
+    /// abandons the attempt and closes the socket. The returned socket is in
+    /// blocking mode, as if from `connect`.
+    #[cfg(feature = "tokio")]
+    pub async fn connect_async(cid: u32, port: u32) -> Result<VsockSocket, nix::Error> {
+        use tokio::io::{unix::AsyncFd, Interest};
+
+        let sockaddr = VsockAddr::new(cid, port);
+        // Just a placeholder!
+        let mut err: nix::Error = Errno::EOPNOTSUPP;
+
+        for i in 0..MAX_CONNECTION_ATTEMPTS {
+            let vsocket = VsockSocket::new(socket(
+                AddressFamily::Vsock,
+                SockType::Stream,
+                SockFlag::SOCK_NONBLOCK,
+                None,
+            )?);
+
+            setsockopt(vsocket.as_raw_fd(), ReuseAddr, &true)?;
+            setsockopt(vsocket.as_raw_fd(), ReusePort, &true)?;
+
+            let result = match connect(vsocket.as_raw_fd(), &sockaddr) {
+                Err(Errno::EINPROGRESS) => {
+                    let fd = vsocket.as_raw_fd();
+                    let async_fd = AsyncFd::with_interest(fd, Interest::WRITABLE)
+                        .map_err(|e| Errno::from_i32(e.raw_os_error().unwrap_or(0)))?;
+                    let guard = async_fd
+                        .writable()
+                        .await
+                        .map_err(|e| Errno::from_i32(e.raw_os_error().unwrap_or(0)))?;
+                    drop(guard);
+                    match getsockopt(fd, SocketError)? {
+                        0 => Ok(()),
+                        errno => Err(Errno::from_i32(errno)),
+                    }
+                }
+                result => result,
+            };
+            match result {
+                Ok(()) => {
+                    fcntl(vsocket.as_raw_fd(), FcntlArg::F_SETFL(OFlag::empty()))?;
+                    return Ok(vsocket);
+                }
+                Err(e) => err = e,
+            }
+
+            // Exponentially backoff before retrying to connect to the io
+            tokio::time::sleep(Duration::from_secs(1 << i)).await;
+        }
+
+        // In case of success this should never be reached.
+        Err(err)
+    }
+
+    /// Request a kernel send buffer (`SO_SNDBUF`) of `size` bytes. The kernel
+    /// may adjust the size: see `send_buffer_size`.
+    pub fn set_send_buffer_size(&self, size: usize) -> Result<(), nix::Error> {
+        setsockopt(self.socket_fd, SndBuf, &size)
+    }
+
+    /// The size of the kernel's send buffer (`SO_SNDBUF`) actually in effect
+    pub fn send_buffer_size(&self) -> Result<usize, nix::Error> {
+        getsockopt(self.socket_fd, SndBuf)
+    }
+
+    /// Request a kernel receive buffer (`SO_RCVBUF`) of `size` bytes. The
+    /// kernel may adjust the size: see `receive_buffer_size`.
+    pub fn set_receive_buffer_size(&self, size: usize) -> Result<(), nix::Error> {
+        setsockopt(self.socket_fd, RcvBuf, &size)
+    }
+
+    /// The size of the kernel's receive buffer (`SO_RCVBUF`) actually in
+    /// effect
+    pub fn receive_buffer_size(&self) -> Result<usize, nix::Error> {
+        getsockopt(self.socket_fd, RcvBuf)
+    }
+
+    /// Set the timeout (`SO_RCVTIMEO`) after which a blocked receive fails with
+    /// `EAGAIN`, or with `None` block indefinitely. As for
+    /// `std::net::TcpStream::set_read_timeout`, a zero timeout is rejected
+    /// with `EINVAL`, since the kernel would take it to mean no timeout.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), nix::Error> {
+        setsockopt(self.socket_fd, ReceiveTimeout, &timeval_of(timeout)?)
+    }
+
+    /// The receive timeout (`SO_RCVTIMEO`) in effect, or `None` if receives
+    /// block indefinitely
+    pub fn read_timeout(&self) -> Result<Option<Duration>, nix::Error> {
+        Ok(duration_of(getsockopt(self.socket_fd, ReceiveTimeout)?))
+    }
+
+    /// Set the timeout (`SO_SNDTIMEO`) after which a blocked send fails with
+    /// `EAGAIN`, or with `None` block indefinitely. A zero timeout is rejected
+    /// as for `set_read_timeout`.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<(), nix::Error> {
+        setsockopt(self.socket_fd, SendTimeout, &timeval_of(timeout)?)
+    }
+
+    /// The send timeout (`SO_SNDTIMEO`) in effect, or `None` if sends block
+    /// indefinitely
+    pub fn write_timeout(&self) -> Result<Option<Duration>, nix::Error> {
+        Ok(duration_of(getsockopt(self.socket_fd, SendTimeout)?))
+    }
+
+    /// Listen for connections made by enclaves to `PARENT_CID` on `port`, for
+    /// protocols where the enclave rather than the host opens the channel.
+    /// The listener is bound to `VMADDR_CID_ANY`, which on the parent
+    /// instance receives the connections enclaves address to `PARENT_CID`.
+    /// Each call to `accept` on the returned listener (or each item of
+    /// `incoming`) yields one enclave's connection, so a single listener can
+    /// serve any number of enclave callbacks.
+    pub fn listen_for_enclave(port: u32) -> Result<VsockListener, nix::Error> {
+        let listener = VsockListener {
+            socket_fd: socket(
+                AddressFamily::Vsock,
+                SockType::Stream,
+                SockFlag::empty(),
+                None,
+            )?,
+        };
+        setsockopt(listener.as_raw_fd(), ReuseAddr, &true)?;
+        bind(listener.as_raw_fd(), &VsockAddr::new(VMADDR_CID_ANY, port))?;
+        listen(listener.as_raw_fd(), LISTEN_BACKLOG)?;
+        Ok(listener)
+    }
+}
+
+/// Wait until `deadline` for a non-blocking connect on `fd` to complete,
+/// failing with `ETIMEDOUT` if it hasn't by then, or with the error the
+/// connect failed with
+fn wait_connected(fd: RawFd, deadline: Instant) -> Result<(), nix::Error> {
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(Errno::ETIMEDOUT);
+        }
+        // rounded up, so that the wait doesn't end early
+        let millis = remaining.as_nanos().div_ceil(1_000_000);
+        let millis = i32::try_from(millis).unwrap_or(i32::MAX);
+        match poll(&mut [PollFd::new(fd, PollFlags::POLLOUT)], millis) {
+            Ok(0) | Err(Errno::EINTR) => continue,
+            Ok(_) => {
+                return match getsockopt(fd, SocketError)? {
+                    0 => Ok(()),
+                    errno => Err(Errno::from_i32(errno)),
+                }
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// The socket timeout for `timeout`, in which zero means none
+fn timeval_of(timeout: Option<Duration>) -> Result<TimeVal, nix::Error> {
+    match timeout {
+        None => Ok(TimeVal::new(0, 0)),
+        Some(timeout) if timeout.is_zero() => Err(nix::errno::Errno::EINVAL),
+        Some(timeout) => {
+            // round the whole timeout up, so that a sub-microsecond timeout
+            // isn't taken as none and rounding carries into the seconds
+            let micros = timeout.as_nanos().div_ceil(1000);
+            Ok(match time_t::try_from(micros / 1_000_000) {
+                Ok(secs) => TimeVal::new(secs, (micros % 1_000_000) as _),
+                // longer than the kernel can represent, and so as good as
+                // none, but still a timeout
+                Err(_) => TimeVal::new(time_t::MAX, 999_999),
+            })
+        }
+    }
+}
+
+/// The timeout a socket timeout stands for
+fn duration_of(timeval: TimeVal) -> Option<Duration> {
+    let timeout = Duration::new(timeval.tv_sec() as u64, timeval.tv_usec() as u32 * 1000);
+    (!timeout.is_zero()).then_some(timeout)
+}
+
+/// A VSOCK bound on the host and listening for connections from enclaves.
+pub struct VsockListener {
+    /// The file handle of the VSOCK.
+    socket_fd: RawFd,
+}
+
+impl VsockListener {
+    /// Wait for an enclave to connect, and return the connection.
+    pub fn accept(&self) -> Result<VsockSocket, nix::Error> {
+        Ok(VsockSocket::new(accept(self.socket_fd)?))
+    }
+
+    /// An endless iterator over the connections made by enclaves, for use as
+    /// an accept loop: `for connection in listener.incoming() { ... }`.
+    pub fn incoming(&self) -> impl Iterator<Item = Result<VsockSocket, nix::Error>> + '_ {
+        std::iter::repeat_with(move || self.accept())
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -99,6 +364,21 @@ impl Drop for VsockSocket {
     }
 }
 
+impl Drop for VsockListener {
+    /// Drop a listener by closing it.
+    fn drop(&mut self) {
+        close(self.socket_fd).unwrap_or_else(|e| eprintln!("Failed to close io: {:?}", e));
+    }
+}
+
+impl AsRawFd for VsockListener {
+    #[inline]
+    /// Extract the raw RadFd from the VsockListener
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket_fd
+    }
+}
+
 impl AsRawFd for VsockSocket {
     #[inline]
     /// Extract the raw RadFd from the VsockSocket
@@ -106,3 +386,104 @@ impl AsRawFd for VsockSocket {
         self.socket_fd
     }
 }
+
+impl FromRawFd for VsockSocket {
+    #[inline]
+    /// Take ownership of a connected stream socket, which is closed when the
+    /// VsockSocket is dropped. Any connected stream socket will do, such as
+    /// one end of a Unix socket pair standing in for an enclave in tests.
+    unsafe fn from_raw_fd(socket_fd: RawFd) -> Self {
+        VsockSocket::new(socket_fd)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Tests.
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::socket::socketpair;
+
+    /// A connected pair of sockets, standing in for a VSOCK connection
+    fn pair() -> (VsockSocket, VsockSocket) {
+        let (a, b) = socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::empty(),
+        )
+        .unwrap();
+        unsafe { (VsockSocket::from_raw_fd(a), VsockSocket::from_raw_fd(b)) }
+    }
+
+    #[test]
+    fn buffer_sizes_read_back_as_requested_or_adjusted() {
+        let (socket, _peer) = pair();
+        let requested = 64 * 1024;
+        socket.set_send_buffer_size(requested).unwrap();
+        socket.set_receive_buffer_size(requested).unwrap();
+        // Linux doubles the size requested, to allow for its bookkeeping
+        for size in [
+            socket.send_buffer_size().unwrap(),
+            socket.receive_buffer_size().unwrap(),
+        ] {
+            assert!((requested..=2 * requested).contains(&size), "{}", size);
+        }
+    }
+
+    #[test]
+    fn buffer_sizes_are_clamped_to_the_kernel_limits() {
+        let (socket, _peer) = pair();
+        socket.set_send_buffer_size(1).unwrap();
+        let smallest = socket.send_buffer_size().unwrap();
+        assert!(smallest > 1, "{}", smallest);
+        socket.set_send_buffer_size(1 << 30).unwrap();
+        let largest = socket.send_buffer_size().unwrap();
+        assert!((smallest..1 << 30).contains(&largest), "{}", largest);
+    }
+
+    #[test]
+    fn timeouts_round_up_to_whole_microseconds() {
+        let timeval = |timeout| {
+            let timeval = timeval_of(Some(timeout)).unwrap();
+            (timeval.tv_sec() as u64, timeval.tv_usec() as u64)
+        };
+        assert_eq!(timeval(Duration::from_nanos(1)), (0, 1));
+        assert_eq!(timeval(Duration::from_millis(1500)), (1, 500_000));
+        assert_eq!(timeval(Duration::new(1, 999_999_001)), (2, 0));
+        assert_eq!(timeval(Duration::MAX), (time_t::MAX as u64, 999_999));
+        assert_eq!(timeval_of(None).unwrap(), TimeVal::new(0, 0));
+        assert_eq!(
+            timeval_of(Some(Duration::ZERO)),
+            Err(nix::errno::Errno::EINVAL)
+        );
+    }
+
+    #[test]
+    fn timeouts_just_under_a_second_are_accepted() {
+        let (socket, _peer) = pair();
+        let timeout = Duration::new(1, 999_999_999);
+        socket.set_read_timeout(Some(timeout)).unwrap();
+        socket.set_write_timeout(Some(timeout)).unwrap();
+        assert_eq!(socket.read_timeout().unwrap(), Some(Duration::from_secs(2)));
+        assert_eq!(
+            socket.write_timeout().unwrap(),
+            Some(Duration::from_secs(2))
+        );
+        // accepted too, though the kernel takes a timeout this long as none
+        socket.set_read_timeout(Some(Duration::MAX)).unwrap();
+    }
+
+    #[test]
+    fn a_connect_with_a_timeout_gives_up_at_the_deadline() {
+        // no enclave has this CID, so the connect can only fail, and must do
+        // so by the deadline rather than after the full back-off
+        let started = Instant::now();
+        assert!(
+            VsockSocket::connect_with_timeout(16u32, 5005, Duration::from_millis(100)).is_err()
+        );
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}